@@ -0,0 +1,106 @@
+import React, { useState } from 'react';
+import { KeyRound, AlertCircle } from 'lucide-react';
+import { invoke } from '@tauri-apps/api/tauri';
+
+export interface AuthPromptRequest {
+  session_id: string;
+  username: string;
+  instructions: string;
+  prompts: { text: string; echo: boolean }[];
+}
+
+interface AuthPromptProps {
+  request: AuthPromptRequest;
+  onDone: () => void;
+}
+
+// Keyboard-interactive challenge from the server, e.g. an OTP code or a Duo choice
+const AuthPrompt: React.FC<AuthPromptProps> = ({ request, onDone }) => {
+  const [answers, setAnswers] = useState<string[]>(() => request.prompts.map(() => ''));
+  const [error, setError] = useState('');
+  const [isLoading, setIsLoading] = useState(false);
+
+  // null cancels authentication
+  const respond = async (responses: string[] | null) => {
+    setIsLoading(true);
+    setError('');
+    try {
+      await invoke('pty_auth_respond', {
+        params: { session_id: request.session_id, responses },
+      });
+      onDone();
+    } catch (err) {
+      setError(String(err));
+    } finally {
+      setIsLoading(false);
+    }
+  };
+
+  const handleKeyDown = (e: React.KeyboardEvent) => {
+    if (e.key === 'Enter') {
+      respond(answers);
+    }
+  };
+
+  return (
+    <div className="absolute inset-0 bg-black/70 backdrop-blur-sm z-20 flex items-center justify-center p-4">
+      <div className="bg-gray-900 border border-gray-700 w-full max-w-md rounded-xl shadow-2xl p-6">
+        <div className="flex items-center gap-3 mb-4">
+          <div className="w-10 h-10 bg-indigo-600/20 rounded-full flex items-center justify-center">
+            <KeyRound className="w-5 h-5 text-indigo-400" />
+          </div>
+          <h2 className="text-lg font-bold text-white">Authentication for {request.username}</h2>
+        </div>
+
+        {request.instructions && (
+          <p className="text-sm text-gray-400 mb-4 whitespace-pre-wrap">{request.instructions}</p>
+        )}
+
+        <div className="space-y-4">
+          {request.prompts.map((prompt, idx) => (
+            <div key={idx}>
+              <label className="block text-xs font-medium text-gray-400 mb-2 whitespace-pre-wrap">
+                {prompt.text}
+              </label>
+              <input
+                type={prompt.echo ? 'text' : 'password'}
+                value={answers[idx]}
+                onChange={(e) => setAnswers((prev) => prev.map((a, i) => (i === idx ? e.target.value : a)))}
+                onKeyDown={handleKeyDown}
+                autoFocus={idx === 0}
+                autoComplete="one-time-code"
+                className="w-full bg-gray-950 border border-gray-700 rounded-lg p-3 text-sm text-white focus:border-indigo-500 outline-none transition"
+              />
+            </div>
+          ))}
+
+          {error && (
+            <div className="p-3 bg-red-900/20 border border-red-700 rounded-lg flex items-center gap-2">
+              <AlertCircle className="w-4 h-4 text-red-400 flex-shrink-0" />
+              <span className="text-xs text-red-300">{error}</span>
+            </div>
+          )}
+
+          <div className="flex gap-2">
+            <button
+              onClick={() => respond(null)}
+              disabled={isLoading}
+              className="flex-1 px-4 py-2 bg-gray-800 hover:bg-gray-700 border border-gray-700 disabled:opacity-50 text-gray-200 text-sm rounded-lg transition"
+            >
+              Cancel
+            </button>
+            <button
+              onClick={() => respond(answers)}
+              disabled={isLoading}
+              className="flex-1 px-4 py-2 bg-indigo-600 hover:bg-indigo-500 disabled:opacity-50 text-white text-sm font-medium rounded-lg transition"
+            >
+              Continue
+            </button>
+          </div>
+        </div>
+      </div>
+    </div>
+  );
+};
+
+export default AuthPrompt;
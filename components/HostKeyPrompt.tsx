@@ -0,0 +1,86 @@
+import React, { useState } from 'react';
+import { ShieldAlert, AlertCircle } from 'lucide-react';
+import { invoke } from '@tauri-apps/api/tauri';
+
+export interface HostKeyPromptRequest {
+  session_id: string;
+  host: string;
+  port: number;
+  key_type: string;
+  fingerprint: string;
+}
+
+interface HostKeyPromptProps {
+  request: HostKeyPromptRequest;
+  onDone: () => void;
+}
+
+// Asks whether to trust a host that is not in known_hosts yet; the connection waits on the answer
+const HostKeyPrompt: React.FC<HostKeyPromptProps> = ({ request, onDone }) => {
+  const [error, setError] = useState('');
+  const [isLoading, setIsLoading] = useState(false);
+
+  const answer = async (accept: boolean) => {
+    setIsLoading(true);
+    setError('');
+    try {
+      await invoke(accept ? 'accept_host_key' : 'reject_host_key', { sessionId: request.session_id });
+      onDone();
+    } catch (err) {
+      setError(String(err));
+    } finally {
+      setIsLoading(false);
+    }
+  };
+
+  const hostLabel = request.port === 22 ? request.host : `[${request.host}]:${request.port}`;
+
+  return (
+    <div className="absolute inset-0 bg-black/70 backdrop-blur-sm z-20 flex items-center justify-center p-4">
+      <div className="bg-gray-900 border border-gray-700 w-full max-w-md rounded-xl shadow-2xl p-6">
+        <div className="flex items-center gap-3 mb-4">
+          <div className="w-10 h-10 bg-yellow-600/20 rounded-full flex items-center justify-center">
+            <ShieldAlert className="w-5 h-5 text-yellow-400" />
+          </div>
+          <h2 className="text-lg font-bold text-white">Unknown Host</h2>
+        </div>
+
+        <p className="text-sm text-gray-400 mb-4">
+          The authenticity of <span className="font-mono text-gray-200">{hostLabel}</span> can't be established.
+          Check the fingerprint with the server's administrator before trusting it.
+        </p>
+
+        <div className="p-3 bg-gray-950 border border-gray-700 rounded-lg mb-4">
+          <div className="text-[10px] text-gray-500 uppercase mb-1">{request.key_type} key fingerprint</div>
+          <div className="font-mono text-xs text-gray-200 break-all">{request.fingerprint}</div>
+        </div>
+
+        {error && (
+          <div className="p-3 mb-4 bg-red-900/20 border border-red-700 rounded-lg flex items-center gap-2">
+            <AlertCircle className="w-4 h-4 text-red-400 flex-shrink-0" />
+            <span className="text-xs text-red-300">{error}</span>
+          </div>
+        )}
+
+        <div className="flex gap-2">
+          <button
+            onClick={() => answer(false)}
+            disabled={isLoading}
+            className="flex-1 px-4 py-2 bg-gray-800 hover:bg-gray-700 border border-gray-700 disabled:opacity-50 text-gray-200 text-sm rounded-lg transition"
+          >
+            Reject
+          </button>
+          <button
+            onClick={() => answer(true)}
+            disabled={isLoading}
+            className="flex-1 px-4 py-2 bg-indigo-600 hover:bg-indigo-500 disabled:opacity-50 text-white text-sm font-medium rounded-lg transition"
+          >
+            Trust and Connect
+          </button>
+        </div>
+      </div>
+    </div>
+  );
+};
+
+export default HostKeyPrompt;
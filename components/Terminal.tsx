@@ -8,6 +8,8 @@ import { Terminal as XTerm } from '@xterm/xterm';
 import { FitAddon } from '@xterm/addon-fit';
 import { WebLinksAddon } from '@xterm/addon-web-links';
 import { SearchAddon } from '@xterm/addon-search';
+import HostKeyPrompt, { HostKeyPromptRequest } from './HostKeyPrompt';
+import AuthPrompt, { AuthPromptRequest } from './AuthPrompt';
 
 interface TerminalProps {
   server: Server | null;
@@ -22,6 +24,8 @@ const Terminal: React.FC<TerminalProps> = ({ server, sshKeys, settings }) => {
   const [isAiLoading, setIsAiLoading] = useState(false);
   const [aiResponses, setAiResponses] = useState<string[]>([]);
   const [selectedProvider, setSelectedProvider] = useState<AIProviderId>(settings.activeProvider);
+  const [hostKeyPrompt, setHostKeyPrompt] = useState<HostKeyPromptRequest | null>(null);
+  const [authPrompt, setAuthPrompt] = useState<AuthPromptRequest | null>(null);
 
   const terminalRef = useRef<HTMLDivElement>(null);
   const xtermRef = useRef<XTerm | null>(null);
@@ -160,6 +164,27 @@ const Terminal: React.FC<TerminalProps> = ({ server, sshKeys, settings }) => {
     };
   }, []);
 
+  // Prompts arrive while pty_connect is still pending, before the session id state settles,
+  // so they are matched against the ref that connectToServer sets
+  useEffect(() => {
+    const unlistenHostKey = listen('pty-hostkey-prompt', (event: any) => {
+      if (event.payload.session_id === sessionIdRef.current) {
+        setHostKeyPrompt(event.payload);
+      }
+    });
+
+    const unlistenAuth = listen('pty-auth-prompt', (event: any) => {
+      if (event.payload.session_id === sessionIdRef.current) {
+        setAuthPrompt(event.payload);
+      }
+    });
+
+    return () => {
+      unlistenHostKey.then((fn) => fn());
+      unlistenAuth.then((fn) => fn());
+    };
+  }, []);
+
   // Listen for PTY output
   useEffect(() => {
     if (!sessionId) return;
@@ -167,7 +192,16 @@ const Terminal: React.FC<TerminalProps> = ({ server, sshKeys, settings }) => {
     const unlistenOutput = listen('pty-output', (event: any) => {
       const payload = event.payload;
       if (payload.session_id === sessionId && xtermRef.current) {
-        xtermRef.current.write(payload.data);
+        // Raw bytes come base64-encoded; xterm takes them as a Uint8Array
+        const data = payload.format === 'base64'
+          ? Uint8Array.from(atob(payload.data), (c) => c.charCodeAt(0))
+          : payload.data;
+        // Ack once xterm has parsed the chunk so the backend keeps reading ahead
+        xtermRef.current.write(data, () => {
+          invoke('pty_ack', {
+            params: { session_id: sessionId, bytes: payload.bytes ?? 0 },
+          }).catch(() => {});
+        });
       }
     });
 
@@ -216,6 +250,7 @@ const Terminal: React.FC<TerminalProps> = ({ server, sshKeys, settings }) => {
       try {
         const newSessionId = crypto.randomUUID();
         console.log('Setting session ID:', newSessionId);
+        sessionIdRef.current = newSessionId;
         setSessionId(newSessionId);
 
         if (server.isLocal) {
@@ -282,6 +317,8 @@ const Terminal: React.FC<TerminalProps> = ({ server, sshKeys, settings }) => {
       } catch (error) {
         console.error('Connection error:', error);
         setStatus(ConnectionStatus.ERROR);
+        setHostKeyPrompt(null);
+        setAuthPrompt(null);
         if (xtermRef.current) {
           xtermRef.current.writeln(`\x1b[31m✗ Connection failed: ${error}\x1b[0m`);
         }
@@ -398,6 +435,16 @@ const Terminal: React.FC<TerminalProps> = ({ server, sshKeys, settings }) => {
             className="flex-1 overflow-hidden"
             style={{ width: '100%', height: '100%' }}
           />
+          {hostKeyPrompt && (
+            <HostKeyPrompt request={hostKeyPrompt} onDone={() => setHostKeyPrompt(null)} />
+          )}
+          {authPrompt && (
+            <AuthPrompt
+              key={`${authPrompt.instructions}|${authPrompt.prompts.map((p) => p.text).join('|')}`}
+              request={authPrompt}
+              onDone={() => setAuthPrompt(null)}
+            />
+          )}
         </div>
 
         {/* AI Side Panel */}
@@ -0,0 +1,242 @@
+// CPU usage and input latency of the PTY I/O loop, compared with the previous design (a
+// thread per session that polls the channel every 500µs under the session mutex, with
+// writers taking the same mutex).
+//
+// A loopback socket stands in for the SSH channel; the "server" end either stays quiet
+// (idle) or floods output like a chatty `tail -f` (busy) while keystrokes are timed from
+// submission until the server sees them.
+//
+//     cargo bench --bench pty_io
+
+#[allow(dead_code)]
+#[path = "../src/pty_io.rs"]
+mod pty_io;
+
+use parking_lot::Mutex;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+const SESSIONS: usize = 8;
+const IDLE_WINDOW: Duration = Duration::from_secs(2);
+const KEYSTROKES: usize = 100;
+const KEYSTROKE_SPACING: Duration = Duration::from_millis(10);
+const FLOOD_CHUNK: usize = 4096;
+
+struct SocketChannel(TcpStream);
+
+impl pty_io::ChannelIo for SocketChannel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+            result => result,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn resize(&mut self, _cols: u32, _rows: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn eof(&self) -> bool {
+        false
+    }
+}
+
+/// Client end (non-blocking) and server end (blocking) of a loopback connection
+fn socket_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    client.set_nonblocking(true).unwrap();
+    (client, server)
+}
+
+/// The remote side: optionally floods output, reports when each keystroke arrives.
+/// Uses blocking I/O so it adds as little CPU and latency of its own as possible.
+fn spawn_server(server: TcpStream, flood: bool, stop: Arc<AtomicBool>, arrivals: std_mpsc::Sender<Instant>) {
+    if flood {
+        let mut writer = server.try_clone().unwrap();
+        thread::spawn(move || {
+            let chunk = vec![b'x'; FLOOD_CHUNK];
+            while !stop.load(Ordering::Relaxed) && writer.write_all(&chunk).is_ok() {
+                thread::sleep(Duration::from_millis(1));
+            }
+        });
+    }
+
+    let mut reader = server;
+    thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        while let Ok(n @ 1..) = reader.read(&mut buf) {
+            for _ in 0..n {
+                let _ = arrivals.send(Instant::now());
+            }
+        }
+    });
+}
+
+/// Write side of one session under test
+trait Session {
+    fn send_key(&self);
+}
+
+struct PolledSession {
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+impl Session for PolledSession {
+    fn send_key(&self) {
+        let mut stream = self.stream.lock();
+        loop {
+            match stream.write(b"k") {
+                Ok(_) => return,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::yield_now(),
+                Err(e) => panic!("write failed: {}", e),
+            }
+        }
+    }
+}
+
+/// The previous reader: poll every 500µs while holding the session lock
+fn start_polled(client: TcpStream, stop: Arc<AtomicBool>, received: Arc<AtomicU64>) -> PolledSession {
+    let stream = Arc::new(Mutex::new(client));
+    let reader = stream.clone();
+    thread::spawn(move || {
+        let mut buf = vec![0u8; 32768];
+        while !stop.load(Ordering::Relaxed) {
+            loop {
+                let n = match reader.lock().read(&mut buf) {
+                    Ok(n) if n > 0 => n,
+                    _ => break,
+                };
+                received.fetch_add(n as u64, Ordering::Relaxed);
+            }
+            thread::sleep(Duration::from_micros(500));
+        }
+    });
+    PolledSession { stream }
+}
+
+struct EventLoopSession {
+    commands: mpsc::Sender<pty_io::IoCommand>,
+}
+
+impl Session for EventLoopSession {
+    fn send_key(&self) {
+        self.commands.blocking_send(pty_io::IoCommand::Write(b"k".to_vec())).unwrap();
+    }
+}
+
+fn start_event_loop(runtime: &tokio::runtime::Runtime, client: TcpStream, received: Arc<AtomicU64>) -> EventLoopSession {
+    let (commands, mut receiver) = mpsc::channel(pty_io::COMMAND_QUEUE_CAPACITY);
+    runtime.spawn(async move {
+        let socket = tokio::net::TcpStream::from_std(client.try_clone().unwrap()).unwrap();
+        let mut channel = SocketChannel(client);
+        let flow = pty_io::OutputFlow::default();
+        pty_io::drive(&mut channel, &socket, &mut receiver, &flow, |data| {
+            received.fetch_add(data.len() as u64, Ordering::Relaxed);
+            true
+        }).await;
+    });
+    EventLoopSession { commands }
+}
+
+/// User + system CPU time of this process
+fn cpu_time() -> Option<Duration> {
+    // utime and stime are fields 14 and 15, in clock ticks (USER_HZ, 100 on Linux)
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let ticks: u64 = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+    Some(Duration::from_millis(ticks * 10))
+}
+
+struct Report {
+    cpu_percent_per_session: Option<f64>,
+    latency_p50: Option<Duration>,
+    latency_p99: Option<Duration>,
+    throughput_mb_s: f64,
+}
+
+fn run(design: &str, busy: bool) -> Report {
+    let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+    let stop = Arc::new(AtomicBool::new(false));
+    let received = Arc::new(AtomicU64::new(0));
+    let (arrivals_tx, arrivals) = std_mpsc::channel();
+
+    let sessions: Vec<Box<dyn Session>> = (0..SESSIONS)
+        .map(|_| {
+            let (client, server) = socket_pair();
+            spawn_server(server, busy, stop.clone(), arrivals_tx.clone());
+            match design {
+                "polled" => Box::new(start_polled(client, stop.clone(), received.clone())) as Box<dyn Session>,
+                _ => Box::new(start_event_loop(&runtime, client, received.clone())),
+            }
+        })
+        .collect();
+
+    thread::sleep(Duration::from_millis(200));
+    let cpu_before = cpu_time();
+    let received_before = received.load(Ordering::Relaxed);
+    let started = Instant::now();
+
+    let mut latencies = Vec::new();
+    if busy {
+        for _ in 0..KEYSTROKES {
+            for session in &sessions {
+                let sent = Instant::now();
+                session.send_key();
+                if let Ok(arrived) = arrivals.recv_timeout(Duration::from_secs(5)) {
+                    latencies.push(arrived.saturating_duration_since(sent));
+                }
+            }
+            thread::sleep(KEYSTROKE_SPACING);
+        }
+    } else {
+        thread::sleep(IDLE_WINDOW);
+    }
+
+    let elapsed = started.elapsed();
+    let cpu = cpu_time().zip(cpu_before).map(|(after, before)| after.saturating_sub(before));
+    let bytes = received.load(Ordering::Relaxed) - received_before;
+    stop.store(true, Ordering::Relaxed);
+    drop(sessions);
+    runtime.shutdown_timeout(Duration::from_secs(1));
+
+    latencies.sort();
+    let percentile = |p: f64| latencies.get(((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1))).copied();
+    Report {
+        // The simulated servers run in this process too; their cost is the same for both designs
+        cpu_percent_per_session: cpu.map(|cpu| cpu.as_secs_f64() / elapsed.as_secs_f64() * 100.0 / SESSIONS as f64),
+        latency_p50: percentile(0.5),
+        latency_p99: percentile(0.99),
+        throughput_mb_s: bytes as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0),
+    }
+}
+
+fn main() {
+    println!("{} sessions per run", SESSIONS);
+    println!("{:<10} {:<6} {:>14} {:>12} {:>12} {:>12}", "design", "load", "cpu/session", "input p50", "input p99", "output MB/s");
+    for busy in [false, true] {
+        for design in ["polled", "event-loop"] {
+            let report = run(design, busy);
+            let fmt_latency = |d: Option<Duration>| d.map_or("-".to_string(), |d| format!("{:.2}ms", d.as_secs_f64() * 1000.0));
+            println!(
+                "{:<10} {:<6} {:>14} {:>12} {:>12} {:>12.1}",
+                design,
+                if busy { "busy" } else { "idle" },
+                report.cpu_percent_per_session.map_or("n/a".to_string(), |c| format!("{:.2}%", c)),
+                fmt_latency(report.latency_p50),
+                fmt_latency(report.latency_p99),
+                report.throughput_mb_s,
+            );
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use crate::secure_storage::{self, StoredScript};
+use crate::terminal_encoding::{AnsiStripper, OutputEncoder, OutputFormat};
+use encoding_rs::Encoding;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::Window;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// How long an `expect` waits unless the script says otherwise
+pub const DEFAULT_EXPECT_TIMEOUT_SECS: u64 = 30;
+
+// Output kept for matching; older output is dropped once a run falls this far behind
+const MAX_BUFFER_BYTES: usize = 64 * 1024;
+
+static NEXT_RUN_ID: AtomicU64 = AtomicU64::new(1);
+
+// Running scripts, keyed by run id
+static RUNS: Lazy<Mutex<HashMap<String, Arc<Run>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Step {
+    /// Wait until the output (escape sequences removed) matches the regex `pattern`
+    Expect {
+        pattern: String,
+        #[serde(default)]
+        case_insensitive: bool,
+        #[serde(default)]
+        timeout_secs: Option<u64>,
+    },
+    /// Type `text`; "\r" is Enter
+    Send { text: String },
+    /// Type the password of a stored credential, then Enter
+    SendCredential { credential_id: String },
+    /// Timeout of the `expect` steps after this one
+    Timeout { secs: u64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutomationScript {
+    pub id: String,
+    pub name: String,
+    pub steps: Vec<Step>,
+}
+
+impl AutomationScript {
+    fn from_stored(stored: StoredScript) -> Result<Self, String> {
+        let steps = serde_json::from_str(&stored.steps)
+            .map_err(|e| format!("Script {} is damaged: {}", stored.id, e))?;
+        Ok(AutomationScript { id: stored.id, name: stored.name, steps })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// Emitted as `automation-finished`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunResult {
+    pub run_id: String,
+    pub script_id: String,
+    pub session_id: String,
+    pub status: RunStatus,
+    pub steps_completed: usize,
+    /// Index of the step that failed or was interrupted
+    pub failed_step: Option<usize>,
+    pub error: Option<String>,
+}
+
+/// Emitted as `automation-step` after every step
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepEvent {
+    pub run_id: String,
+    pub session_id: String,
+    pub step: usize,
+    /// Text an `expect` matched; what was sent is not reported, it may be a password
+    pub matched: Option<String>,
+}
+
+enum CompiledStep {
+    Expect { regex: Regex, timeout: Option<Duration> },
+    Send(String),
+    SendCredential(String),
+    Timeout(Duration),
+}
+
+fn compile(steps: &[Step]) -> Result<Vec<CompiledStep>, String> {
+    steps.iter().enumerate().map(|(index, step)| {
+        Ok(match step {
+            Step::Expect { pattern, case_insensitive, timeout_secs } => CompiledStep::Expect {
+                regex: RegexBuilder::new(pattern)
+                    .case_insensitive(*case_insensitive)
+                    .build()
+                    .map_err(|e| format!("Step {}: invalid pattern: {}", index + 1, e))?,
+                timeout: timeout_secs.map(|secs| step_timeout(index, secs)).transpose()?,
+            },
+            Step::Send { text } => CompiledStep::Send(text.clone()),
+            Step::SendCredential { credential_id } => CompiledStep::SendCredential(credential_id.clone()),
+            Step::Timeout { secs } => CompiledStep::Timeout(step_timeout(index, *secs)?),
+        })
+    }).collect()
+}
+
+fn step_timeout(index: usize, secs: u64) -> Result<Duration, String> {
+    let timeout = Duration::from_secs(secs);
+    expect_deadline(timeout).map_err(|e| format!("Step {}: {}", index + 1, e))?;
+    Ok(timeout)
+}
+
+// When an expect started now gives up; fails for timeouts too long to count down
+fn expect_deadline(timeout: Duration) -> Result<Instant, String> {
+    Instant::now().checked_add(timeout)
+        .ok_or_else(|| format!("timeout of {}s is too long", timeout.as_secs()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StopReason {
+    Cancelled,
+    SessionClosed,
+}
+
+// A running script's view of its session
+struct Run {
+    session_id: String,
+    output: Mutex<RunOutput>,
+    // Woken by new output and by stop()
+    wake: Notify,
+    stop: Mutex<Option<StopReason>>,
+}
+
+struct RunOutput {
+    decoder: OutputEncoder,
+    stripper: AnsiStripper,
+    // Output not yet consumed by a match
+    buffer: String,
+}
+
+impl Run {
+    fn new(session_id: &str, charset: &'static Encoding, seed: &str) -> Self {
+        Run {
+            session_id: session_id.to_string(),
+            output: Mutex::new(RunOutput {
+                decoder: OutputEncoder::new(OutputFormat::Text, charset),
+                stripper: AnsiStripper::default(),
+                buffer: seed.to_string(),
+            }),
+            wake: Notify::new(),
+            stop: Mutex::new(None),
+        }
+    }
+
+    fn push_output(&self, data: &[u8]) {
+        {
+            let mut output = self.output.lock();
+            let text = output.decoder.encode(data);
+            let plain = output.stripper.strip(&text);
+            output.buffer.push_str(&plain);
+            if output.buffer.len() > MAX_BUFFER_BYTES {
+                let mut cut = output.buffer.len() - MAX_BUFFER_BYTES;
+                while !output.buffer.is_char_boundary(cut) {
+                    cut += 1;
+                }
+                output.buffer.drain(..cut);
+            }
+        }
+        // A stored permit if the run is not waiting yet, so no output is missed
+        self.wake.notify_one();
+    }
+
+    /// The first match in the buffer; the buffer then starts right after it, like expect(1)
+    fn take_match(&self, regex: &Regex) -> Option<String> {
+        let mut output = self.output.lock();
+        let found = regex.find(&output.buffer)?;
+        let (matched, end) = (found.as_str().to_string(), found.end());
+        output.buffer.drain(..end);
+        Some(matched)
+    }
+
+    fn stop(&self, reason: StopReason) {
+        self.stop.lock().get_or_insert(reason);
+        self.wake.notify_one();
+    }
+}
+
+enum Failure {
+    Error(String),
+    Stopped(StopReason),
+}
+
+// Run the steps against `run`. Returns the index of the step that failed and why.
+async fn execute<F, Fut>(
+    run: &Run,
+    steps: &[CompiledStep],
+    mut send: F,
+    mut on_step: impl FnMut(usize, Option<String>),
+) -> Result<(), (usize, Failure)>
+where
+    F: FnMut(String) -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let mut default_timeout = Duration::from_secs(DEFAULT_EXPECT_TIMEOUT_SECS);
+    for (index, step) in steps.iter().enumerate() {
+        let fail = |failure| (index, failure);
+        if let Some(reason) = *run.stop.lock() {
+            return Err(fail(Failure::Stopped(reason)));
+        }
+        match step {
+            CompiledStep::Expect { regex, timeout } => {
+                let timeout = timeout.unwrap_or(default_timeout);
+                let deadline = expect_deadline(timeout).map_err(|e| fail(Failure::Error(e)))?;
+                let matched = loop {
+                    if let Some(reason) = *run.stop.lock() {
+                        return Err(fail(Failure::Stopped(reason)));
+                    }
+                    if let Some(matched) = run.take_match(regex) {
+                        break matched;
+                    }
+                    if tokio::time::timeout_at(deadline, run.wake.notified()).await.is_err() {
+                        return Err(fail(Failure::Error(format!(
+                            "Timed out after {}s waiting for /{}/", timeout.as_secs(), regex.as_str()
+                        ))));
+                    }
+                };
+                on_step(index, Some(matched));
+            }
+            CompiledStep::Send(text) => {
+                send(text.clone()).await.map_err(|e| fail(Failure::Error(e)))?;
+                on_step(index, None);
+            }
+            CompiledStep::SendCredential(credential_id) => {
+                let password = credential_password(credential_id).map_err(|e| fail(Failure::Error(e)))?;
+                send(password + "\r").await.map_err(|e| fail(Failure::Error(e)))?;
+                on_step(index, None);
+            }
+            CompiledStep::Timeout(timeout) => {
+                default_timeout = *timeout;
+                on_step(index, None);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn credential_password(credential_id: &str) -> Result<String, String> {
+    secure_storage::with_database(|db| {
+        let stored = db.get_credential(credential_id)?;
+        db.decrypt_password(stored.password_encrypted)?
+            .ok_or_else(|| format!("Credential {} has no password", credential_id))
+    })
+}
+
+fn run_result(run_id: &str, script_id: &str, session_id: &str, steps: usize, outcome: Result<(), (usize, Failure)>) -> RunResult {
+    let (status, failed_step, error) = match outcome {
+        Ok(()) => (RunStatus::Succeeded, None, None),
+        Err((step, Failure::Error(e))) => (RunStatus::Failed, Some(step), Some(e)),
+        Err((step, Failure::Stopped(StopReason::Cancelled))) => (RunStatus::Cancelled, Some(step), None),
+        Err((step, Failure::Stopped(StopReason::SessionClosed))) => {
+            (RunStatus::Failed, Some(step), Some("Session closed".to_string()))
+        }
+    };
+    RunResult {
+        run_id: run_id.to_string(),
+        script_id: script_id.to_string(),
+        session_id: session_id.to_string(),
+        status,
+        steps_completed: failed_step.unwrap_or(steps),
+        failed_step,
+        error,
+    }
+}
+
+pub fn next_run_id() -> String {
+    format!("automation-{}", NEXT_RUN_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Check a script and save it, replacing one with the same id
+pub fn save_script(script: &AutomationScript) -> Result<(), String> {
+    if script.name.trim().is_empty() {
+        return Err("Script name is required".to_string());
+    }
+    compile(&script.steps)?;
+    let steps = serde_json::to_string(&script.steps).map_err(|e| e.to_string())?;
+    secure_storage::with_database(|db| db.store_script(&script.id, &script.name, &steps))
+}
+
+pub fn load_script(script_id: &str) -> Result<AutomationScript, String> {
+    AutomationScript::from_stored(secure_storage::with_database(|db| db.get_script(script_id))?)
+}
+
+pub fn list_scripts() -> Result<Vec<AutomationScript>, String> {
+    secure_storage::with_database(|db| db.list_scripts())?
+        .into_iter()
+        .map(AutomationScript::from_stored)
+        .collect()
+}
+
+pub fn delete_script(script_id: &str) -> Result<(), String> {
+    secure_storage::with_database(|db| db.delete_script(script_id))
+}
+
+/// Start running `script` on a session. Output from now on is matched, plus the line the
+/// cursor is on, so a prompt that is already showing counts. Progress is emitted as
+/// `automation-step` events and the result as `automation-finished`.
+pub fn start(run_id: &str, session_id: &str, script: &AutomationScript, charset: &'static Encoding, window: Window) -> Result<(), String> {
+    let steps = compile(&script.steps)?;
+    let run = register(run_id, session_id, charset)?;
+
+    let (run_id, script_id) = (run_id.to_string(), script.id.clone());
+    tauri::async_runtime::spawn(async move {
+        let session_id = run.session_id.clone();
+        let send = |text: String| {
+            let session_id = session_id.clone();
+            async move { crate::write_session_input(&session_id, &text).await }
+        };
+        let on_step = |step, matched| {
+            let _ = window.emit("automation-step", StepEvent {
+                run_id: run_id.clone(),
+                session_id: session_id.clone(),
+                step,
+                matched,
+            });
+        };
+        let outcome = execute(&run, &steps, send, on_step).await;
+
+        RUNS.lock().remove(&run_id);
+        let result = run_result(&run_id, &script_id, &session_id, steps.len(), outcome);
+        let _ = window.emit("automation-finished", result);
+    });
+    Ok(())
+}
+
+fn register(run_id: &str, session_id: &str, charset: &'static Encoding) -> Result<Arc<Run>, String> {
+    let prompt = crate::scrollback::range(session_id, None, Some(0)).map(|range| range.current).unwrap_or_default();
+    let run = Arc::new(Run::new(session_id, charset, &prompt));
+    let mut runs = RUNS.lock();
+    if runs.contains_key(run_id) {
+        return Err(format!("Run {} is already running", run_id));
+    }
+    runs.insert(run_id.to_string(), run.clone());
+    Ok(run)
+}
+
+/// Wait for the session's output to match `pattern`, like a script with one `expect`
+/// step; None when `timeout` passes first. Cancelled with `cancel(run_id)`.
+pub async fn wait_for(run_id: &str, session_id: &str, charset: &'static Encoding, pattern: &str, timeout: Duration) -> Result<Option<String>, String> {
+    let regex = Regex::new(pattern).map_err(|e| format!("Invalid pattern: {}", e))?;
+    expect_deadline(timeout).map_err(|e| format!("Invalid wait: {}", e))?;
+    let steps = [CompiledStep::Expect { regex, timeout: Some(timeout) }];
+    let run = register(run_id, session_id, charset)?;
+    let mut matched = None;
+    let outcome = execute(&run, &steps, |_| async { Ok(()) }, |_, text| matched = text).await;
+    RUNS.lock().remove(run_id);
+    match outcome {
+        Ok(()) => Ok(matched),
+        // A single expect only fails by timing out
+        Err((_, Failure::Error(_))) => Ok(None),
+        Err((_, Failure::Stopped(StopReason::Cancelled))) => Err("Cancelled".to_string()),
+        Err((_, Failure::Stopped(StopReason::SessionClosed))) => Err("Session closed".to_string()),
+    }
+}
+
+/// Ids of the scripts currently running
+pub fn running_ids() -> Vec<String> {
+    RUNS.lock().keys().cloned().collect()
+}
+
+pub fn cancel(run_id: &str) -> Result<(), String> {
+    let run = RUNS.lock().get(run_id).cloned()
+        .ok_or_else(|| format!("No running script with id {}", run_id))?;
+    run.stop(StopReason::Cancelled);
+    Ok(())
+}
+
+/// Fail the session's runs; called when it closes
+pub fn stop_for_session(session_id: &str) {
+    for run in RUNS.lock().values().filter(|run| run.session_id == session_id) {
+        run.stop(StopReason::SessionClosed);
+    }
+}
+
+pub fn capture_output(session_id: &str, data: &[u8]) {
+    let runs = RUNS.lock();
+    for run in runs.values().filter(|run| run.session_id == session_id) {
+        run.push_output(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+
+    fn steps(json: &str) -> Vec<CompiledStep> {
+        compile(&serde_json::from_str::<Vec<Step>>(json).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_invalid_pattern_names_step() {
+        let script: Vec<Step> = serde_json::from_str(r#"[{"type": "send", "text": "x"}, {"type": "expect", "pattern": "("}]"#).unwrap();
+        assert!(compile(&script).err().unwrap().starts_with("Step 2: invalid pattern"));
+    }
+
+    #[tokio::test]
+    async fn test_unschedulable_timeouts_are_rejected() {
+        for json in [
+            r#"[{"type": "expect", "pattern": "$", "timeout_secs": 18446744073709551615}]"#,
+            r#"[{"type": "timeout", "secs": 18446744073709551615}]"#,
+        ] {
+            let script: Vec<Step> = serde_json::from_str(json).unwrap();
+            assert!(compile(&script).err().unwrap().starts_with("Step 1: timeout of"));
+        }
+        let waited = wait_for("auto-test-wait", "auto-test", UTF_8, "$", Duration::MAX).await;
+        assert!(waited.unwrap_err().starts_with("Invalid wait"));
+    }
+
+    #[tokio::test]
+    async fn test_expect_and_send() {
+        let run = Arc::new(Run::new("auto-test", UTF_8, "Last login: today\r\n"));
+        let steps = steps(r#"[
+            {"type": "send", "text": "sudo -i\r"},
+            {"type": "expect", "pattern": "password for \\w+:", "timeout_secs": 5},
+            {"type": "send", "text": "hunter2\r"},
+            {"type": "expect", "pattern": "ROOT#"},
+            {"type": "send", "text": "uptime\r"}
+        ]"#);
+
+        // The fake shell answers each line typed into it
+        let shell = run.clone();
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let sent_by_script = sent.clone();
+        let send = move |text: String| {
+            let reply: &[u8] = match text.as_str() {
+                "sudo -i\r" => b"sudo -i\r\n[sudo] password for \x1b[1madmin\x1b[0m: ",
+                "hunter2\r" => b"\r\n\x1b[31mroot\x1b[0m ROOT# ",
+                _ => b"",
+            };
+            shell.push_output(reply);
+            sent_by_script.lock().push(text);
+            async { Ok(()) }
+        };
+        let mut matches = Vec::new();
+        let outcome = execute(&run, &steps, send, |step, matched| matches.push((step, matched))).await;
+
+        assert!(outcome.is_ok());
+        assert_eq!(*sent.lock(), vec!["sudo -i\r", "hunter2\r", "uptime\r"]);
+        assert_eq!(matches[1], (1, Some("password for admin:".to_string())));
+        assert_eq!(matches[3], (3, Some("ROOT#".to_string())));
+        // Matched output is consumed
+        assert_eq!(run.output.lock().buffer, " ");
+    }
+
+    #[tokio::test]
+    async fn test_expect_timeout_fails_step() {
+        let run = Run::new("auto-test", UTF_8, "");
+        let steps = steps(r#"[{"type": "timeout", "secs": 0}, {"type": "expect", "pattern": "never"}]"#);
+        let outcome = execute(&run, &steps, |_| async { Ok(()) }, |_, _| {}).await;
+        let result = run_result("r", "s", "auto-test", steps.len(), outcome);
+        assert_eq!((result.status, result.failed_step, result.steps_completed), (RunStatus::Failed, Some(1), 1));
+        assert_eq!(result.error.as_deref(), Some("Timed out after 0s waiting for /never/"));
+    }
+
+    #[tokio::test]
+    async fn test_stop_wakes_waiting_expect() {
+        let run = Arc::new(Run::new("auto-test", UTF_8, ""));
+        let steps = steps(r#"[{"type": "expect", "pattern": "\\$ $"}, {"type": "expect", "pattern": "never"}]"#);
+        let waiting = run.clone();
+        let task = tokio::spawn(async move { execute(&waiting, &steps, |_| async { Ok(()) }, |_, _| {}).await });
+
+        // Split across chunks, and only a match once complete
+        run.push_output(b"user@web1:~");
+        run.push_output(b"$ ");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        run.stop(StopReason::SessionClosed);
+
+        let result = run_result("r", "s", "auto-test", 2, task.await.unwrap());
+        assert_eq!((result.status, result.failed_step), (RunStatus::Failed, Some(1)));
+        assert_eq!(result.error.as_deref(), Some("Session closed"));
+    }
+}
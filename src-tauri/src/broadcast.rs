@@ -0,0 +1,253 @@
+use futures::future::join_all;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+
+// Broadcast groups, keyed by group id. Sessions come and go, so groups are not saved.
+static GROUPS: Lazy<Mutex<HashMap<String, Group>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Default)]
+struct Group {
+    members: Vec<String>,
+    // Members input is not sent to for now; they stay in the group
+    excluded: HashSet<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BroadcastGroup {
+    pub group_id: String,
+    /// In the order they were added
+    pub session_ids: Vec<String>,
+    pub excluded: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberFailure {
+    pub session_id: String,
+    pub error: String,
+}
+
+/// What became of one write to a group
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BroadcastResult {
+    pub group_id: String,
+    pub written: Vec<String>,
+    pub excluded: Vec<String>,
+    pub failed: Vec<MemberFailure>,
+}
+
+impl Group {
+    fn info(&self, group_id: &str) -> BroadcastGroup {
+        BroadcastGroup {
+            group_id: group_id.to_string(),
+            session_ids: self.members.clone(),
+            excluded: self.members.iter().filter(|id| self.excluded.contains(*id)).cloned().collect(),
+        }
+    }
+
+    /// (members input goes to, excluded members)
+    fn targets(&self) -> (Vec<String>, Vec<String>) {
+        let (excluded, targets): (Vec<String>, Vec<String>) = self.members.iter()
+            .cloned()
+            .partition(|id| self.excluded.contains(id));
+        (targets, excluded)
+    }
+}
+
+/// Create the group, or replace its members. Members that stay keep being excluded. A session
+/// can be in one group at a time, so input typed into it has one group to go to.
+pub fn set_group(group_id: &str, session_ids: Vec<String>) -> Result<BroadcastGroup, String> {
+    if group_id.trim().is_empty() {
+        return Err("Group id is empty".to_string());
+    }
+    let mut members = Vec::new();
+    for session_id in session_ids {
+        if !members.contains(&session_id) {
+            members.push(session_id);
+        }
+    }
+    let mut groups = GROUPS.lock();
+    for (other_id, other) in groups.iter().filter(|(id, _)| id.as_str() != group_id) {
+        if let Some(taken) = members.iter().find(|id| other.members.contains(id)) {
+            return Err(format!("Session {} is already in broadcast group {}", taken, other_id));
+        }
+    }
+    let group = groups.entry(group_id.to_string()).or_default();
+    group.excluded.retain(|id| members.contains(id));
+    group.members = members;
+    Ok(group.info(group_id))
+}
+
+pub fn delete_group(group_id: &str) -> Result<(), String> {
+    GROUPS.lock().remove(group_id)
+        .map(|_| ())
+        .ok_or_else(|| format!("Broadcast group {} not found", group_id))
+}
+
+/// Sorted by group id
+pub fn groups() -> Vec<BroadcastGroup> {
+    let groups = GROUPS.lock();
+    let mut list: Vec<BroadcastGroup> = groups.iter().map(|(id, group)| group.info(id)).collect();
+    list.sort_by(|a, b| a.group_id.cmp(&b.group_id));
+    list
+}
+
+/// Stop (or resume) sending the group's input to one of its members
+pub fn set_excluded(group_id: &str, session_id: &str, excluded: bool) -> Result<BroadcastGroup, String> {
+    let mut groups = GROUPS.lock();
+    let group = groups.get_mut(group_id)
+        .ok_or_else(|| format!("Broadcast group {} not found", group_id))?;
+    if !group.members.iter().any(|id| id == session_id) {
+        return Err(format!("Session {} is not in broadcast group {}", session_id, group_id));
+    }
+    if excluded {
+        group.excluded.insert(session_id.to_string());
+    } else {
+        group.excluded.remove(session_id);
+    }
+    Ok(group.info(group_id))
+}
+
+/// Take a closed session out of every group
+pub fn remove_session(session_id: &str) {
+    for group in GROUPS.lock().values_mut() {
+        group.members.retain(|id| id != session_id);
+        group.excluded.remove(session_id);
+    }
+}
+
+/// Send `data` to every member that is not excluded, all at once so one stalled session
+/// does not hold up the rest. A member that fails does not stop the others.
+pub async fn write<F, Fut>(group_id: &str, data: &str, write_member: F) -> Result<BroadcastResult, String>
+where
+    F: Fn(String, String) -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let (targets, excluded) = GROUPS.lock().get(group_id)
+        .ok_or_else(|| format!("Broadcast group {} not found", group_id))?
+        .targets();
+    Ok(send(group_id.to_string(), targets, excluded, data, write_member).await)
+}
+
+/// Input typed into `session_id`: when it is a member of a group and not excluded, `data` goes
+/// to the whole group (itself included), as `write` does. None when it is not, and the input is
+/// the session's alone.
+pub async fn write_from<F, Fut>(session_id: &str, data: &str, write_member: F) -> Option<BroadcastResult>
+where
+    F: Fn(String, String) -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let (group_id, (targets, excluded)) = {
+        let groups = GROUPS.lock();
+        let (group_id, group) = groups.iter()
+            .find(|(_, group)| group.members.iter().any(|id| id == session_id))?;
+        if group.excluded.contains(session_id) {
+            return None;
+        }
+        (group_id.clone(), group.targets())
+    };
+    Some(send(group_id, targets, excluded, data, write_member).await)
+}
+
+async fn send<F, Fut>(group_id: String, targets: Vec<String>, excluded: Vec<String>, data: &str, write_member: F) -> BroadcastResult
+where
+    F: Fn(String, String) -> Fut,
+    Fut: Future<Output = Result<(), String>>,
+{
+    let results = join_all(targets.iter().map(|id| write_member(id.clone(), data.to_string()))).await;
+    let mut result = BroadcastResult {
+        group_id,
+        written: Vec::new(),
+        excluded,
+        failed: Vec::new(),
+    };
+    for (session_id, outcome) in targets.into_iter().zip(results) {
+        match outcome {
+            Ok(()) => result.written.push(session_id),
+            Err(error) => result.failed.push(MemberFailure { session_id, error }),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_group_membership() {
+        let group = set_group("bc-test-1", ids(&["a", "b", "a", "c"])).unwrap();
+        assert_eq!(group.session_ids, ids(&["a", "b", "c"]));
+
+        set_excluded("bc-test-1", "b", true).unwrap();
+        assert!(set_excluded("bc-test-1", "z", true).is_err());
+        // Replacing the members keeps b excluded
+        let group = set_group("bc-test-1", ids(&["b", "c", "d"])).unwrap();
+        assert_eq!(group.excluded, ids(&["b"]));
+
+        remove_session("b");
+        let group = set_excluded("bc-test-1", "c", false).unwrap();
+        assert_eq!((group.session_ids, group.excluded), (ids(&["c", "d"]), Vec::new()));
+
+        assert!(set_group(" ", Vec::new()).is_err());
+        let taken = set_group("bc-test-1b", ids(&["e", "d"])).unwrap_err();
+        assert_eq!(taken, "Session d is already in broadcast group bc-test-1");
+        delete_group("bc-test-1").unwrap();
+        assert!(delete_group("bc-test-1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_write_reports_each_member() {
+        set_group("bc-test-2", ids(&["ok1", "gone", "skip", "ok2"])).unwrap();
+        set_excluded("bc-test-2", "skip", true).unwrap();
+        let sent = Mutex::new(Vec::new());
+
+        let result = write("bc-test-2", "uptime\r", |session_id, data| {
+            let outcome = match session_id.as_str() {
+                "gone" => Err("Session not found. Please connect first.".to_string()),
+                _ => {
+                    sent.lock().push((session_id, data));
+                    Ok(())
+                }
+            };
+            async move { outcome }
+        }).await.unwrap();
+
+        assert_eq!(result.written, ids(&["ok1", "ok2"]));
+        assert_eq!(result.excluded, ids(&["skip"]));
+        assert_eq!(result.failed.len(), 1);
+        assert_eq!(result.failed[0].session_id, "gone");
+        assert_eq!(sent.lock().len(), 2);
+        assert!(sent.lock().iter().all(|(_, data)| data == "uptime\r"));
+
+        delete_group("bc-test-2").unwrap();
+        assert!(write("bc-test-2", "x", |_, _| async { Ok(()) }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_input_typed_into_a_member_goes_to_the_group() {
+        set_group("bc-test-3", ids(&["m1", "m2", "m3"])).unwrap();
+        set_excluded("bc-test-3", "m3", true).unwrap();
+        let sent = Mutex::new(Vec::new());
+        let write_member = |session_id: String, _: String| {
+            sent.lock().push(session_id);
+            async { Ok(()) }
+        };
+
+        let result = write_from("m2", "ls\r", write_member).await.unwrap();
+        assert_eq!((result.group_id.as_str(), result.excluded), ("bc-test-3", ids(&["m3"])));
+        assert_eq!(*sent.lock(), ids(&["m1", "m2"]));
+
+        // Input typed into an excluded member, or a session outside every group, stays there
+        assert!(write_from("m3", "x", write_member).await.is_none());
+        assert!(write_from("loner", "x", write_member).await.is_none());
+        assert_eq!(sent.lock().len(), 2);
+        delete_group("bc-test-3").unwrap();
+    }
+}
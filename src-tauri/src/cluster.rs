@@ -0,0 +1,310 @@
+use crate::{exec, proxy_jump};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use ssh2::Session;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use tauri::Window;
+
+const DEFAULT_PARALLELISM: usize = 8;
+
+static NEXT_RUN_ID: AtomicU64 = AtomicU64::new(1);
+
+// Cancellation flags of running cluster runs, keyed by run id
+static RUNS: Lazy<Mutex<HashMap<String, Arc<AtomicBool>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A saved server; its credentials are stored in the secure database under `server_id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterHost {
+    pub server_id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub host: String,
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub jump_hosts: Vec<proxy_jump::JumpHost>,
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterExecRequest {
+    pub hosts: Vec<ClusterHost>,
+    pub command: String,
+    /// Hosts connected to at the same time
+    #[serde(default)]
+    pub parallelism: Option<usize>,
+    /// Per-host command timeout
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostResult {
+    pub server_id: String,
+    pub host: String,
+    /// Set when the command ran, whatever its exit status
+    pub output: Option<exec::ExecOutput>,
+    /// Connection or authentication failure
+    pub error: Option<String>,
+}
+
+/// Hosts that produced byte-identical stdout with the same exit status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutputGroup {
+    pub stdout: String,
+    pub exit_status: Option<i32>,
+    pub server_ids: Vec<String>,
+}
+
+/// Hosts that could not run the command, grouped by error message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorGroup {
+    pub error: String,
+    pub server_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterSummary {
+    pub run_id: String,
+    pub total: usize,
+    /// Exit status 0
+    pub succeeded: usize,
+    /// Ran, but exited non-zero, was killed or timed out
+    pub failed: usize,
+    /// Never got to run the command
+    pub unreachable: usize,
+    pub cancelled: bool,
+    /// Largest group first
+    pub groups: Vec<OutputGroup>,
+    pub errors: Vec<ErrorGroup>,
+}
+
+pub fn next_run_id() -> String {
+    format!("cluster-{}", NEXT_RUN_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Stop a run: hosts not started yet are skipped and running commands are cancelled
+pub fn cancel(run_id: &str) -> Result<(), String> {
+    let flag = RUNS.lock().get(run_id).cloned()
+        .ok_or_else(|| format!("No running cluster command with id {}", run_id))?;
+    flag.store(true, Ordering::Relaxed);
+
+    let prefix = format!("{}:", run_id);
+    for exec_id in exec::running_ids().into_iter().filter(|id| id.starts_with(&prefix)) {
+        let _ = exec::cancel(&exec_id);
+    }
+    Ok(())
+}
+
+/// Run `request.command` on every host, at most `parallelism` at a time.
+///
+/// `connect` opens an authenticated session for a host. Each host's result is emitted as a
+/// `cluster-exec-host-result` event as soon as it is known; the summary is emitted as
+/// `cluster-exec-finished` and returned.
+pub fn run(
+    run_id: &str,
+    request: &ClusterExecRequest,
+    window: &Window,
+    connect: impl Fn(&ClusterHost) -> Result<Session, String> + Sync,
+) -> ClusterSummary {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    RUNS.lock().insert(run_id.to_string(), cancelled.clone());
+
+    let parallelism = request.parallelism.unwrap_or(DEFAULT_PARALLELISM).clamp(1, request.hosts.len().max(1));
+    let next_host = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(request.hosts.len()));
+    let exec_request = exec::ExecRequest {
+        command: request.command.clone(),
+        timeout_secs: request.timeout_secs,
+        ..Default::default()
+    };
+
+    thread::scope(|scope| {
+        for _ in 0..parallelism {
+            scope.spawn(|| loop {
+                if cancelled.load(Ordering::Relaxed) {
+                    break;
+                }
+                let Some(host) = request.hosts.get(next_host.fetch_add(1, Ordering::Relaxed)) else { break };
+
+                let _ = window.emit("cluster-exec-host-started", serde_json::json!({
+                    "run_id": run_id,
+                    "server_id": host.server_id,
+                    "name": host.name,
+                    "host": host.host
+                }));
+
+                let result = run_on_host(run_id, host, &exec_request, &connect);
+                let _ = window.emit("cluster-exec-host-result", serde_json::json!({
+                    "run_id": run_id,
+                    "result": result
+                }));
+                results.lock().push(result);
+            });
+        }
+    });
+
+    RUNS.lock().remove(run_id);
+
+    let mut summary = summarize(run_id, &results.into_inner());
+    summary.total = request.hosts.len();
+    summary.cancelled = cancelled.load(Ordering::Relaxed);
+    let _ = window.emit("cluster-exec-finished", &summary);
+    summary
+}
+
+fn run_on_host(
+    run_id: &str,
+    host: &ClusterHost,
+    request: &exec::ExecRequest,
+    connect: &(impl Fn(&ClusterHost) -> Result<Session, String> + Sync),
+) -> HostResult {
+    let mut result = HostResult {
+        server_id: host.server_id.clone(),
+        host: host.host.clone(),
+        output: None,
+        error: None,
+    };
+
+    let sess = match connect(host) {
+        Ok(sess) => sess,
+        Err(e) => {
+            result.error = Some(e);
+            return result;
+        }
+    };
+
+    let exec_id = format!("{}:{}", run_id, host.server_id);
+    match exec::run(&sess, &exec_id, request, |_, _| {}) {
+        Ok(output) => result.output = Some(output),
+        Err(e) => result.error = Some(e),
+    }
+    let _ = sess.disconnect(None, "Cluster command finished", None);
+    result
+}
+
+/// Group per-host results by identical output and by error message
+fn summarize(run_id: &str, results: &[HostResult]) -> ClusterSummary {
+    let mut summary = ClusterSummary {
+        run_id: run_id.to_string(),
+        total: results.len(),
+        succeeded: 0,
+        failed: 0,
+        unreachable: 0,
+        cancelled: false,
+        groups: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    for result in results {
+        match (&result.output, &result.error) {
+            (Some(output), _) => {
+                if output.success() {
+                    summary.succeeded += 1;
+                } else {
+                    summary.failed += 1;
+                }
+                match summary.groups.iter_mut()
+                    .find(|g| g.stdout == output.stdout && g.exit_status == output.exit_status)
+                {
+                    Some(group) => group.server_ids.push(result.server_id.clone()),
+                    None => summary.groups.push(OutputGroup {
+                        stdout: output.stdout.clone(),
+                        exit_status: output.exit_status,
+                        server_ids: vec![result.server_id.clone()],
+                    }),
+                }
+            }
+            (None, error) => {
+                summary.unreachable += 1;
+                let error = error.clone().unwrap_or_else(|| "Unknown error".to_string());
+                match summary.errors.iter_mut().find(|g| g.error == error) {
+                    Some(group) => group.server_ids.push(result.server_id.clone()),
+                    None => summary.errors.push(ErrorGroup { error, server_ids: vec![result.server_id.clone()] }),
+                }
+            }
+        }
+    }
+
+    // Stable sort keeps groups of equal size in completion order
+    summary.groups.sort_by_key(|g| std::cmp::Reverse(g.server_ids.len()));
+    summary.errors.sort_by_key(|g| std::cmp::Reverse(g.server_ids.len()));
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ran(server_id: &str, stdout: &str, exit_status: i32) -> HostResult {
+        HostResult {
+            server_id: server_id.to_string(),
+            host: format!("{}.example.com", server_id),
+            output: Some(exec::ExecOutput {
+                stdout: stdout.to_string(),
+                exit_status: Some(exit_status),
+                ..Default::default()
+            }),
+            error: None,
+        }
+    }
+
+    fn unreachable(server_id: &str, error: &str) -> HostResult {
+        HostResult {
+            server_id: server_id.to_string(),
+            host: format!("{}.example.com", server_id),
+            output: None,
+            error: Some(error.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_summarize_groups_identical_output() {
+        let results = vec![
+            ran("web1", "ok\n", 0),
+            ran("web2", "disk full\n", 1),
+            ran("web3", "ok\n", 0),
+            unreachable("db1", "Connection refused"),
+            ran("web4", "ok\n", 0),
+            unreachable("db2", "Connection refused"),
+        ];
+        let summary = summarize("cluster-1", &results);
+
+        assert_eq!(summary.total, 6);
+        assert_eq!(summary.succeeded, 3);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(summary.unreachable, 2);
+
+        assert_eq!(summary.groups.len(), 2);
+        assert_eq!(summary.groups[0].stdout, "ok\n");
+        assert_eq!(summary.groups[0].server_ids, vec!["web1", "web3", "web4"]);
+        assert_eq!(summary.groups[1].server_ids, vec!["web2"]);
+
+        assert_eq!(summary.errors.len(), 1);
+        assert_eq!(summary.errors[0].server_ids, vec!["db1", "db2"]);
+    }
+
+    #[test]
+    fn test_summarize_separates_exit_status() {
+        let results = vec![ran("a", "", 0), ran("b", "", 2)];
+        let summary = summarize("cluster-2", &results);
+        assert_eq!(summary.groups.len(), 2);
+    }
+
+    #[test]
+    fn test_cluster_host_defaults() {
+        let host: ClusterHost = serde_json::from_str(
+            r#"{"server_id":"srv-1","host":"10.0.0.5"}"#,
+        ).unwrap();
+        assert_eq!(host.port, 22);
+        assert!(host.jump_hosts.is_empty());
+    }
+}
@@ -0,0 +1,272 @@
+use crate::tunnel;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use ssh2::Session;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static NEXT_EXEC_ID: AtomicU64 = AtomicU64::new(1);
+
+// Cancellation flags of running commands, keyed by exec id
+static RUNNING: Lazy<Mutex<HashMap<String, Arc<AtomicBool>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExecRequest {
+    pub command: String,
+    /// Written to the command's stdin before EOF is sent
+    #[serde(default)]
+    pub stdin: Option<String>,
+    /// The channel is closed once this elapses; `timed_out` is set in the result
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Deliver output through the chunk callback instead of collecting it
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExecOutput {
+    pub exec_id: String,
+    /// Empty in streaming mode
+    pub stdout: String,
+    pub stderr: String,
+    /// None if the command was killed by a signal, timed out or was cancelled
+    pub exit_status: Option<i32>,
+    /// Signal name without the "SIG" prefix, e.g. "TERM"
+    pub exit_signal: Option<String>,
+    pub timed_out: bool,
+    pub cancelled: bool,
+    pub duration_ms: u64,
+}
+
+impl ExecOutput {
+    pub fn success(&self) -> bool {
+        self.exit_status == Some(0)
+    }
+}
+
+pub fn next_exec_id() -> String {
+    format!("exec-{}", NEXT_EXEC_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Stop a running command; its result comes back with `cancelled` set
+pub fn cancel(exec_id: &str) -> Result<(), String> {
+    let flag = RUNNING.lock().get(exec_id).cloned()
+        .ok_or_else(|| format!("No running command with id {}", exec_id))?;
+    flag.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Ids of the commands currently running
+pub fn running_ids() -> Vec<String> {
+    RUNNING.lock().keys().cloned().collect()
+}
+
+/// Run a command on its own `exec` channel of `sess` and wait for it to finish.
+///
+/// Works with blocking and non-blocking sessions alike, so it can share the PTY's session.
+/// In streaming mode every chunk of output is passed to `on_output` as it arrives.
+pub fn run(
+    sess: &Session,
+    exec_id: &str,
+    request: &ExecRequest,
+    mut on_output: impl FnMut(OutputStream, &[u8]),
+) -> Result<ExecOutput, String> {
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    RUNNING.lock().insert(exec_id.to_string(), cancel_flag.clone());
+    let result = run_channel(sess, exec_id, request, &cancel_flag, &mut on_output);
+    RUNNING.lock().remove(exec_id);
+    result
+}
+
+// Switches a session to non-blocking mode until dropped, then restores the mode it had, on
+// error returns too
+struct NonBlocking<'a> {
+    sess: &'a Session,
+    was_blocking: bool,
+}
+
+impl<'a> NonBlocking<'a> {
+    fn enter(sess: &'a Session) -> Self {
+        let was_blocking = sess.is_blocking();
+        sess.set_blocking(false);
+        NonBlocking { sess, was_blocking }
+    }
+}
+
+impl Drop for NonBlocking<'_> {
+    fn drop(&mut self) {
+        self.sess.set_blocking(self.was_blocking);
+    }
+}
+
+fn run_channel(
+    sess: &Session,
+    exec_id: &str,
+    request: &ExecRequest,
+    cancel_flag: &AtomicBool,
+    on_output: &mut dyn FnMut(OutputStream, &[u8]),
+) -> Result<ExecOutput, String> {
+    let started = Instant::now();
+    let timeout = request.timeout_secs.map(Duration::from_secs);
+
+    // Non-blocking, so stdout and stderr can be drained side by side and the timeout enforced.
+    // Declared before the channel, so the mode is restored after the channel is dropped.
+    let _mode = NonBlocking::enter(sess);
+
+    let mut channel = tunnel::retry_would_block(|| sess.channel_session())
+        .map_err(|e| format!("Failed to open channel: {}", e))?;
+    tunnel::retry_would_block(|| channel.exec(&request.command))
+        .map_err(|e| format!("Failed to run command: {}", e))?;
+
+    if let Some(stdin) = &request.stdin {
+        tunnel::write_all_blocking(&mut channel, stdin.as_bytes())
+            .map_err(|e| format!("Failed to write stdin: {}", e))?;
+    }
+    tunnel::retry_would_block(|| channel.send_eof())
+        .map_err(|e| format!("Failed to close stdin: {}", e))?;
+
+    let mut output = ExecOutput { exec_id: exec_id.to_string(), ..Default::default() };
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut buffer = vec![0u8; 32768];
+
+    loop {
+        if cancel_flag.load(Ordering::Relaxed) {
+            output.cancelled = true;
+            break;
+        }
+        if timeout.is_some_and(|t| started.elapsed() >= t) {
+            output.timed_out = true;
+            break;
+        }
+
+        let mut progressed = false;
+        for stream in [OutputStream::Stdout, OutputStream::Stderr] {
+            let read = match stream {
+                OutputStream::Stdout => channel.read(&mut buffer),
+                OutputStream::Stderr => channel.stderr().read(&mut buffer),
+            };
+            match read {
+                Ok(0) => {}
+                Ok(n) => {
+                    progressed = true;
+                    if request.stream {
+                        on_output(stream, &buffer[..n]);
+                    } else if stream == OutputStream::Stdout {
+                        stdout.extend_from_slice(&buffer[..n]);
+                    } else {
+                        stderr.extend_from_slice(&buffer[..n]);
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(format!("Failed to read command output: {}", e)),
+            }
+        }
+
+        if !progressed {
+            if channel.eof() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    let finished = !output.timed_out && !output.cancelled;
+    let _ = tunnel::retry_would_block(|| channel.close());
+    if finished {
+        let _ = tunnel::retry_would_block(|| channel.wait_close());
+        let signal = channel.exit_signal().ok().and_then(|s| s.exit_signal);
+        if signal.is_some() {
+            output.exit_signal = signal;
+        } else {
+            output.exit_status = channel.exit_status().ok();
+        }
+    }
+
+    output.stdout = String::from_utf8_lossy(&stdout).to_string();
+    output.stderr = String::from_utf8_lossy(&stderr).to_string();
+    output.duration_ms = started.elapsed().as_millis() as u64;
+    Ok(output)
+}
+
+/// Run a command and return its stdout, failing on a non-zero exit
+pub fn capture(sess: &Session, command: &str, timeout_secs: u64) -> Result<String, String> {
+    let request = ExecRequest {
+        command: command.to_string(),
+        timeout_secs: Some(timeout_secs),
+        ..Default::default()
+    };
+    let output = run(sess, &next_exec_id(), &request, |_, _| {})?;
+    if output.timed_out {
+        return Err(format!("`{}` timed out after {}s", command, timeout_secs));
+    }
+    if !output.success() {
+        let detail = output.stderr.trim();
+        return Err(match (output.exit_status, &output.exit_signal) {
+            (_, Some(signal)) => format!("`{}` was killed by SIG{}: {}", command, signal, detail),
+            (Some(status), _) => format!("`{}` exited with status {}: {}", command, status, detail),
+            (None, None) => format!("`{}` did not report an exit status", command),
+        });
+    }
+    Ok(output.stdout)
+}
+
+/// Quote a string for a POSIX shell
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("/tmp/file.txt"), "'/tmp/file.txt'");
+        assert_eq!(shell_quote("it's here"), r"'it'\''s here'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_exec_request_defaults() {
+        let request: ExecRequest = serde_json::from_str(r#"{"command":"uptime"}"#).unwrap();
+        assert_eq!(request.command, "uptime");
+        assert!(request.stdin.is_none());
+        assert!(request.timeout_secs.is_none());
+        assert!(!request.stream);
+    }
+
+    #[test]
+    fn test_blocking_mode_restored() {
+        let sess = Session::new().unwrap();
+        {
+            let _mode = NonBlocking::enter(&sess);
+            assert!(!sess.is_blocking());
+        }
+        assert!(sess.is_blocking());
+    }
+
+    #[test]
+    fn test_cancel_unknown_exec() {
+        assert!(cancel("exec-does-not-exist").is_err());
+    }
+
+    #[test]
+    fn test_exec_ids_are_unique() {
+        assert_ne!(next_exec_id(), next_exec_id());
+    }
+}
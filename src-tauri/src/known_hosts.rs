@@ -0,0 +1,337 @@
+use crate::ssh_auth::Prompts;
+use base64::{Engine as _, engine::general_purpose};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use ssh2::{CheckResult, HashType, HostKeyType, KnownHostFileKind, KnownHostKeyFormat, KnownHosts, Session};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+// How long pty_connect waits for the user to accept or reject an unknown host key
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Unknown host keys waiting for a decision from the frontend, keyed by session_id
+static PENDING_PROMPTS: Lazy<Mutex<HashMap<String, PendingHostKey>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct PendingHostKey {
+    info: HostKeyInfo,
+    decision: mpsc::Sender<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostKeyInfo {
+    pub host: String,
+    pub port: u16,
+    pub key_type: String,
+    pub fingerprint: String,
+    #[serde(skip)]
+    key: Vec<u8>,
+    #[serde(skip)]
+    format: Option<KnownHostKeyFormat>,
+}
+
+pub enum HostKeyCheck {
+    Trusted,
+    Unknown(HostKeyInfo),
+    Changed(HostKeyInfo),
+}
+
+/// App-managed known_hosts file, stored next to the database
+pub fn app_known_hosts_path() -> Result<PathBuf, String> {
+    Ok(crate::app_data_dir()?.join("known_hosts"))
+}
+
+/// The user's OpenSSH known_hosts file, if a home directory can be found
+pub fn user_known_hosts_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".ssh").join("known_hosts"))
+}
+
+/// Name used for a host in known_hosts files ("[host]:port" for non-default ports)
+pub fn host_entry_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+/// Format a raw SHA256 digest the way OpenSSH prints it
+pub fn format_fingerprint(hash: &[u8]) -> String {
+    format!("SHA256:{}", general_purpose::STANDARD_NO_PAD.encode(hash))
+}
+
+fn key_type_name(key_type: HostKeyType) -> &'static str {
+    match key_type {
+        HostKeyType::Rsa => "ssh-rsa",
+        HostKeyType::Dss => "ssh-dss",
+        HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+        HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+        HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+        HostKeyType::Ed25519 => "ssh-ed25519",
+        HostKeyType::Unknown => "unknown",
+    }
+}
+
+/// Check a raw host key against every known_hosts file in `files`
+fn check_key_in_files(
+    sess: &Session,
+    files: &[PathBuf],
+    host: &str,
+    port: u16,
+    key: &[u8],
+) -> Result<CheckResult, String> {
+    let mut known_hosts = sess.known_hosts()
+        .map_err(|e| format!("Failed to initialize known hosts: {}", e))?;
+
+    for file in files {
+        if file.exists() {
+            read_entries(&mut known_hosts, file)?;
+        }
+    }
+
+    Ok(known_hosts.check_port(host, port, key))
+}
+
+// Load a known_hosts file one line at a time. libssh2's own reader stops at the first line
+// it does not support (e.g. @cert-authority), which would hide every entry after it and
+// turn a changed key into an unknown one; here only the unsupported lines are left out.
+fn read_entries(known_hosts: &mut KnownHosts, path: &Path) -> Result<(), String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let _ = known_hosts.read_str(line, KnownHostFileKind::OpenSSH);
+    }
+    Ok(())
+}
+
+fn add_key_to_file(
+    path: &Path,
+    host: &str,
+    port: u16,
+    key: &[u8],
+    format: KnownHostKeyFormat,
+) -> Result<(), String> {
+    let sess = Session::new()
+        .map_err(|e| format!("Failed to create SSH session: {}", e))?;
+    let mut known_hosts = sess.known_hosts()
+        .map_err(|e| format!("Failed to initialize known hosts: {}", e))?;
+
+    if path.exists() {
+        known_hosts.read_file(path, KnownHostFileKind::OpenSSH)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    } else if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+
+    known_hosts.add(&host_entry_name(host, port), key, "added by NebulaTerm", format)
+        .map_err(|e| format!("Failed to add host key: {}", e))?;
+    known_hosts.write_file(path, KnownHostFileKind::OpenSSH)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    Ok(())
+}
+
+fn remove_host_from_file(path: &Path, host: &str, port: u16) -> Result<bool, String> {
+    if !path.exists() {
+        return Ok(false);
+    }
+
+    let sess = Session::new()
+        .map_err(|e| format!("Failed to create SSH session: {}", e))?;
+    let mut known_hosts = sess.known_hosts()
+        .map_err(|e| format!("Failed to initialize known hosts: {}", e))?;
+    known_hosts.read_file(path, KnownHostFileKind::OpenSSH)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+    let entry_name = host_entry_name(host, port);
+    let hosts = known_hosts.hosts()
+        .map_err(|e| format!("Failed to list known hosts: {}", e))?;
+
+    let mut removed = false;
+    for entry in hosts.iter().filter(|h| h.name() == Some(entry_name.as_str())) {
+        known_hosts.remove(entry)
+            .map_err(|e| format!("Failed to remove host key: {}", e))?;
+        removed = true;
+    }
+
+    if removed {
+        known_hosts.write_file(path, KnownHostFileKind::OpenSSH)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+
+    Ok(removed)
+}
+
+/// Check the server's host key against ~/.ssh/known_hosts and the app-managed file
+pub fn check_host_key(sess: &Session, host: &str, port: u16) -> Result<HostKeyCheck, String> {
+    let (key, key_type) = sess.host_key()
+        .ok_or("Server did not provide a host key")?;
+    let fingerprint = sess.host_key_hash(HashType::Sha256)
+        .map(format_fingerprint)
+        .ok_or("Failed to compute host key fingerprint")?;
+
+    let info = HostKeyInfo {
+        host: host.to_string(),
+        port,
+        key_type: key_type_name(key_type).to_string(),
+        fingerprint,
+        key: key.to_vec(),
+        format: Some(key_type.into()),
+    };
+
+    let mut files: Vec<PathBuf> = user_known_hosts_path().into_iter().collect();
+    files.push(app_known_hosts_path()?);
+
+    match check_key_in_files(sess, &files, host, port, key)? {
+        CheckResult::Match => Ok(HostKeyCheck::Trusted),
+        CheckResult::NotFound => Ok(HostKeyCheck::Unknown(info)),
+        CheckResult::Mismatch => Ok(HostKeyCheck::Changed(info)),
+        CheckResult::Failure => Err("Failed to check host key against known_hosts".to_string()),
+    }
+}
+
+/// Verify the host key after the handshake, prompting the frontend for unknown hosts.
+///
+/// Blocks until the user accepts or rejects the key via `accept_host_key` / `reject_host_key`.
+/// Unattended connections refuse unknown hosts straight away.
+pub fn verify_host_key(sess: &Session, host: &str, port: u16, prompts: Prompts) -> Result<(), String> {
+    let info = match check_host_key(sess, host, port)? {
+        HostKeyCheck::Trusted => return Ok(()),
+        HostKeyCheck::Changed(info) => {
+            return Err(format!(
+                "HOST KEY VERIFICATION FAILED: the {} key for {} has changed (now {}). \
+                 This could be a man-in-the-middle attack. Remove the old key if the change is expected.",
+                info.key_type, host_entry_name(host, port), info.fingerprint
+            ));
+        }
+        HostKeyCheck::Unknown(info) => info,
+    };
+    let Prompts::Frontend { session_id, window } = prompts else {
+        return Err(format!(
+            "Host key for {} is not trusted yet ({} {}). Connect to it from a terminal once to accept it.",
+            host_entry_name(host, port), info.key_type, info.fingerprint
+        ));
+    };
+
+    let (tx, rx) = mpsc::channel();
+    PENDING_PROMPTS.lock().insert(session_id.to_string(), PendingHostKey {
+        info: info.clone(),
+        decision: tx,
+    });
+
+    let _ = window.emit("pty-hostkey-prompt", serde_json::json!({
+        "session_id": session_id,
+        "host": info.host,
+        "port": info.port,
+        "key_type": info.key_type,
+        "fingerprint": info.fingerprint
+    }));
+
+    let decision = rx.recv_timeout(PROMPT_TIMEOUT);
+    PENDING_PROMPTS.lock().remove(session_id);
+
+    match decision {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("Host key for {} was rejected", host_entry_name(host, port))),
+        Err(_) => Err("Timed out waiting for host key confirmation".to_string()),
+    }
+}
+
+/// Trust the pending host key for a session and persist it to the app known_hosts file
+pub fn accept_pending(session_id: &str) -> Result<HostKeyInfo, String> {
+    let pending = PENDING_PROMPTS.lock().remove(session_id)
+        .ok_or("No host key confirmation pending for this session")?;
+
+    let info = pending.info;
+    let format = info.format.unwrap_or(KnownHostKeyFormat::Unknown);
+    add_key_to_file(&app_known_hosts_path()?, &info.host, info.port, &info.key, format)?;
+
+    let _ = pending.decision.send(true);
+    Ok(info)
+}
+
+/// Reject the pending host key for a session, aborting its connection attempt
+pub fn reject_pending(session_id: &str) -> Result<(), String> {
+    let pending = PENDING_PROMPTS.lock().remove(session_id)
+        .ok_or("No host key confirmation pending for this session")?;
+    let _ = pending.decision.send(false);
+    Ok(())
+}
+
+/// Remove a host from the app-managed known_hosts file. ~/.ssh/known_hosts is never modified.
+pub fn forget_host(host: &str, port: u16) -> Result<bool, String> {
+    remove_host_from_file(&app_known_hosts_path()?, host, port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const KEY_A: &[u8] = b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    const KEY_B: &[u8] = b"\x00\x00\x00\x0bssh-ed25519\x00\x00\x00\x20bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+
+    #[test]
+    fn test_host_entry_name() {
+        assert_eq!(host_entry_name("example.com", 22), "example.com");
+        assert_eq!(host_entry_name("example.com", 2222), "[example.com]:2222");
+    }
+
+    #[test]
+    fn test_format_fingerprint_has_no_padding() {
+        let fingerprint = format_fingerprint(&[0u8; 32]);
+        assert_eq!(fingerprint, "SHA256:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA");
+        assert!(!fingerprint.ends_with('='));
+    }
+
+    #[test]
+    fn test_add_check_and_forget_host_key() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("known_hosts");
+        let sess = Session::new().unwrap();
+        let files = vec![path.clone()];
+
+        let result = check_key_in_files(&sess, &files, "example.com", 2222, KEY_A).unwrap();
+        assert!(matches!(result, CheckResult::NotFound));
+
+        add_key_to_file(&path, "example.com", 2222, KEY_A, KnownHostKeyFormat::Ed25519).unwrap();
+
+        let result = check_key_in_files(&sess, &files, "example.com", 2222, KEY_A).unwrap();
+        assert!(matches!(result, CheckResult::Match));
+
+        let result = check_key_in_files(&sess, &files, "example.com", 2222, KEY_B).unwrap();
+        assert!(matches!(result, CheckResult::Mismatch));
+
+        assert!(remove_host_from_file(&path, "example.com", 2222).unwrap());
+        assert!(!remove_host_from_file(&path, "example.com", 2222).unwrap());
+
+        let result = check_key_in_files(&sess, &files, "example.com", 2222, KEY_A).unwrap();
+        assert!(matches!(result, CheckResult::NotFound));
+    }
+
+    #[test]
+    fn test_entries_after_unsupported_lines_are_checked() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("known_hosts");
+        add_key_to_file(&path, "example.com", 22, KEY_A, KnownHostKeyFormat::Ed25519).unwrap();
+        let entries = std::fs::read_to_string(&path).unwrap();
+        let unsupported = "@cert-authority *.example.com ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA\nnot a known_hosts line\n";
+        std::fs::write(&path, format!("# comment\n{}{}", unsupported, entries)).unwrap();
+
+        let sess = Session::new().unwrap();
+        let result = check_key_in_files(&sess, &[path], "example.com", 22, KEY_B).unwrap();
+        assert!(matches!(result, CheckResult::Mismatch));
+    }
+
+    #[test]
+    fn test_reject_without_pending_prompt_fails() {
+        assert!(reject_pending("no-such-session").is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use crate::{automation, cluster, exec, scrollback, secure_storage, ssh_auth};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rhai::{Dynamic, Engine, EvalAltResult, Scope};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::Window;
+
+// Longest a sleeping macro goes without checking whether it was cancelled
+const SLEEP_CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+static NEXT_RUN_ID: AtomicU64 = AtomicU64::new(1);
+
+// Running macros, keyed by run id
+static RUNS: Lazy<Mutex<HashMap<String, Arc<MacroRun>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MacroInfo {
+    pub name: String,
+    /// Rhai source
+    pub source: String,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MacroStatus {
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// Emitted as `macro-finished`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MacroResult {
+    pub run_id: String,
+    pub name: String,
+    pub status: MacroStatus,
+    /// The value of the macro's last expression, unless it is ()
+    pub result: Option<String>,
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+struct MacroRun {
+    run_id: String,
+    cancelled: AtomicBool,
+    // Numbers the execs and waits the macro starts, for their ids
+    next_child: AtomicU64,
+}
+
+impl MacroRun {
+    fn new(run_id: &str) -> Self {
+        MacroRun { run_id: run_id.to_string(), cancelled: AtomicBool::new(false), next_child: AtomicU64::new(1) }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// "<run id>:<kind>-<n>"; cancel() finds the run's children by the prefix
+    fn child_id(&self, kind: &str) -> String {
+        format!("{}:{}-{}", self.run_id, kind, self.next_child.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+type RhaiResult<T> = Result<T, Box<EvalAltResult>>;
+
+fn to_dynamic(value: impl Serialize) -> RhaiResult<Dynamic> {
+    rhai::serde::to_dynamic(value)
+}
+
+/// A sandboxed engine: no file access or module imports, no `eval`, bounded sizes. print()
+/// and debug() go to `log`. Functions that need no session: sleep, search, credential.
+fn engine(run: &Arc<MacroRun>, log: Arc<dyn Fn(&str) + Send + Sync>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new());
+    engine.set_max_modules(0);
+    engine.disable_symbol("eval");
+    engine.set_max_call_levels(64);
+    engine.set_max_string_size(16 * 1024 * 1024);
+    engine.set_max_array_size(100_000);
+    engine.set_max_map_size(100_000);
+
+    let print_log = log.clone();
+    engine.on_print(move |text| print_log(text));
+    engine.on_debug(move |text, _, _| log(text));
+    let progress = run.clone();
+    engine.on_progress(move |_| progress.is_cancelled().then_some(Dynamic::UNIT));
+
+    let sleeping = run.clone();
+    engine.register_fn("sleep", move |ms: i64| -> RhaiResult<()> {
+        let until = Instant::now() + Duration::from_millis(ms.max(0) as u64);
+        while let Some(left) = until.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) {
+            if sleeping.is_cancelled() {
+                return Err("Cancelled".into());
+            }
+            thread::sleep(left.min(SLEEP_CANCEL_CHECK_INTERVAL));
+        }
+        Ok(())
+    });
+
+    engine.register_fn("search", |session_id: &str, pattern: &str| -> RhaiResult<Dynamic> {
+        let request = scrollback::SearchRequest {
+            pattern: pattern.to_string(),
+            session_id: Some(session_id.to_string()),
+            case_insensitive: false,
+            max_results: None,
+        };
+        to_dynamic(scrollback::search(&request)?.matches)
+    });
+
+    engine.register_fn("credential", |credential_id: &str| -> RhaiResult<Dynamic> {
+        let credential = secure_storage::with_database(|db| {
+            let stored = db.get_credential(credential_id)?;
+            Ok(serde_json::json!({
+                "name": stored.name,
+                "username": stored.username,
+                "password": db.decrypt_password(stored.password_encrypted)?,
+            }))
+        })?;
+        to_dynamic(credential)
+    });
+
+    engine
+}
+
+/// Functions on open sessions and saved servers: sessions, write, wait_for, exec, exec_host
+fn register_session_api(engine: &mut Engine, run: &Arc<MacroRun>, window: Window) {
+    engine.register_fn("sessions", || -> Dynamic { crate::session_ids().into() });
+
+    engine.register_fn("write", |session_id: &str, text: &str| -> RhaiResult<()> {
+        Ok(tauri::async_runtime::block_on(crate::write_session_input(session_id, text))?)
+    });
+
+    // The text that matched, or () once `timeout_secs` passes
+    let waiting = run.clone();
+    engine.register_fn("wait_for", move |session_id: &str, pattern: &str, timeout_secs: i64| -> RhaiResult<Dynamic> {
+        let charset = crate::session_charset(session_id)?;
+        let timeout = Duration::from_secs(timeout_secs.max(0) as u64);
+        let wait_id = waiting.child_id("wait");
+        let matched = tauri::async_runtime::block_on(automation::wait_for(&wait_id, session_id, charset, pattern, timeout))?;
+        Ok(matched.map(Dynamic::from).unwrap_or(Dynamic::UNIT))
+    });
+
+    // On the terminal's own connection
+    let exec_run = run.clone();
+    let exec_on_session = move |session_id: &str, command: &str, timeout_secs: Option<u64>| -> RhaiResult<Dynamic> {
+        let sess = crate::ssh_session_source(session_id, &window)?.live
+            .ok_or("Session not found. Please connect first.")?;
+        let request = exec::ExecRequest { command: command.to_string(), timeout_secs, ..Default::default() };
+        to_dynamic(exec::run(&sess, &exec_run.child_id("exec"), &request, |_, _| {})?)
+    };
+    let exec_default = exec_on_session.clone();
+    engine.register_fn("exec", move |session_id: &str, command: &str| exec_default(session_id, command, None));
+    engine.register_fn("exec", move |session_id: &str, command: &str, timeout_secs: i64| {
+        exec_on_session(session_id, command, Some(timeout_secs.max(0) as u64))
+    });
+
+    // On a new connection to a saved server, given as a map like a cluster host:
+    // #{server_id: "...", host: "...", port: 22}
+    let host_run = run.clone();
+    engine.register_fn("exec_host", move |host: Dynamic, command: &str| -> RhaiResult<Dynamic> {
+        let host: cluster::ClusterHost = rhai::serde::from_dynamic(&host)?;
+        let exec_id = host_run.child_id("exec");
+        // No view answers prompts for a macro's connection: unknown host keys are refused
+        let sess = crate::open_saved_host_session(exec_id.clone(), &host, ssh_auth::Prompts::Unattended)?;
+        let request = exec::ExecRequest { command: command.to_string(), ..Default::default() };
+        let result = exec::run(&sess, &exec_id, &request, |_, _| {});
+        let _ = sess.disconnect(None, "Command finished", None);
+        to_dynamic(result?)
+    });
+}
+
+fn evaluate(engine: &Engine, source: &str, args: serde_json::Value) -> RhaiResult<Dynamic> {
+    let ast = engine.compile(source)?;
+    let mut scope = Scope::new();
+    scope.push_dynamic("args", to_dynamic(args)?);
+    engine.eval_ast_with_scope(&mut scope, &ast)
+}
+
+fn macro_result(run: &MacroRun, name: &str, outcome: RhaiResult<Dynamic>, started: Instant) -> MacroResult {
+    let (status, result, error) = match outcome {
+        Ok(value) if value.is_unit() => (MacroStatus::Succeeded, None, None),
+        Ok(value) => (MacroStatus::Succeeded, Some(value.to_string()), None),
+        Err(_) if run.is_cancelled() => (MacroStatus::Cancelled, None, None),
+        Err(e) => (MacroStatus::Failed, None, Some(e.to_string())),
+    };
+    MacroResult {
+        run_id: run.run_id.clone(),
+        name: name.to_string(),
+        status,
+        result,
+        error,
+        duration_ms: started.elapsed().as_millis() as u64,
+    }
+}
+
+pub fn next_run_id() -> String {
+    format!("macro-{}", NEXT_RUN_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Check that `source` compiles, then save it under `name`
+pub fn save(name: &str, source: &str) -> Result<(), String> {
+    if name.trim().is_empty() {
+        return Err("Macro name is required".to_string());
+    }
+    engine(&Arc::new(MacroRun::new("check")), Arc::new(|_: &str| {}))
+        .compile(source)
+        .map_err(|e| format!("Syntax error: {}", e))?;
+    secure_storage::with_database(|db| db.store_macro(name, source))
+}
+
+pub fn list() -> Result<Vec<MacroInfo>, String> {
+    let macros = secure_storage::with_database(|db| db.list_macros())?;
+    Ok(macros.into_iter()
+        .map(|stored| MacroInfo { name: stored.name, source: stored.source, updated_at: stored.updated_at })
+        .collect())
+}
+
+pub fn delete(name: &str) -> Result<(), String> {
+    secure_storage::with_database(|db| db.delete_macro(name))
+}
+
+/// Run the macro called `name` on its own thread, with `args` as the `args` variable.
+/// Every line it prints is emitted as `macro-log`, the result as `macro-finished`.
+pub fn start(run_id: &str, name: &str, args: serde_json::Value, window: Window) -> Result<(), String> {
+    let source = secure_storage::with_database(|db| db.get_macro(name))?.source;
+    let run = Arc::new(MacroRun::new(run_id));
+    {
+        let mut runs = RUNS.lock();
+        if runs.contains_key(run_id) {
+            return Err(format!("Run {} is already running", run_id));
+        }
+        runs.insert(run_id.to_string(), run.clone());
+    }
+
+    let name = name.to_string();
+    thread::spawn(move || {
+        let log_window = window.clone();
+        let log_run_id = run.run_id.clone();
+        let log = Arc::new(move |line: &str| {
+            let _ = log_window.emit("macro-log", serde_json::json!({
+                "run_id": log_run_id,
+                "line": line
+            }));
+        });
+        let mut engine = engine(&run, log);
+        register_session_api(&mut engine, &run, window.clone());
+
+        let started = Instant::now();
+        let outcome = evaluate(&engine, &source, args);
+        RUNS.lock().remove(&run.run_id);
+        let _ = window.emit("macro-finished", macro_result(&run, &name, outcome, started));
+    });
+    Ok(())
+}
+
+/// Stop a macro, along with the commands and waits it is in the middle of
+pub fn cancel(run_id: &str) -> Result<(), String> {
+    let run = RUNS.lock().get(run_id).cloned()
+        .ok_or_else(|| format!("No running macro with id {}", run_id))?;
+    run.cancelled.store(true, Ordering::Relaxed);
+
+    let prefix = format!("{}:", run_id);
+    for exec_id in exec::running_ids().into_iter().filter(|id| id.starts_with(&prefix)) {
+        let _ = exec::cancel(&exec_id);
+    }
+    for wait_id in automation::running_ids().into_iter().filter(|id| id.starts_with(&prefix)) {
+        let _ = automation::cancel(&wait_id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_macro(source: &str, args: serde_json::Value) -> (MacroResult, Vec<String>) {
+        let run = Arc::new(MacroRun::new("macro-test"));
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let logged = lines.clone();
+        let engine = engine(&run, Arc::new(move |line: &str| logged.lock().push(line.to_string())));
+        let result = macro_result(&run, "test", evaluate(&engine, source, args), Instant::now());
+        let lines = lines.lock().clone();
+        (result, lines)
+    }
+
+    #[test]
+    fn test_print_streams_and_result() {
+        let source = r#"
+            let total = 0;
+            for host in args.hosts {
+                print(`checking ${host}`);
+                total += host.len();
+            }
+            total
+        "#;
+        let (result, lines) = run_macro(source, serde_json::json!({ "hosts": ["web1", "db01"] }));
+        assert_eq!(result.status, MacroStatus::Succeeded);
+        assert_eq!(result.result.as_deref(), Some("8"));
+        assert_eq!(lines, vec!["checking web1", "checking db01"]);
+    }
+
+    #[test]
+    fn test_errors_report_position() {
+        let (result, _) = run_macro("let x = 1;\nthrow \"disk full\";", serde_json::Value::Null);
+        assert_eq!(result.status, MacroStatus::Failed);
+        let error = result.error.unwrap();
+        assert!(error.contains("disk full") && error.contains("line 2"), "{}", error);
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let (result, _) = run_macro(r#"eval("1 + 1")"#, serde_json::Value::Null);
+        assert_eq!(result.status, MacroStatus::Failed);
+        let (result, _) = run_macro(r#"import "secrets" as s;"#, serde_json::Value::Null);
+        assert_eq!(result.status, MacroStatus::Failed);
+        let (result, _) = run_macro("fn f(n) { f(n + 1) } f(0)", serde_json::Value::Null);
+        assert_eq!(result.status, MacroStatus::Failed);
+    }
+
+    #[test]
+    fn test_search_scrollback() {
+        scrollback::open("macro-test-1", encoding_rs::UTF_8);
+        scrollback::capture_output("macro-test-1", b"ok\nERROR: disk full\n");
+        let source = r#"
+            let found = search("macro-test-1", "ERROR: (.*)");
+            `${found.len()} ${found[0].line_number} ${found[0].line}`
+        "#;
+        let (result, _) = run_macro(source, serde_json::Value::Null);
+        assert_eq!(result.result.as_deref(), Some("1 1 ERROR: disk full"));
+        scrollback::close("macro-test-1");
+    }
+
+    #[test]
+    fn test_cancel_stops_loops_and_sleeps() {
+        for source in ["loop { }", "sleep(60000)"] {
+            let run = Arc::new(MacroRun::new(&format!("macro-test-{}", source.len())));
+            RUNS.lock().insert(run.run_id.clone(), run.clone());
+            let running = run.clone();
+            let source = source.to_string();
+            let handle = thread::spawn(move || {
+                let engine = engine(&running, Arc::new(|_: &str| {}));
+                macro_result(&running, "test", evaluate(&engine, &source, serde_json::Value::Null), Instant::now())
+            });
+            thread::sleep(Duration::from_millis(50));
+            cancel(&run.run_id).unwrap();
+            assert_eq!(handle.join().unwrap().status, MacroStatus::Cancelled);
+            RUNS.lock().remove(&run.run_id);
+        }
+        assert!(cancel("macro-test-missing").is_err());
+    }
+}
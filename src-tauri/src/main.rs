@@ -1,20 +1,43 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod automation;
+mod broadcast;
+mod cluster;
+mod exec;
+mod known_hosts;
+mod macros;
+mod port_forward;
+mod proxy_jump;
+mod pty_io;
+mod reconnect;
+mod recording;
+mod screen;
+mod scrollback;
 mod secure_storage;
+mod session_log;
+mod sftp;
+mod shell_integration;
+mod ssh_auth;
+mod ssh_config;
+mod terminal_encoding;
+mod timeouts;
+mod transfers;
+mod triggers;
+mod tunnel;
 
 use ssh2::{Session, Channel};
 use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::net::TcpStream;
-use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
 use parking_lot::Mutex;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use tauri::Window;
+use tauri::{Manager, Window};
+use tokio::sync::mpsc;
 use std::thread;
-use std::time::Duration;
 
 // Use portable-pty for all platforms (cross-platform PTY support)
 use portable_pty::{CommandBuilder, PtySize, native_pty_system, PtyPair, Child};
@@ -22,8 +45,15 @@ use portable_pty::{CommandBuilder, PtySize, native_pty_system, PtyPair, Child};
 // PTY Session enum - supports both SSH and local PTY
 enum PtySessionType {
     Ssh {
+        // Shared with SFTP, exec and forwards; the shell channel itself is owned by the
+        // session's I/O task (see spawn_ssh_io)
         session: Session,
-        channel: Option<Channel>,
+        // Input and resizes for the I/O task
+        commands: mpsc::Sender<pty_io::IoCommand>,
+        // Kept so background jobs can open a fresh connection with the same credentials
+        params: Box<ConnectionParams>,
+        // Last known (cols, rows), re-applied after a reconnect
+        size: (u32, u32),
     },
     Local {
         pty_pair: Arc<Mutex<PtyPair>>,
@@ -36,21 +66,56 @@ enum PtySessionType {
 // PTY Session structure
 struct PtySession {
     session_type: PtySessionType,
+    // Output flow control and counters, shared with the session's reader
+    output: Arc<pty_io::OutputFlow>,
+    // Remote charset; output is converted from it and input to it
+    charset: &'static encoding_rs::Encoding,
+}
+
+impl PtySession {
+    // Current terminal size as (cols, rows)
+    fn size(&self) -> (u32, u32) {
+        match &self.session_type {
+            PtySessionType::Ssh { size, .. } => *size,
+            PtySessionType::Local { pty_pair, .. } => pty_pair.lock().master.get_size()
+                .map(|size| (size.cols as u32, size.rows as u32))
+                .unwrap_or((80, 24)),
+        }
+    }
 }
 
 // Global PTY sessions storage
 static PTY_SESSIONS: Lazy<Arc<Mutex<HashMap<String, Arc<Mutex<PtySession>>>>>> =
     Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct ConnectionParams {
     session_id: String,
     host: String,
     port: u16,
-    username: String,
-    password: Option<String>,
-    ssh_key_path: Option<String>,
-    ssh_key_passphrase: Option<String>,
+    #[serde(flatten)]
+    credentials: ssh_auth::Credentials,
+    /// Bastions to tunnel through, in order (like ssh -J)
+    #[serde(default)]
+    jump_hosts: Vec<proxy_jump::JumpHost>,
+    #[serde(default)]
+    reconnect: reconnect::ReconnectPolicy,
+    #[serde(default)]
+    timeouts: timeouts::ConnectionTimeouts,
+    #[serde(default)]
+    output_format: terminal_encoding::OutputFormat,
+    /// Charset the remote shell speaks, e.g. "gbk" or "shift_jis" (UTF-8 when unset)
+    #[serde(default)]
+    encoding: Option<String>,
+    /// Log this server's sessions; unset follows the global logging setting
+    #[serde(default)]
+    session_log: Option<bool>,
+    /// Type the OSC 133/OSC 7 snippet into the shell (bash or zsh) once it starts
+    #[serde(default)]
+    shell_integration: bool,
+    /// Saved server the session was opened from, for triggers scoped to servers
+    #[serde(default)]
+    server_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -59,6 +124,25 @@ struct PtyWriteParams {
     data: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct BroadcastGroupParams {
+    group_id: String,
+    session_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BroadcastExcludeParams {
+    group_id: String,
+    session_id: String,
+    excluded: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BroadcastWriteParams {
+    group_id: String,
+    data: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct PtyResizeParams {
     session_id: String,
@@ -66,133 +150,404 @@ struct PtyResizeParams {
     rows: u32,
 }
 
-#[tauri::command]
-async fn pty_connect(params: ConnectionParams, window: Window) -> Result<String, String> {
-    let tcp = TcpStream::connect(format!("{}:{}", params.host, params.port))
-        .map_err(|e| format!("Failed to connect to {}:{} - {}", params.host, params.port, e))?;
+#[derive(Debug, Serialize, Deserialize)]
+struct PtyAckParams {
+    session_id: String,
+    /// Sum of the `bytes` fields of the pty-output events rendered since the last ack
+    bytes: u64,
+}
+
+// Connect, verify the host key and authenticate; the returned session is in blocking mode
+fn open_ssh_session(params: &ConnectionParams, prompts: ssh_auth::Prompts) -> Result<Session, String> {
+    connect_ssh(params, prompts).map(|(sess, _)| sess)
+}
+
+// open_ssh_session, plus a clone of the session's socket for readiness and keepalive checks
+fn connect_ssh(params: &ConnectionParams, prompts: ssh_auth::Prompts) -> Result<(Session, TcpStream), String> {
+    let tcp = if params.jump_hosts.is_empty() {
+        timeouts::connect_tcp(&params.host, params.port, params.timeouts.connect_timeout())?
+    } else {
+        proxy_jump::connect_via(&params.jump_hosts, &params.host, params.port, &params.timeouts, prompts)?
+    };
+    let socket = tcp.try_clone()
+        .map_err(|e| format!("Failed to clone socket: {}", e))?;
 
     let mut sess = Session::new()
         .map_err(|e| format!("Failed to create SSH session: {}", e))?;
 
     sess.set_tcp_stream(tcp);
+    params.timeouts.start_auth(&sess);
     sess.handshake()
         .map_err(|e| format!("SSH handshake failed: {}", e))?;
 
-    // Set keepalive to prevent connection timeout (send keepalive every 60 seconds)
-    sess.set_keepalive(true, 60);
+    // Verify the server's host key before sending any credentials
+    known_hosts::verify_host_key(&sess, &params.host, params.port, prompts)?;
 
     // Authentication
-    if let Some(key_path) = params.ssh_key_path {
-        let passphrase = params.ssh_key_passphrase.as_deref();
-        sess.userauth_pubkey_file(
-            &params.username,
-            None,
-            Path::new(&key_path),
-            passphrase,
-        )
-        .map_err(|e| format!("SSH key authentication failed: {}", e))?;
-    } else if let Some(password) = params.password {
-        sess.userauth_password(&params.username, &password)
-            .map_err(|e| format!("Password authentication failed: {}", e))?;
-    } else {
-        return Err("No authentication method provided".to_string());
-    }
+    ssh_auth::authenticate(&sess, &params.credentials, prompts)?;
 
-    if !sess.authenticated() {
-        return Err("Authentication failed".to_string());
-    }
+    params.timeouts.finish_auth(&sess);
+    Ok((sess, socket))
+}
 
+// Open the interactive shell channel, then switch the session to non-blocking mode
+fn open_shell_channel(sess: &Session, params: &ConnectionParams, cols: u32, rows: u32) -> Result<Channel, String> {
     // Open PTY channel (in blocking mode first)
     let mut channel = sess.channel_session()
         .map_err(|e| format!("Failed to open channel: {}", e))?;
 
-    // Request PTY with default terminal size (80x24)
-    channel.request_pty("xterm-256color", None, Some((80, 24, 0, 0)))
+    channel.request_pty("xterm-256color", None, Some((cols, rows, 0, 0)))
         .map_err(|e| format!("Failed to request PTY: {}", e))?;
 
     // Start shell
     channel.shell()
         .map_err(|e| format!("Failed to start shell: {}", e))?;
 
+    // Read by the shell once it is ready, like typed-ahead input
+    if params.shell_integration {
+        channel.write_all(shell_integration::SNIPPET.as_bytes())
+            .map_err(|e| format!("Failed to send shell integration: {}", e))?;
+    }
+
     // NOW set session to non-blocking mode for async I/O in background thread
     sess.set_blocking(false);
 
+    Ok(channel)
+}
+
+// An interactive shell, owned by its session's I/O task
+struct SshShell {
+    session: Session,
+    channel: Channel,
+    // Same connection as the session, used for readiness only
+    socket: TcpStream,
+    keepalive: Option<timeouts::KeepaliveMonitor>,
+}
+
+impl SshShell {
+    fn open(params: &ConnectionParams, window: &Window, cols: u32, rows: u32) -> Result<Self, String> {
+        let (session, socket) = connect_ssh(params, ssh_auth::Prompts::Frontend { session_id: &params.session_id, window })?;
+        let channel = open_shell_channel(&session, params, cols, rows)?;
+        socket.set_nonblocking(true)
+            .map_err(|e| format!("Failed to configure socket: {}", e))?;
+        let keepalive = timeouts::KeepaliveMonitor::new(&socket, &params.timeouts);
+        Ok(SshShell { session, channel, socket, keepalive })
+    }
+
+    // Close the channel and the connection; returns the shell's exit status if it sent one
+    fn close(mut self, reason: &str) -> Option<i32> {
+        let _ = tunnel::retry_would_block(|| self.channel.close());
+        let exit_status = tunnel::retry_would_block(|| self.channel.wait_close())
+            .ok()
+            .and_then(|_| self.channel.exit_status().ok());
+        let _ = self.session.disconnect(None, reason, None);
+        exit_status
+    }
+}
+
+impl pty_io::ChannelIo for SshShell {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.channel.read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.channel.write(buf)
+    }
+
+    fn resize(&mut self, cols: u32, rows: u32) -> std::io::Result<()> {
+        match self.channel.request_pty_size(cols, rows, None, None) {
+            Ok(()) => Ok(()),
+            Err(ref e) if tunnel::is_would_block(e) => Err(std::io::ErrorKind::WouldBlock.into()),
+            Err(e) => Err(std::io::Error::other(format!("Failed to resize PTY: {}", e))),
+        }
+    }
+
+    fn eof(&self) -> bool {
+        self.channel.eof()
+    }
+
+    fn tick(&mut self, inbound: bool) -> std::io::Result<()> {
+        match &mut self.keepalive {
+            Some(monitor) => monitor.tick(&self.session, inbound).map_err(std::io::Error::other),
+            None => Ok(()),
+        }
+    }
+}
+
+#[tauri::command]
+async fn pty_connect(params: ConnectionParams, window: Window) -> Result<String, String> {
+    let charset = terminal_encoding::charset(params.encoding.as_deref())?;
+    // Request PTY with default terminal size (80x24). Off the async runtime: the host key
+    // and keyboard-interactive prompts block until the user answers.
+    let shell = {
+        let (params, window) = (params.clone(), window.clone());
+        tauri::async_runtime::spawn_blocking(move || SshShell::open(&params, &window, 80, 24)).await
+            .map_err(|e| format!("Connection task failed: {}", e))??
+    };
+    let (commands, receiver) = mpsc::channel(pty_io::COMMAND_QUEUE_CAPACITY);
+    let output = Arc::new(pty_io::OutputFlow::default());
+
     let pty_session = Arc::new(Mutex::new(PtySession {
         session_type: PtySessionType::Ssh {
-            session: sess,
-            channel: Some(channel),
+            session: shell.session.clone(),
+            commands,
+            params: Box::new(params.clone()),
+            size: (80, 24),
         },
+        output: output.clone(),
+        charset,
     }));
 
     // Store session
-    PTY_SESSIONS.lock().insert(params.session_id.clone(), pty_session.clone());
+    PTY_SESSIONS.lock().insert(params.session_id.clone(), pty_session);
+    screen::open(&params.session_id, (80, 24), charset);
+    scrollback::open(&params.session_id, charset);
+    shell_integration::open(&params.session_id);
+    triggers::open(&params.session_id, params.server_id.clone(), charset);
+    start_session_log(&params.session_id, &params.credentials.username, &params.host, params.session_log, charset, &window);
 
-    // Start background thread to stream output
-    let session_id_clone = params.session_id.clone();
-    let window_clone = window.clone();
-    thread::spawn(move || {
-        let mut buffer = vec![0u8; 32768]; // Even larger buffer (32KB)
+    // Start the session's I/O task to stream output
+    spawn_ssh_io(params.session_id.clone(), shell, receiver, output, (params.output_format, charset), window);
+
+    Ok(format!("Connected to {}@{}:{}", params.credentials.username, params.host, params.port))
+}
+
+// Run the shell's event loop until the tab is closed, reconnecting on connection errors
+fn spawn_ssh_io(
+    session_id: String,
+    mut shell: SshShell,
+    mut commands: mpsc::Receiver<pty_io::IoCommand>,
+    output: Arc<pty_io::OutputFlow>,
+    // How output reaches the frontend and the charset it is converted from
+    (format, charset): (terminal_encoding::OutputFormat, &'static encoding_rs::Encoding),
+    window: Window,
+) {
+    tauri::async_runtime::spawn(async move {
         loop {
-            // Check if session still exists and get Arc clone with minimal lock time
-            let pty_session_arc = {
-                let sessions = PTY_SESSIONS.lock();
-                if let Some(arc) = sessions.get(&session_id_clone) {
-                    arc.clone()
-                } else {
-                    break;
+            // Fresh per connection: a sequence cut off by a dropped connection is not
+            // continued by the new one
+            let mut encoder = terminal_encoding::OutputEncoder::new(format, charset);
+            let exit = match shell.socket.try_clone().and_then(tokio::net::TcpStream::from_std) {
+                Ok(socket) => {
+                    pty_io::drive(&mut shell, &socket, &mut commands, &output, |data| {
+                        emit_pty_output(&window, &session_id, &mut encoder, data)
+                    }).await
                 }
+                Err(e) => pty_io::LoopExit::Error(e),
             };
+            emit_pty_output_tail(&window, &session_id, &mut encoder);
 
-            // Continuously drain until WouldBlock
-            loop {
-                let bytes_read = {
-                    let mut pty = pty_session_arc.lock();
-                    match &mut pty.session_type {
-                        PtySessionType::Ssh { channel, .. } => {
-                            if let Some(ref mut ch) = channel {
-                                match ch.read(&mut buffer) {
-                                    Ok(n) => n,
-                                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                                        // No more data available right now
-                                        break;
-                                    },
-                                    Err(e) => {
-                                        // Connection error - emit disconnect event
-                                        let _ = window_clone.emit("pty-disconnect", serde_json::json!({
-                                            "session_id": session_id_clone,
-                                            "error": format!("Connection lost: {}", e)
-                                        }));
-                                        return; // Exit thread on error
-                                    }
-                                }
-                            } else {
-                                return; // Exit thread if no channel
-                            }
-                        },
-                        _ => {
-                            // Local PTY types don't use this thread
-                            return;
-                        }
+            match exit {
+                pty_io::LoopExit::Closed => {
+                    close_shell(shell, "Client disconnecting").await;
+                    return;
+                }
+                pty_io::LoopExit::Eof => {
+                    let exit_status = close_shell(shell, "Shell exited").await;
+                    let _ = window.emit("pty-exit", serde_json::json!({
+                        "session_id": session_id,
+                        "exit_status": exit_status
+                    }));
+                    return;
+                }
+                pty_io::LoopExit::Error(e) => {
+                    let (id, win) = (session_id.clone(), window.clone());
+                    let reconnected = tauri::async_runtime::spawn_blocking(move || {
+                        let new_shell = reconnect_ssh(&id, &win, e.to_string());
+                        shell.close("Reconnecting");
+                        new_shell
+                    }).await;
+                    match reconnected {
+                        Ok(Some(new_shell)) => shell = new_shell,
+                        _ => return,
                     }
-                };
+                }
+            }
+        }
+    });
+}
 
-                if bytes_read > 0 {
-                    let data = String::from_utf8_lossy(&buffer[..bytes_read]).to_string();
-                    let _ = window_clone.emit("pty-output", serde_json::json!({
-                        "session_id": session_id_clone,
-                        "data": data
-                    }));
-                } else {
-                    break;
+// Closing waits for the server to confirm, so it runs off the async runtime
+async fn close_shell(shell: SshShell, reason: &'static str) -> Option<i32> {
+    tauri::async_runtime::spawn_blocking(move || shell.close(reason)).await
+        .ok()
+        .flatten()
+}
+
+// One coalesced chunk of terminal output. `bytes` is what the frontend acks via pty_ack
+// once the chunk is rendered. Returns whether the webview accepted the event.
+fn emit_pty_output(window: &Window, session_id: &str, encoder: &mut terminal_encoding::OutputEncoder, data: &[u8]) -> bool {
+    recording::capture_output(session_id, data);
+    session_log::capture_output(session_id, data);
+    // The screen first: shell integration reads command lines off it
+    screen::capture_output(session_id, data);
+    let marks = scrollback::capture_output(session_id, data);
+    for command in shell_integration::handle_marks(session_id, marks) {
+        let _ = window.emit("command-finished", &command);
+    }
+    automation::capture_output(session_id, data);
+    for fire in triggers::capture_output(session_id, data) {
+        run_trigger_actions(window, fire);
+    }
+    window.emit("pty-output", serde_json::json!({
+        "session_id": session_id,
+        "data": encoder.encode(data),
+        "format": encoder.format().as_str(),
+        "bytes": data.len()
+    })).is_ok()
+}
+
+// Start the session's log if logging is on for it. A log that cannot be written is
+// reported, but does not keep the terminal from opening.
+fn start_session_log(
+    session_id: &str,
+    user: &str,
+    host: &str,
+    server_setting: Option<bool>,
+    charset: &'static encoding_rs::Encoding,
+    window: &Window,
+) {
+    let settings = session_log::settings();
+    if let Err(e) = session_log::start(session_id, user, host, server_setting, &settings, charset) {
+        let _ = window.emit("session-log-error", serde_json::json!({
+            "session_id": session_id,
+            "error": e
+        }));
+    }
+}
+
+fn local_username() -> String {
+    std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_else(|_| "local".to_string())
+}
+
+// Carry out a trigger's actions. Input is written from a task of its own, as the output
+// path must not wait on the session's write queue.
+fn run_trigger_actions(window: &Window, fire: triggers::TriggerFire) {
+    for action in &fire.actions {
+        match action {
+            triggers::TriggerAction::Highlight { .. } => {
+                let _ = window.emit("trigger-highlight", &fire);
+            }
+            triggers::TriggerAction::Notify { title } => {
+                let identifier = window.config().tauri.bundle.identifier.clone();
+                let shown = tauri::api::notification::Notification::new(identifier)
+                    .title(title.as_deref().unwrap_or(&fire.rule_name))
+                    .body(&fire.line)
+                    .show();
+                if let Err(e) = shown {
+                    emit_trigger_error(window, &fire, format!("Failed to show notification: {}", e));
                 }
             }
+            triggers::TriggerAction::SendText { text } => {
+                let (fire, text, window) = (fire.clone(), text.clone(), window.clone());
+                tauri::async_runtime::spawn(async move {
+                    if let Err(e) = write_session_input(&fire.session_id, &text).await {
+                        emit_trigger_error(&window, &fire, e);
+                    }
+                });
+            }
+            triggers::TriggerAction::StartLogging => {
+                if session_log::status(&fire.session_id).is_some() {
+                    continue;
+                }
+                let Some(pty_session) = PTY_SESSIONS.lock().get(&fire.session_id).cloned() else {
+                    continue;
+                };
+                let (user, host, charset) = {
+                    let pty = pty_session.lock();
+                    match &pty.session_type {
+                        PtySessionType::Ssh { params, .. } => (params.credentials.username.clone(), params.host.clone(), pty.charset),
+                        PtySessionType::Local { .. } => (local_username(), "localhost".to_string(), pty.charset),
+                    }
+                };
+                start_session_log(&fire.session_id, &user, &host, Some(true), charset, window);
+            }
+        }
+    }
+}
+
+fn emit_trigger_error(window: &Window, fire: &triggers::TriggerFire, error: String) {
+    let _ = window.emit("trigger-error", serde_json::json!({
+        "session_id": fire.session_id,
+        "rule_id": fire.rule_id,
+        "error": error
+    }));
+}
 
-            // Sleep briefly after draining all available data
-            thread::sleep(Duration::from_micros(500));
+// Flush a multi-byte sequence the stream ended in the middle of
+fn emit_pty_output_tail(window: &Window, session_id: &str, encoder: &mut terminal_encoding::OutputEncoder) {
+    let tail = encoder.finish();
+    if !tail.is_empty() {
+        let _ = window.emit("pty-output", serde_json::json!({
+            "session_id": session_id,
+            "data": tail,
+            "format": encoder.format().as_str(),
+            "bytes": 0
+        }));
+    }
+}
+
+// Re-establish a dropped SSH terminal under the same session_id, following the server's
+// reconnect policy. Returns None (after emitting `pty-disconnect`) when the tab is dead.
+fn reconnect_ssh(session_id: &str, window: &Window, error: String) -> Option<SshShell> {
+    let pty_session = PTY_SESSIONS.lock().get(session_id).cloned()?;
+    let (params, (cols, rows)) = match &pty_session.lock().session_type {
+        PtySessionType::Ssh { params, size, .. } => (params.clone(), *size),
+        PtySessionType::Local { .. } => return None,
+    };
+
+    // Side channels were bound to the old connection; forwards are set up again once the
+    // new one is up
+    sftp::close_session(session_id);
+    let forwards = port_forward::close_for_session(session_id);
+
+    let mut last_error = error.clone();
+    let mut attempt = 1;
+    while let Some(delay) = params.reconnect.delay(attempt) {
+        let _ = window.emit("pty-reconnecting", serde_json::json!({
+            "session_id": session_id,
+            "attempt": attempt,
+            "max_attempts": params.reconnect.max_attempts,
+            "delay_ms": delay.as_millis() as u64,
+            "error": last_error
+        }));
+        thread::sleep(delay);
+
+        // The user closed the tab while we were waiting
+        if !PTY_SESSIONS.lock().contains_key(session_id) {
+            return None;
         }
-    });
 
-    Ok(format!("Connected to {}@{}:{}", params.username, params.host, params.port))
+        match SshShell::open(&params, window, cols, rows) {
+            Ok(shell) => {
+                if let PtySessionType::Ssh { session, .. } = &mut pty_session.lock().session_type {
+                    *session = shell.session.clone();
+                }
+                let restored = port_forward::restore(session_id, &shell.session, forwards, window);
+                let _ = window.emit("pty-reconnected", serde_json::json!({
+                    "session_id": session_id,
+                    "attempt": attempt,
+                    "cols": cols,
+                    "rows": rows,
+                    "forwards": restored
+                }));
+                return Some(shell);
+            }
+            Err(e) => last_error = e,
+        }
+        attempt += 1;
+    }
+
+    let error = if attempt > 1 {
+        format!("Connection lost: {} (reconnect failed after {} attempts: {})", error, attempt - 1, last_error)
+    } else {
+        format!("Connection lost: {}", error)
+    };
+    let _ = window.emit("pty-disconnect", serde_json::json!({
+        "session_id": session_id,
+        "error": error
+    }));
+    None
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -200,11 +555,23 @@ struct LocalPtyParams {
     session_id: String,
     cols: u16,
     rows: u16,
+    #[serde(default)]
+    output_format: terminal_encoding::OutputFormat,
+    /// Charset of the local shell (UTF-8 when unset)
+    #[serde(default)]
+    encoding: Option<String>,
+    /// Log this terminal; unset follows the global logging setting
+    #[serde(default)]
+    session_log: Option<bool>,
+    /// Type the OSC 133/OSC 7 snippet into the shell (bash or zsh) once it starts
+    #[serde(default)]
+    shell_integration: bool,
 }
 
 // Cross-platform local PTY implementation using portable-pty
 #[tauri::command]
 async fn pty_connect_local(params: LocalPtyParams, window: Window) -> Result<String, String> {
+    let charset = terminal_encoding::charset(params.encoding.as_deref())?;
     let pty_system = native_pty_system();
 
     let pty_pair = pty_system
@@ -237,6 +604,7 @@ async fn pty_connect_local(params: LocalPtyParams, window: Window) -> Result<Str
     let writer_arc = Arc::new(Mutex::new(writer));
 
     let pty_arc = Arc::new(Mutex::new(pty_pair));
+    let output = Arc::new(pty_io::OutputFlow::default());
 
     let pty_session = Arc::new(Mutex::new(PtySession {
         session_type: PtySessionType::Local {
@@ -244,95 +612,119 @@ async fn pty_connect_local(params: LocalPtyParams, window: Window) -> Result<Str
             writer: writer_arc,
             child: child,
         },
+        output: output.clone(),
+        charset,
     }));
 
     // Store session
     PTY_SESSIONS.lock().insert(params.session_id.clone(), pty_session.clone());
+    screen::open(&params.session_id, (params.cols as u32, params.rows as u32), charset);
+    scrollback::open(&params.session_id, charset);
+    shell_integration::open(&params.session_id);
+    triggers::open(&params.session_id, None, charset);
+    start_session_log(&params.session_id, &local_username(), "localhost", params.session_log, charset, &window);
 
     // Start background thread to stream output
     let session_id_clone = params.session_id.clone();
     let window_clone = window.clone();
 
     // Get a reader from the master PTY
-    let mut reader = {
+    let reader = {
         let pty_pair = pty_arc.lock();
         pty_pair.master.try_clone_reader()
             .map_err(|e| format!("Failed to clone reader: {}", e))?
     };
 
-    thread::spawn(move || {
-        let mut buffer = vec![0u8; 8192];
-
-        loop {
-            // Check if session still exists
-            {
-                let sessions = PTY_SESSIONS.lock();
-                if !sessions.contains_key(&session_id_clone) {
-                    break;
-                }
-            }
-
-            let read_result = reader.read(&mut buffer);
-
-            match read_result {
-                Ok(0) => {
-                    let _ = window_clone.emit("pty-disconnect", serde_json::json!({
-                        "session_id": session_id_clone,
-                        "error": "Shell process has exited"
-                    }));
-                    break;
-                },
-                Ok(bytes_read) => {
-                    let text = String::from_utf8_lossy(&buffer[..bytes_read]).to_string();
-                    let _ = window_clone.emit("pty-output", serde_json::json!({
-                        "session_id": session_id_clone,
-                        "data": text
-                    }));
-                },
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // No data available, sleep briefly
-                    thread::sleep(Duration::from_millis(10));
-                },
-                Err(e) => {
-                    let _ = window_clone.emit("pty-disconnect", serde_json::json!({
-                        "session_id": session_id_clone,
-                        "error": format!("Local PTY error: {}", e)
-                    }));
-                    break;
-                }
-            }
+    if params.shell_integration {
+        if let PtySessionType::Local { writer, .. } = &pty_session.lock().session_type {
+            writer.lock().write_all(shell_integration::SNIPPET.as_bytes())
+                .map_err(|e| format!("Failed to send shell integration: {}", e))?;
         }
+    }
+
+    let format = params.output_format;
+    thread::spawn(move || {
+        let mut encoder = terminal_encoding::OutputEncoder::new(format, charset);
+        let is_open = || PTY_SESSIONS.lock().contains_key(&session_id_clone);
+        let exit = pty_io::pump_blocking(reader, output, is_open, |data| {
+            emit_pty_output(&window_clone, &session_id_clone, &mut encoder, data)
+        });
+        emit_pty_output_tail(&window_clone, &session_id_clone, &mut encoder);
+
+        let error = match exit {
+            pty_io::LoopExit::Closed => return,
+            pty_io::LoopExit::Eof => "Shell process has exited".to_string(),
+            pty_io::LoopExit::Error(e) => format!("Local PTY error: {}", e),
+        };
+        let _ = window_clone.emit("pty-disconnect", serde_json::json!({
+            "session_id": session_id_clone,
+            "error": error
+        }));
     });
 
     Ok("Local terminal connected".to_string())
 }
 
+// Input typed into a member of a broadcast group goes to the whole group; members other
+// than this session that fail are reported with a `broadcast-failed` event
 #[tauri::command]
-async fn pty_write(params: PtyWriteParams) -> Result<(), String> {
-    let sessions = PTY_SESSIONS.lock();
-    let pty_session = sessions.get(&params.session_id)
-        .ok_or_else(|| "Session not found. Please connect first.".to_string())?;
+async fn pty_write(params: PtyWriteParams, window: Window) -> Result<(), String> {
+    let Some(result) = broadcast::write_from(&params.session_id, &params.data, write_member_input).await else {
+        recording::capture_input(&params.session_id, &params.data);
+        return write_session_input(&params.session_id, &params.data).await;
+    };
+    let own_failure = result.failed.iter().find(|failure| failure.session_id == params.session_id);
+    if let Some(failure) = own_failure {
+        return Err(failure.error.clone());
+    }
+    if !result.failed.is_empty() {
+        let _ = window.emit("broadcast-failed", &result);
+    }
+    Ok(())
+}
 
-    let mut pty = pty_session.lock();
-    match &mut pty.session_type {
-        PtySessionType::Ssh { channel, .. } => {
-            if let Some(ref mut ch) = channel {
-                ch.write_all(params.data.as_bytes())
+// pty_write for one session of a broadcast
+async fn write_member_input(session_id: String, data: String) -> Result<(), String> {
+    recording::capture_input(&session_id, &data);
+    write_session_input(&session_id, &data).await
+}
+
+// Ids of the open terminal sessions, sorted
+fn session_ids() -> Vec<String> {
+    let mut ids: Vec<String> = PTY_SESSIONS.lock().keys().cloned().collect();
+    ids.sort();
+    ids
+}
+
+fn session_charset(session_id: &str) -> Result<&'static encoding_rs::Encoding, String> {
+    PTY_SESSIONS.lock().get(session_id)
+        .map(|pty_session| pty_session.lock().charset)
+        .ok_or_else(|| "Session not found. Please connect first.".to_string())
+}
+
+// Type `text` into a session, converted to its charset
+async fn write_session_input(session_id: &str, text: &str) -> Result<(), String> {
+    let (commands, data) = {
+        let sessions = PTY_SESSIONS.lock();
+        let pty_session = sessions.get(session_id)
+            .ok_or_else(|| "Session not found. Please connect first.".to_string())?;
+
+        let pty = pty_session.lock();
+        let data = terminal_encoding::encode_input(pty.charset, text);
+        match &pty.session_type {
+            PtySessionType::Ssh { commands, .. } => (commands.clone(), data),
+            PtySessionType::Local { writer, .. } => {
+                let mut w = writer.lock();
+                w.write_all(&data)
                     .map_err(|e| format!("Failed to write to PTY: {}", e))?;
-                ch.flush()
-                    .map_err(|e| format!("Failed to flush PTY: {}", e))?;
-                Ok(())
-            } else {
-                Err("Channel not available".to_string())
+                return Ok(());
             }
-        },
-        PtySessionType::Local { writer, .. } => {
-            let mut w = writer.lock();
-            w.write_all(params.data.as_bytes())
-                .map_err(|e| format!("Failed to write to PTY: {}", e))?;
-            Ok(())
         }
-    }
+    };
+
+    // Waits only when the I/O task is a full queue behind
+    commands.send(pty_io::IoCommand::Write(data)).await
+        .map_err(|_| "Channel not available".to_string())
 }
 
 #[tauri::command]
@@ -343,14 +735,10 @@ async fn pty_resize(params: PtyResizeParams) -> Result<(), String> {
 
     let mut pty = pty_session.lock();
     match &mut pty.session_type {
-        PtySessionType::Ssh { channel, .. } => {
-            if let Some(ref mut ch) = channel {
-                ch.request_pty_size(params.cols, params.rows, None, None)
-                    .map_err(|e| format!("Failed to resize PTY: {}", e))?;
-                Ok(())
-            } else {
-                Err("Channel not available".to_string())
-            }
+        PtySessionType::Ssh { commands, size, .. } => {
+            commands.try_send(pty_io::IoCommand::Resize { cols: params.cols, rows: params.rows })
+                .map_err(|e| format!("Failed to resize PTY: {}", e))?;
+            *size = (params.cols, params.rows);
         },
         PtySessionType::Local { pty_pair, .. } => {
             let pair = pty_pair.lock();
@@ -360,24 +748,38 @@ async fn pty_resize(params: PtyResizeParams) -> Result<(), String> {
                 pixel_width: 0,
                 pixel_height: 0,
             }).map_err(|e| format!("Failed to resize PTY: {}", e))?;
-            Ok(())
         }
     }
+    recording::capture_resize(&params.session_id, params.cols, params.rows);
+    screen::resize(&params.session_id, params.cols, params.rows);
+    Ok(())
 }
 
 #[tauri::command]
 async fn pty_disconnect(session_id: String) -> Result<String, String> {
-    let mut sessions = PTY_SESSIONS.lock();
+    port_forward::close_for_session(&session_id);
+    sftp::close_session(&session_id);
+    transfers::cancel_for_session(&session_id);
+    let _ = recording::stop(&session_id);
+    automation::stop_for_session(&session_id);
+    session_log::stop(&session_id);
+    screen::close(&session_id);
+    scrollback::close(&session_id);
+    shell_integration::close(&session_id);
+    triggers::close(&session_id);
 
-    if let Some(pty_session) = sessions.remove(&session_id) {
-        let mut pty = pty_session.lock();
-        match &mut pty.session_type {
-            PtySessionType::Ssh { session, channel } => {
-                if let Some(mut ch) = channel.take() {
-                    let _ = ch.close();
-                    let _ = ch.wait_close();
-                }
-                let _ = session.disconnect(None, "Client disconnecting", None);
+    let mut sessions = PTY_SESSIONS.lock();
+    let removed = sessions.remove(&session_id);
+    // After the session is gone, under the same lock broadcast_set_group checks members with,
+    // so a group being set at the same time cannot put it back
+    broadcast::remove_session(&session_id);
+
+    if let Some(pty_session) = removed {
+        let pty = pty_session.lock();
+        match &pty.session_type {
+            PtySessionType::Ssh { commands, .. } => {
+                // The I/O task closes the channel and the connection
+                let _ = commands.try_send(pty_io::IoCommand::Close);
             },
             PtySessionType::Local { .. } => {
                 // Local PTY will be cleaned up when dropped
@@ -389,25 +791,723 @@ async fn pty_disconnect(session_id: String) -> Result<String, String> {
     }
 }
 
+// Lets the session read further ahead; the first ack turns flow control on
+#[tauri::command]
+async fn pty_ack(params: PtyAckParams) -> Result<(), String> {
+    output_flow(&params.session_id)?.ack(params.bytes);
+    Ok(())
+}
+
+#[tauri::command]
+async fn pty_output_stats(session_id: String) -> Result<pty_io::OutputStats, String> {
+    Ok(output_flow(&session_id)?.stats())
+}
+
+fn output_flow(session_id: &str) -> Result<Arc<pty_io::OutputFlow>, String> {
+    let sessions = PTY_SESSIONS.lock();
+    let pty_session = sessions.get(session_id)
+        .ok_or_else(|| "Session not found. Please connect first.".to_string())?;
+    let output = pty_session.lock().output.clone();
+    Ok(output)
+}
+
 #[tauri::command]
 async fn pty_check_connection(session_id: String) -> Result<bool, String> {
     let sessions = PTY_SESSIONS.lock();
     Ok(sessions.contains_key(&session_id))
 }
 
-// Secure Storage Commands
+#[derive(Debug, Serialize, Deserialize)]
+struct PtyAuthRespondParams {
+    session_id: String,
+    /// One answer per prompt, or null to cancel authentication
+    responses: Option<Vec<String>>,
+}
 
 #[tauri::command]
-async fn init_secure_storage(_app: tauri::AppHandle) -> Result<(), String> {
-    // Get the executable's directory for portable database storage
+async fn pty_auth_respond(params: PtyAuthRespondParams) -> Result<(), String> {
+    ssh_auth::respond(&params.session_id, params.responses)
+}
+
+// Clone of the authenticated ssh2 session behind an SSH entry in PTY_SESSIONS.
+// ssh2 serializes access internally, so the clone can be used alongside the reader thread.
+fn ssh_session_handle(session_id: &str) -> Result<Session, String> {
+    let pty_session = PTY_SESSIONS.lock()
+        .get(session_id)
+        .cloned()
+        .ok_or_else(|| "Session not found. Please connect first.".to_string())?;
+
+    let pty = pty_session.lock();
+    match &pty.session_type {
+        PtySessionType::Ssh { session, .. } => Ok(session.clone()),
+        PtySessionType::Local { .. } => Err("This operation requires an SSH session".to_string()),
+    }
+}
+
+// Live session for a background job plus a way to reconnect with the same credentials
+fn ssh_session_source(session_id: &str, window: &Window) -> Result<transfers::SessionSource, String> {
+    let pty_session = PTY_SESSIONS.lock()
+        .get(session_id)
+        .cloned()
+        .ok_or_else(|| "Session not found. Please connect first.".to_string())?;
+
+    let pty = pty_session.lock();
+    match &pty.session_type {
+        PtySessionType::Ssh { session, params, .. } => {
+            let params = params.clone();
+            let window = window.clone();
+            Ok(transfers::SessionSource {
+                live: Some(session.clone()),
+                reconnect: Box::new(move || {
+                    open_ssh_session(&params, ssh_auth::Prompts::Frontend { session_id: &params.session_id, window: &window })
+                }),
+            })
+        }
+        PtySessionType::Local { .. } => Err("This operation requires an SSH session".to_string()),
+    }
+}
+
+// Port Forwarding Commands
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PortForwardParams {
+    session_id: String,
+    #[serde(flatten)]
+    spec: port_forward::ForwardSpec,
+}
+
+#[tauri::command]
+async fn port_forward_create(params: PortForwardParams, window: Window) -> Result<port_forward::ForwardInfo, String> {
+    let sess = ssh_session_handle(&params.session_id)?;
+    port_forward::start(&params.session_id, sess, params.spec, window)
+}
+
+#[tauri::command]
+async fn port_forward_list(session_id: Option<String>) -> Result<Vec<port_forward::ForwardInfo>, String> {
+    Ok(port_forward::list(session_id.as_deref()))
+}
+
+#[tauri::command]
+async fn port_forward_close(forward_id: String) -> Result<(), String> {
+    port_forward::close(&forward_id)
+}
+
+// SFTP Commands
+
+// SFTP calls block until the server answers, so they run off the async runtime
+async fn run_sftp<T: Send + 'static>(
+    session_id: String,
+    f: impl FnOnce(&ssh2::Sftp) -> Result<T, String> + Send + 'static,
+) -> Result<T, String> {
+    let sess = ssh_session_handle(&session_id)?;
+    tauri::async_runtime::spawn_blocking(move || sftp::with_sftp(&session_id, &sess, f)).await
+        .map_err(|e| format!("SFTP task failed: {}", e))?
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SftpPathParams {
+    session_id: String,
+    path: String,
+}
+
+#[tauri::command]
+async fn sftp_list_dir(params: SftpPathParams) -> Result<Vec<sftp::RemoteEntry>, String> {
+    run_sftp(params.session_id, move |sftp| sftp::list_dir(sftp, &params.path)).await
+}
+
+#[tauri::command]
+async fn sftp_stat(params: SftpPathParams) -> Result<sftp::RemoteEntry, String> {
+    run_sftp(params.session_id, move |sftp| sftp::stat(sftp, &params.path)).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SftpMkdirParams {
+    session_id: String,
+    path: String,
+    mode: Option<i32>,
+}
+
+#[tauri::command]
+async fn sftp_mkdir(params: SftpMkdirParams) -> Result<(), String> {
+    run_sftp(params.session_id, move |sftp| sftp::mkdir(sftp, &params.path, params.mode)).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SftpRenameParams {
+    session_id: String,
+    from: String,
+    to: String,
+}
+
+#[tauri::command]
+async fn sftp_rename(params: SftpRenameParams) -> Result<(), String> {
+    run_sftp(params.session_id, move |sftp| sftp::rename(sftp, &params.from, &params.to)).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SftpRemoveParams {
+    session_id: String,
+    path: String,
+    #[serde(default)]
+    recursive: bool,
+}
+
+#[tauri::command]
+async fn sftp_remove(params: SftpRemoveParams) -> Result<(), String> {
+    run_sftp(params.session_id, move |sftp| sftp::remove(sftp, &params.path, params.recursive)).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SftpChmodParams {
+    session_id: String,
+    path: String,
+    mode: u32,
+}
+
+#[tauri::command]
+async fn sftp_chmod(params: SftpChmodParams) -> Result<(), String> {
+    run_sftp(params.session_id, move |sftp| sftp::chmod(sftp, &params.path, params.mode)).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SftpReadFileParams {
+    session_id: String,
+    path: String,
+    max_bytes: Option<u64>,
+}
+
+#[tauri::command]
+async fn sftp_read_file(params: SftpReadFileParams) -> Result<sftp::FileContent, String> {
+    let max_bytes = params.max_bytes.unwrap_or(sftp::DEFAULT_MAX_READ_BYTES);
+    run_sftp(params.session_id, move |sftp| sftp::read_file(sftp, &params.path, max_bytes)).await
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SftpWriteFileParams {
+    session_id: String,
+    path: String,
+    /// Base64-encoded contents
+    data: String,
+    mode: Option<i32>,
+}
+
+#[tauri::command]
+async fn sftp_write_file(params: SftpWriteFileParams) -> Result<(), String> {
+    run_sftp(params.session_id, move |sftp| sftp::write_file(sftp, &params.path, &params.data, params.mode)).await
+}
+
+// ssh_config Commands
+
+fn load_ssh_config(config_path: Option<String>) -> Result<ssh_config::SshConfig, String> {
+    let path = match config_path {
+        Some(path) => PathBuf::from(path),
+        None => ssh_config::default_config_path().ok_or("Could not locate ~/.ssh/config")?,
+    };
+    ssh_config::SshConfig::load(&path)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SshConfigResolveParams {
+    session_id: String,
+    alias: String,
+    /// Defaults to ~/.ssh/config
+    config_path: Option<String>,
+}
+
+#[tauri::command]
+async fn ssh_config_resolve(params: SshConfigResolveParams) -> Result<ConnectionParams, String> {
+    let config = load_ssh_config(params.config_path)?;
+    let resolved = config.resolve(&params.alias);
+    let ssh_key_path = ssh_config::first_identity_file(&resolved.identity_files);
+
+    Ok(ConnectionParams {
+        session_id: params.session_id,
+        host: resolved.host_name.clone(),
+        port: resolved.port,
+        credentials: ssh_auth::Credentials {
+            username: resolved.user.clone()
+                .or_else(ssh_config::local_username)
+                .unwrap_or_default(),
+            // Without an IdentityFile, ssh falls back to the agent
+            auth_method: if ssh_key_path.is_none() { Some(ssh_auth::AuthMethod::Agent) } else { None },
+            ssh_key_path,
+            ..Default::default()
+        },
+        jump_hosts: config.jump_hosts(&resolved)?,
+        reconnect: Default::default(),
+        timeouts: Default::default(),
+        output_format: Default::default(),
+        encoding: None,
+        session_log: None,
+        shell_integration: false,
+        server_id: None,
+    })
+}
+
+#[tauri::command]
+async fn ssh_config_import(config_path: Option<String>) -> Result<Vec<ssh_config::ImportedServer>, String> {
+    load_ssh_config(config_path)?.import_servers()
+}
+
+// Remote Exec Commands
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SshExecParams {
+    session_id: String,
+    /// Lets streaming callers match `ssh-exec-output` events before the command returns
+    #[serde(default)]
+    exec_id: Option<String>,
+    /// Run on a new connection instead of the terminal's session
+    #[serde(default)]
+    fresh_connection: bool,
+    #[serde(flatten)]
+    request: exec::ExecRequest,
+}
+
+#[tauri::command]
+async fn ssh_exec(params: SshExecParams, window: Window) -> Result<exec::ExecOutput, String> {
+    let source = ssh_session_source(&params.session_id, &window)?;
+    // Off the async runtime: the command runs to completion, and a fresh connection may
+    // wait on host key and keyboard-interactive prompts
+    tauri::async_runtime::spawn_blocking(move || {
+        let sess = if params.fresh_connection {
+            (source.reconnect)()?
+        } else {
+            source.live.ok_or("Session not found. Please connect first.")?
+        };
+
+        let exec_id = params.exec_id.unwrap_or_else(exec::next_exec_id);
+        let result = exec::run(&sess, &exec_id, &params.request, |stream, data| {
+            let _ = window.emit("ssh-exec-output", serde_json::json!({
+                "exec_id": exec_id,
+                "session_id": params.session_id,
+                "stream": stream,
+                "data": String::from_utf8_lossy(data)
+            }));
+        });
+
+        if params.fresh_connection {
+            let _ = sess.disconnect(None, "Command finished", None);
+        }
+        result
+    }).await
+        .map_err(|e| format!("Exec task failed: {}", e))?
+}
+
+#[tauri::command]
+async fn ssh_exec_cancel(exec_id: String) -> Result<(), String> {
+    exec::cancel(&exec_id)
+}
+
+// Cluster Exec Commands
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ClusterExecParams {
+    /// Lets callers match `cluster-exec-*` events before the command returns
+    #[serde(default)]
+    run_id: Option<String>,
+    #[serde(flatten)]
+    request: cluster::ClusterExecRequest,
+}
+
+#[tauri::command]
+async fn cluster_exec(params: ClusterExecParams, window: Window) -> Result<cluster::ClusterSummary, String> {
+    if params.request.hosts.is_empty() {
+        return Err("No hosts selected".to_string());
+    }
+
+    let run_id = params.run_id.unwrap_or_else(cluster::next_run_id);
+    // Blocking connects and commands, off the async runtime. Nobody answers prompts for a
+    // run across many hosts, so hosts that would ask for one fail instead.
+    tauri::async_runtime::spawn_blocking(move || {
+        cluster::run(&run_id, &params.request, &window, |host| {
+            open_saved_host_session(format!("{}:{}", run_id, host.server_id), host, ssh_auth::Prompts::Unattended)
+        })
+    }).await
+        .map_err(|e| format!("Cluster task failed: {}", e))
+}
+
+// Connect to a saved server with its stored credentials, for running commands
+fn open_saved_host_session(session_id: String, host: &cluster::ClusterHost, prompts: ssh_auth::Prompts) -> Result<Session, String> {
+    let connection = ConnectionParams {
+        session_id,
+        host: host.host.clone(),
+        port: host.port,
+        credentials: ssh_auth::stored_credentials(&host.server_id)?,
+        jump_hosts: host.jump_hosts.clone(),
+        reconnect: Default::default(),
+        timeouts: Default::default(),
+        output_format: Default::default(),
+        encoding: None,
+        session_log: None,
+        shell_integration: false,
+        server_id: Some(host.server_id.clone()),
+    };
+    open_ssh_session(&connection, prompts)
+}
+
+#[tauri::command]
+async fn cluster_exec_cancel(run_id: String) -> Result<(), String> {
+    cluster::cancel(&run_id)
+}
+
+// Transfer Commands
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TransferEnqueueParams {
+    session_id: String,
+    #[serde(flatten)]
+    request: transfers::TransferRequest,
+}
+
+#[tauri::command]
+async fn transfer_enqueue(params: TransferEnqueueParams, window: Window) -> Result<transfers::TransferInfo, String> {
+    let source = ssh_session_source(&params.session_id, &window)?;
+    Ok(transfers::enqueue(&params.session_id, params.request, source, window))
+}
+
+#[tauri::command]
+async fn transfer_list(session_id: Option<String>) -> Result<Vec<transfers::TransferInfo>, String> {
+    Ok(transfers::list(session_id.as_deref()))
+}
+
+#[tauri::command]
+async fn transfer_cancel(transfer_id: String) -> Result<(), String> {
+    transfers::cancel(&transfer_id)
+}
+
+#[tauri::command]
+async fn transfer_resume(transfer_id: String, window: Window) -> Result<transfers::TransferInfo, String> {
+    let session_id = transfers::list(None).into_iter()
+        .find(|info| info.id == transfer_id)
+        .map(|info| info.session_id)
+        .ok_or_else(|| format!("Transfer {} not found", transfer_id))?;
+    let source = ssh_session_source(&session_id, &window)?;
+    transfers::resume(&transfer_id, source)
+}
+
+// Recording Commands
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordingStartParams {
+    session_id: String,
+    #[serde(flatten)]
+    options: recording::RecordingOptions,
+}
+
+#[tauri::command]
+async fn recording_start(params: RecordingStartParams) -> Result<recording::RecordingInfo, String> {
+    let (size, charset) = {
+        let sessions = PTY_SESSIONS.lock();
+        let pty_session = sessions.get(&params.session_id)
+            .ok_or_else(|| "Session not found. Please connect first.".to_string())?;
+        let pty = pty_session.lock();
+        (pty.size(), pty.charset)
+    };
+    recording::start(&params.session_id, &params.options, size, charset)
+}
+
+#[tauri::command]
+async fn recording_stop(session_id: String) -> Result<recording::RecordingInfo, String> {
+    recording::stop(&session_id)
+}
+
+#[tauri::command]
+async fn recording_status(session_id: String) -> Result<Option<recording::RecordingInfo>, String> {
+    Ok(recording::status(&session_id))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordingPlayParams {
+    /// Session id the replayed pty-output events are addressed to
+    playback_id: String,
+    #[serde(flatten)]
+    options: recording::PlaybackOptions,
+}
+
+#[tauri::command]
+async fn recording_play(params: RecordingPlayParams, window: Window) -> Result<recording::CastHeader, String> {
+    recording::play(&params.playback_id, &params.options, window)
+}
+
+#[tauri::command]
+async fn recording_play_stop(playback_id: String) -> Result<(), String> {
+    recording::stop_playback(&playback_id)
+}
+
+// Session Log Commands
+
+#[tauri::command]
+async fn session_log_get_settings() -> Result<session_log::LogSettings, String> {
+    Ok(session_log::settings())
+}
+
+// Saved to the database; applies to sessions opened from now on
+#[tauri::command]
+async fn session_log_set_settings(settings: session_log::LogSettings) -> Result<(), String> {
+    let json = serde_json::to_string(&settings).map_err(|e| e.to_string())?;
+    secure_storage::with_database(|db| db.set_setting(session_log::SETTINGS_KEY, &json))?;
+    session_log::set_settings(settings);
+    Ok(())
+}
+
+#[tauri::command]
+async fn session_log_status(session_id: String) -> Result<Option<session_log::LogInfo>, String> {
+    Ok(session_log::status(&session_id))
+}
+
+// Automation Commands
+
+#[tauri::command]
+async fn automation_save_script(script: automation::AutomationScript) -> Result<(), String> {
+    automation::save_script(&script)
+}
+
+#[tauri::command]
+async fn automation_list_scripts() -> Result<Vec<automation::AutomationScript>, String> {
+    automation::list_scripts()
+}
+
+#[tauri::command]
+async fn automation_delete_script(script_id: String) -> Result<(), String> {
+    automation::delete_script(&script_id)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AutomationRunParams {
+    session_id: String,
+    script_id: String,
+    /// Lets callers match `automation-*` events before the command returns
+    #[serde(default)]
+    run_id: Option<String>,
+}
+
+// Returns the run id once the script has started; the outcome arrives as an event
+#[tauri::command]
+async fn automation_run(params: AutomationRunParams, window: Window) -> Result<String, String> {
+    let charset = session_charset(&params.session_id)?;
+    let script = automation::load_script(&params.script_id)?;
+    let run_id = params.run_id.unwrap_or_else(automation::next_run_id);
+    automation::start(&run_id, &params.session_id, &script, charset, window)?;
+    Ok(run_id)
+}
+
+#[tauri::command]
+async fn automation_cancel(run_id: String) -> Result<(), String> {
+    automation::cancel(&run_id)
+}
+
+// Macro Commands
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MacroSaveParams {
+    name: String,
+    source: String,
+}
+
+#[tauri::command]
+async fn macro_save(params: MacroSaveParams) -> Result<(), String> {
+    macros::save(&params.name, &params.source)
+}
+
+#[tauri::command]
+async fn macro_list() -> Result<Vec<macros::MacroInfo>, String> {
+    macros::list()
+}
+
+#[tauri::command]
+async fn macro_delete(name: String) -> Result<(), String> {
+    macros::delete(&name)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MacroRunParams {
+    name: String,
+    /// Lets callers match `macro-*` events before the command returns
+    #[serde(default)]
+    run_id: Option<String>,
+    /// Available to the macro as `args`
+    #[serde(default)]
+    args: serde_json::Value,
+}
+
+// Returns the run id once the macro has started; its output and result arrive as events
+#[tauri::command]
+async fn macro_run(params: MacroRunParams, window: Window) -> Result<String, String> {
+    let run_id = params.run_id.unwrap_or_else(macros::next_run_id);
+    macros::start(&run_id, &params.name, params.args, window)?;
+    Ok(run_id)
+}
+
+#[tauri::command]
+async fn macro_cancel(run_id: String) -> Result<(), String> {
+    macros::cancel(&run_id)
+}
+
+// Broadcast Commands
+
+// Create the group or replace its members, which must be open sessions. The sessions stay
+// locked until the group is set, so none can close in between and be left in it.
+#[tauri::command]
+async fn broadcast_set_group(params: BroadcastGroupParams) -> Result<broadcast::BroadcastGroup, String> {
+    let sessions = PTY_SESSIONS.lock();
+    if let Some(missing) = params.session_ids.iter().find(|id| !sessions.contains_key(*id)) {
+        return Err(format!("Session {} not found", missing));
+    }
+    broadcast::set_group(&params.group_id, params.session_ids)
+}
+
+#[tauri::command]
+async fn broadcast_delete_group(group_id: String) -> Result<(), String> {
+    broadcast::delete_group(&group_id)
+}
+
+#[tauri::command]
+async fn broadcast_list_groups() -> Result<Vec<broadcast::BroadcastGroup>, String> {
+    Ok(broadcast::groups())
+}
+
+#[tauri::command]
+async fn broadcast_set_excluded(params: BroadcastExcludeParams) -> Result<broadcast::BroadcastGroup, String> {
+    broadcast::set_excluded(&params.group_id, &params.session_id, params.excluded)
+}
+
+// pty_write for every member of the group; members that fail are listed in the result
+#[tauri::command]
+async fn broadcast_write(params: BroadcastWriteParams) -> Result<broadcast::BroadcastResult, String> {
+    broadcast::write(&params.group_id, &params.data, write_member_input).await
+}
+
+// Trigger Commands
+
+#[tauri::command]
+async fn trigger_list() -> Result<Vec<triggers::TriggerRule>, String> {
+    Ok(triggers::rules())
+}
+
+// Saved to the database; open sessions pick it up with their next output
+#[tauri::command]
+async fn trigger_save(rule: triggers::TriggerRule) -> Result<(), String> {
+    triggers::save_rule(rule)
+}
+
+#[tauri::command]
+async fn trigger_delete(id: String) -> Result<(), String> {
+    triggers::delete_rule(&id)
+}
+
+// Screen Commands
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ScreenSnapshotParams {
+    session_id: String,
+    /// Also return every row's cell attributes
+    #[serde(default)]
+    include_cells: bool,
+}
+
+#[tauri::command]
+async fn get_screen_snapshot(params: ScreenSnapshotParams) -> Result<screen::ScreenSnapshot, String> {
+    screen::snapshot(&params.session_id, params.include_cells)
+}
+
+// Scrollback Commands
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ScrollbackGetParams {
+    session_id: String,
+    /// First line number to return; the newest lines when unset
+    #[serde(default)]
+    start: Option<u64>,
+    #[serde(default)]
+    count: Option<usize>,
+}
+
+#[tauri::command]
+async fn scrollback_get(params: ScrollbackGetParams) -> Result<scrollback::ScrollbackRange, String> {
+    scrollback::range(&params.session_id, params.start, params.count)
+}
+
+#[tauri::command]
+async fn scrollback_search(params: scrollback::SearchRequest) -> Result<scrollback::SearchResult, String> {
+    scrollback::search(&params)
+}
+
+#[tauri::command]
+async fn scrollback_clear(session_id: String) -> Result<(), String> {
+    scrollback::clear(&session_id)
+}
+
+// Shell Integration Commands
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ShellHistoryParams {
+    session_id: String,
+    /// Only the most recent commands
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+#[tauri::command]
+async fn shell_command_history(params: ShellHistoryParams) -> Result<shell_integration::ShellHistory, String> {
+    shell_integration::history(&params.session_id, params.limit)
+}
+
+// Host Key Commands
+
+#[tauri::command]
+async fn accept_host_key(session_id: String) -> Result<known_hosts::HostKeyInfo, String> {
+    known_hosts::accept_pending(&session_id)
+}
+
+#[tauri::command]
+async fn reject_host_key(session_id: String) -> Result<(), String> {
+    known_hosts::reject_pending(&session_id)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ForgetHostKeyParams {
+    host: String,
+    port: u16,
+}
+
+#[tauri::command]
+async fn forget_host_key(params: ForgetHostKeyParams) -> Result<bool, String> {
+    known_hosts::forget_host(&params.host, params.port)
+}
+
+// Directory next to the executable, used for portable app data (database, known_hosts)
+fn app_data_dir() -> Result<PathBuf, String> {
     let exe_path = std::env::current_exe()
         .map_err(|e| format!("Failed to get executable path: {}", e))?;
 
     let app_dir = exe_path.parent()
         .ok_or("Failed to get executable parent directory")?;
 
-    let db_path = app_dir.join("nebulaterm.db");
+    Ok(app_dir.to_path_buf())
+}
+
+// Secure Storage Commands
+
+#[tauri::command]
+async fn init_secure_storage(app: tauri::AppHandle) -> Result<(), String> {
+    // Get the executable's directory for portable database storage
+    let db_path = app_data_dir()?.join("nebulaterm.db");
     secure_storage::init_database(db_path)?;
+
+    // Settings kept in the database
+    let log_settings = secure_storage::with_database(|db| db.get_setting(session_log::SETTINGS_KEY))?;
+    if let Some(settings) = log_settings.and_then(|json| serde_json::from_str(&json).ok()) {
+        session_log::set_settings(settings);
+    }
+    // A rule that no longer compiles is reported, not a reason to refuse the database
+    for skipped in triggers::load()? {
+        let _ = app.emit_all("trigger-error", serde_json::json!({
+            "session_id": null,
+            "rule_id": skipped.rule_id,
+            "error": skipped.error
+        }));
+    }
     Ok(())
 }
 
@@ -507,6 +1607,64 @@ fn main() {
             pty_resize,
             pty_disconnect,
             pty_check_connection,
+            pty_ack,
+            pty_output_stats,
+            pty_auth_respond,
+            ssh_config_resolve,
+            ssh_config_import,
+            port_forward_create,
+            port_forward_list,
+            port_forward_close,
+            sftp_list_dir,
+            sftp_stat,
+            sftp_mkdir,
+            sftp_rename,
+            sftp_remove,
+            sftp_chmod,
+            sftp_read_file,
+            sftp_write_file,
+            ssh_exec,
+            ssh_exec_cancel,
+            cluster_exec,
+            cluster_exec_cancel,
+            transfer_enqueue,
+            transfer_list,
+            transfer_cancel,
+            transfer_resume,
+            recording_start,
+            recording_stop,
+            recording_status,
+            recording_play,
+            recording_play_stop,
+            session_log_get_settings,
+            session_log_set_settings,
+            session_log_status,
+            automation_save_script,
+            automation_list_scripts,
+            automation_delete_script,
+            automation_run,
+            automation_cancel,
+            macro_save,
+            macro_list,
+            macro_delete,
+            macro_run,
+            macro_cancel,
+            broadcast_set_group,
+            broadcast_delete_group,
+            broadcast_list_groups,
+            broadcast_set_excluded,
+            broadcast_write,
+            trigger_list,
+            trigger_save,
+            trigger_delete,
+            get_screen_snapshot,
+            scrollback_get,
+            scrollback_search,
+            scrollback_clear,
+            shell_command_history,
+            accept_host_key,
+            reject_host_key,
+            forget_host_key,
             init_secure_storage,
             has_master_password,
             set_master_password,
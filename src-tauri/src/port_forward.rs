@@ -0,0 +1,554 @@
+use crate::tunnel;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use ssh2::Session;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tauri::Window;
+
+// How often listener threads check whether their forward was closed
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const SOCKS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+static NEXT_FORWARD_ID: AtomicU64 = AtomicU64::new(1);
+
+// Active forwards, keyed by forward id
+static FORWARDS: Lazy<Mutex<HashMap<String, Forward>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardKind {
+    /// ssh -L: local port -> remote target
+    Local,
+    /// ssh -R: remote port -> local target
+    Remote,
+    /// ssh -D: local SOCKS5 proxy
+    Dynamic,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardSpec {
+    pub kind: ForwardKind,
+    /// Defaults to 127.0.0.1 locally and localhost on the server
+    #[serde(default)]
+    pub bind_host: Option<String>,
+    /// 0 lets the OS (or the server, for remote forwards) pick a port
+    pub bind_port: u16,
+    #[serde(default)]
+    pub target_host: Option<String>,
+    #[serde(default)]
+    pub target_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardInfo {
+    pub id: String,
+    pub session_id: String,
+    pub kind: ForwardKind,
+    pub bind_host: String,
+    pub bind_port: u16,
+    pub target_host: Option<String>,
+    pub target_port: Option<u16>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub active_connections: u64,
+    pub total_connections: u64,
+}
+
+#[derive(Default)]
+struct ForwardCounters {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    active_connections: AtomicU64,
+    total_connections: AtomicU64,
+}
+
+struct Forward {
+    info: ForwardInfo,
+    counters: Arc<ForwardCounters>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Forward {
+    fn snapshot(&self) -> ForwardInfo {
+        ForwardInfo {
+            bytes_sent: self.counters.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.counters.bytes_received.load(Ordering::Relaxed),
+            active_connections: self.counters.active_connections.load(Ordering::Relaxed),
+            total_connections: self.counters.total_connections.load(Ordering::Relaxed),
+            ..self.info.clone()
+        }
+    }
+}
+
+/// Everything a forward's worker threads need
+#[derive(Clone)]
+struct ForwardContext {
+    id: String,
+    session_id: String,
+    sess: Session,
+    counters: Arc<ForwardCounters>,
+    stop: Arc<AtomicBool>,
+    window: Window,
+}
+
+impl ForwardContext {
+    fn emit_error(&self, error: String) {
+        let _ = self.window.emit("port-forward-error", serde_json::json!({
+            "forward_id": self.id,
+            "session_id": self.session_id,
+            "error": error
+        }));
+    }
+
+    // `peer` is the connecting client; None for remote forwards, as libssh2 does not pass on
+    // the originator address the server sends with the channel
+    fn emit_connection(&self, peer: Option<&str>, target: &(String, u16)) {
+        let _ = self.window.emit("port-forward-connection", serde_json::json!({
+            "forward_id": self.id,
+            "session_id": self.session_id,
+            "peer": peer,
+            "target": format!("{}:{}", target.0, target.1)
+        }));
+    }
+
+    /// Shuttle one connection, updating the forward's counters
+    fn run_pump(&self, mut channel: ssh2::Channel, mut socket: TcpStream) {
+        self.counters.active_connections.fetch_add(1, Ordering::Relaxed);
+        self.counters.total_connections.fetch_add(1, Ordering::Relaxed);
+
+        let result = tunnel::pump(&mut channel, &mut socket, |progress| {
+            self.counters.bytes_sent.fetch_add(progress.sent as u64, Ordering::Relaxed);
+            self.counters.bytes_received.fetch_add(progress.received as u64, Ordering::Relaxed);
+            !self.stop.load(Ordering::Relaxed)
+        });
+        if let Err(e) = result {
+            self.emit_error(format!("Connection closed with error: {}", e));
+        }
+
+        let _ = tunnel::retry_would_block(|| channel.close());
+        self.counters.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ForwardSpec {
+    fn target(&self) -> Result<(String, u16), String> {
+        match (&self.target_host, self.target_port) {
+            (Some(host), Some(port)) => Ok((host.clone(), port)),
+            _ => Err("This forward requires target_host and target_port".to_string()),
+        }
+    }
+}
+
+impl ForwardInfo {
+    // The spec that sets the forward up again on the port it got
+    fn spec(&self) -> ForwardSpec {
+        ForwardSpec {
+            kind: self.kind,
+            bind_host: Some(self.bind_host.clone()),
+            bind_port: self.bind_port,
+            target_host: self.target_host.clone(),
+            target_port: self.target_port,
+        }
+    }
+}
+
+/// Start a forward on an authenticated (non-blocking) session
+pub fn start(session_id: &str, sess: Session, spec: ForwardSpec, window: Window) -> Result<ForwardInfo, String> {
+    let id = format!("fwd-{}", NEXT_FORWARD_ID.fetch_add(1, Ordering::Relaxed));
+    start_as(id, session_id, sess, spec, window)
+}
+
+fn start_as(id: String, session_id: &str, sess: Session, spec: ForwardSpec, window: Window) -> Result<ForwardInfo, String> {
+    let ctx = ForwardContext {
+        id: id.clone(),
+        session_id: session_id.to_string(),
+        sess,
+        counters: Arc::new(ForwardCounters::default()),
+        stop: Arc::new(AtomicBool::new(false)),
+        window,
+    };
+
+    let (bind_host, bind_port) = match spec.kind {
+        ForwardKind::Local | ForwardKind::Dynamic => {
+            if spec.kind == ForwardKind::Local {
+                spec.target()?;
+            }
+            let bind_host = spec.bind_host.clone().unwrap_or_else(|| "127.0.0.1".to_string());
+            let listener = TcpListener::bind((bind_host.as_str(), spec.bind_port))
+                .map_err(|e| format!("Failed to listen on {}:{} - {}", bind_host, spec.bind_port, e))?;
+            listener.set_nonblocking(true)
+                .map_err(|e| format!("Failed to configure listener: {}", e))?;
+            let bind_port = listener.local_addr()
+                .map_err(|e| format!("Failed to read listener address: {}", e))?
+                .port();
+
+            let ctx = ctx.clone();
+            let spec = spec.clone();
+            thread::spawn(move || accept_local(listener, spec, ctx));
+            (bind_host, bind_port)
+        }
+        ForwardKind::Remote => {
+            let target = spec.target()?;
+            let bind_host = spec.bind_host.clone().unwrap_or_else(|| "localhost".to_string());
+            let (listener, bind_port) = tunnel::retry_would_block(|| {
+                ctx.sess.channel_forward_listen(spec.bind_port, Some(&bind_host), None)
+            })
+            .map_err(|e| format!("Server refused to listen on {}:{} - {}", bind_host, spec.bind_port, e))?;
+
+            let ctx = ctx.clone();
+            thread::spawn(move || accept_remote(listener, target, ctx));
+            (bind_host, bind_port)
+        }
+    };
+
+    let forward = Forward {
+        info: ForwardInfo {
+            id: id.clone(),
+            session_id: session_id.to_string(),
+            kind: spec.kind,
+            bind_host,
+            bind_port,
+            target_host: spec.target_host,
+            target_port: spec.target_port,
+            bytes_sent: 0,
+            bytes_received: 0,
+            active_connections: 0,
+            total_connections: 0,
+        },
+        counters: ctx.counters,
+        stop: ctx.stop,
+    };
+    let info = forward.snapshot();
+    FORWARDS.lock().insert(id, forward);
+
+    Ok(info)
+}
+
+/// List forwards, optionally only those of one session
+pub fn list(session_id: Option<&str>) -> Vec<ForwardInfo> {
+    let forwards = FORWARDS.lock();
+    let mut infos: Vec<ForwardInfo> = forwards.values()
+        .filter(|f| session_id.is_none_or(|id| f.info.session_id == id))
+        .map(Forward::snapshot)
+        .collect();
+    infos.sort_by(|a, b| a.id.cmp(&b.id));
+    infos
+}
+
+/// Stop a forward; its listener and open connections shut down in the background
+pub fn close(forward_id: &str) -> Result<(), String> {
+    let forward = FORWARDS.lock().remove(forward_id)
+        .ok_or("Port forward not found")?;
+    forward.stop.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Stop every forward belonging to a session (used when the session disconnects). Returns
+/// the forwards that were stopped, for `restore`.
+pub fn close_for_session(session_id: &str) -> Vec<ForwardInfo> {
+    let mut closed = Vec::new();
+    FORWARDS.lock().retain(|_, forward| {
+        let keep = forward.info.session_id != session_id;
+        if !keep {
+            forward.stop.store(true, Ordering::Relaxed);
+            closed.push(forward.info.clone());
+        }
+        keep
+    });
+    closed.sort_by(|a, b| a.id.cmp(&b.id));
+    closed
+}
+
+/// Start forwards stopped by `close_for_session` on the session's new connection, under their
+/// old ids and ports. Those that cannot be started again are reported with `port-forward-error`.
+pub fn restore(session_id: &str, sess: &Session, forwards: Vec<ForwardInfo>, window: &Window) -> Vec<ForwardInfo> {
+    let mut restored = Vec::new();
+    for forward in forwards {
+        match start_as(forward.id.clone(), session_id, sess.clone(), forward.spec(), window.clone()) {
+            Ok(info) => restored.push(info),
+            Err(e) => {
+                let _ = window.emit("port-forward-error", serde_json::json!({
+                    "forward_id": forward.id,
+                    "session_id": session_id,
+                    "error": format!("Not restored after reconnecting: {}", e)
+                }));
+            }
+        }
+    }
+    restored
+}
+
+fn accept_local(listener: TcpListener, spec: ForwardSpec, ctx: ForwardContext) {
+    while !ctx.stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((socket, peer)) => {
+                let ctx = ctx.clone();
+                let spec = spec.clone();
+                thread::spawn(move || handle_local_connection(socket, peer.to_string(), spec, ctx));
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) => {
+                ctx.emit_error(format!("Listener failed: {}", e));
+                break;
+            }
+        }
+    }
+}
+
+fn handle_local_connection(mut socket: TcpStream, peer: String, spec: ForwardSpec, ctx: ForwardContext) {
+    // Accepted sockets may inherit non-blocking mode from the listener
+    let _ = socket.set_nonblocking(false);
+    let _ = socket.set_read_timeout(Some(SOCKS_HANDSHAKE_TIMEOUT));
+
+    let target = match spec.kind {
+        ForwardKind::Dynamic => match socks5_handshake(&mut socket) {
+            Ok(target) => target,
+            Err(e) => {
+                ctx.emit_error(format!("SOCKS5 handshake with {} failed: {}", peer, e));
+                return;
+            }
+        },
+        _ => match spec.target() {
+            Ok(target) => target,
+            Err(_) => return,
+        },
+    };
+
+    let channel = tunnel::retry_would_block(|| ctx.sess.channel_direct_tcpip(&target.0, target.1, None));
+
+    if spec.kind == ForwardKind::Dynamic {
+        let reply = if channel.is_ok() { SOCKS_REPLY_SUCCEEDED } else { SOCKS_REPLY_HOST_UNREACHABLE };
+        if socks5_reply(&mut socket, reply).is_err() {
+            return;
+        }
+    }
+
+    match channel {
+        Ok(channel) => {
+            ctx.emit_connection(Some(&peer), &target);
+            ctx.run_pump(channel, socket);
+        }
+        Err(e) => ctx.emit_error(format!("Failed to open tunnel to {}:{} - {}", target.0, target.1, e)),
+    }
+}
+
+fn accept_remote(mut listener: ssh2::Listener, target: (String, u16), ctx: ForwardContext) {
+    while !ctx.stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok(channel) => {
+                let ctx = ctx.clone();
+                let target = target.clone();
+                thread::spawn(move || match TcpStream::connect((target.0.as_str(), target.1)) {
+                    Ok(socket) => {
+                        ctx.emit_connection(None, &target);
+                        ctx.run_pump(channel, socket);
+                    }
+                    Err(e) => {
+                        let mut channel = channel;
+                        let _ = tunnel::retry_would_block(|| channel.close());
+                        ctx.emit_error(format!("Failed to connect to {}:{} - {}", target.0, target.1, e));
+                    }
+                });
+            }
+            Err(ref e) if tunnel::is_would_block(e) => {
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            }
+            Err(e) => {
+                ctx.emit_error(format!("Remote listener failed: {}", e));
+                break;
+            }
+        }
+    }
+    // Dropping the listener cancels the remote forward
+}
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTH: u8 = 0;
+const SOCKS_NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+const SOCKS_CMD_CONNECT: u8 = 1;
+const SOCKS_REPLY_SUCCEEDED: u8 = 0;
+const SOCKS_REPLY_HOST_UNREACHABLE: u8 = 4;
+const SOCKS_REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const SOCKS_REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Negotiate a no-auth SOCKS5 CONNECT and return the requested destination.
+/// The caller sends the final reply once it knows whether the tunnel opened.
+fn socks5_handshake<S: Read + Write>(stream: &mut S) -> Result<(String, u16), String> {
+    let io_err = |e: std::io::Error| e.to_string();
+
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).map_err(io_err)?;
+    if header[0] != SOCKS_VERSION {
+        return Err(format!("Unsupported SOCKS version {}", header[0]));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).map_err(io_err)?;
+    if !methods.contains(&SOCKS_NO_AUTH) {
+        let _ = stream.write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHODS]);
+        return Err("Client does not support unauthenticated SOCKS5".to_string());
+    }
+    stream.write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH]).map_err(io_err)?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).map_err(io_err)?;
+    if request[1] != SOCKS_CMD_CONNECT {
+        let _ = socks5_reply(stream, SOCKS_REPLY_COMMAND_NOT_SUPPORTED);
+        return Err(format!("Unsupported SOCKS command {}", request[1]));
+    }
+
+    let host = match request[3] {
+        1 => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr).map_err(io_err)?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).map_err(io_err)?;
+            let mut name = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut name).map_err(io_err)?;
+            String::from_utf8(name).map_err(|_| "Invalid domain name".to_string())?
+        }
+        4 => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr).map_err(io_err)?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        other => {
+            let _ = socks5_reply(stream, SOCKS_REPLY_ADDRESS_NOT_SUPPORTED);
+            return Err(format!("Unsupported SOCKS address type {}", other));
+        }
+    };
+
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).map_err(io_err)?;
+
+    Ok((host, u16::from_be_bytes(port)))
+}
+
+fn socks5_reply<S: Write + ?Sized>(stream: &mut S, reply: u8) -> std::io::Result<()> {
+    // Bound address is not meaningful for a tunnel, so report 0.0.0.0:0
+    stream.write_all(&[SOCKS_VERSION, reply, 0, 1, 0, 0, 0, 0, 0, 0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// In-memory duplex stream: reads from `input`, records writes in `output`
+    struct FakeStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for FakeStream {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for FakeStream {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn fake_stream(input: Vec<u8>) -> FakeStream {
+        FakeStream { input: Cursor::new(input), output: Vec::new() }
+    }
+
+    #[test]
+    fn test_closed_forwards_can_be_restored() {
+        let info = ForwardInfo {
+            id: "fwd-test".to_string(),
+            session_id: "fwd-test-session".to_string(),
+            kind: ForwardKind::Local,
+            bind_host: "127.0.0.1".to_string(),
+            bind_port: 15432,
+            target_host: Some("db".to_string()),
+            target_port: Some(5432),
+            bytes_sent: 0,
+            bytes_received: 0,
+            active_connections: 0,
+            total_connections: 0,
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        FORWARDS.lock().insert(info.id.clone(), Forward {
+            info,
+            counters: Arc::new(ForwardCounters::default()),
+            stop: stop.clone(),
+        });
+
+        let closed = close_for_session("fwd-test-session");
+        assert!(stop.load(Ordering::Relaxed));
+        assert!(list(Some("fwd-test-session")).is_empty());
+        assert_eq!(closed.len(), 1);
+        // Started again on the port it had, not a fresh one
+        let spec = closed[0].spec();
+        assert_eq!((spec.bind_host.as_deref(), spec.bind_port), (Some("127.0.0.1"), 15432));
+        assert_eq!(spec.target().unwrap(), ("db".to_string(), 5432));
+    }
+
+    #[test]
+    fn test_socks5_domain_connect() {
+        let mut input = vec![5, 1, 0, 5, 1, 0, 3, 11];
+        input.extend_from_slice(b"example.com");
+        input.extend_from_slice(&443u16.to_be_bytes());
+        let mut stream = fake_stream(input);
+
+        let target = socks5_handshake(&mut stream).unwrap();
+        assert_eq!(target, ("example.com".to_string(), 443));
+        assert_eq!(stream.output, vec![5, 0]);
+    }
+
+    #[test]
+    fn test_socks5_ipv4_and_ipv6_connect() {
+        let mut stream = fake_stream(vec![5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 22]);
+        assert_eq!(socks5_handshake(&mut stream).unwrap(), ("10.0.0.1".to_string(), 22));
+
+        let mut input = vec![5, 1, 0, 5, 1, 0, 4];
+        input.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        input.extend_from_slice(&[0x1f, 0x90]);
+        let mut stream = fake_stream(input);
+        assert_eq!(socks5_handshake(&mut stream).unwrap(), ("::1".to_string(), 8080));
+    }
+
+    #[test]
+    fn test_socks5_rejects_auth_only_clients() {
+        let mut stream = fake_stream(vec![5, 1, 2]);
+        assert!(socks5_handshake(&mut stream).is_err());
+        assert_eq!(stream.output, vec![5, SOCKS_NO_ACCEPTABLE_METHODS]);
+    }
+
+    #[test]
+    fn test_socks5_rejects_bind_command() {
+        let mut stream = fake_stream(vec![5, 1, 0, 5, 2, 0, 1, 10, 0, 0, 1, 0, 22]);
+        assert!(socks5_handshake(&mut stream).is_err());
+        assert_eq!(stream.output[2..4], [5, SOCKS_REPLY_COMMAND_NOT_SUPPORTED]);
+    }
+
+    #[test]
+    fn test_local_forward_requires_target() {
+        let spec = ForwardSpec {
+            kind: ForwardKind::Local,
+            bind_host: None,
+            bind_port: 0,
+            target_host: Some("db.internal".to_string()),
+            target_port: None,
+        };
+        assert!(spec.target().is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{known_hosts, ssh_auth, timeouts, tunnel};
+use serde::{Deserialize, Serialize};
+use ssh2::{Channel, Session};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Connections from other processes `socket_pair` turns away before giving up
+const MAX_FOREIGN_CONNECTIONS: usize = 16;
+
+/// One bastion in a ProxyJump chain.
+///
+/// Credentials are loaded from secure storage when `credential_id` is set; otherwise the hop
+/// logs in as `username` with `ssh_key_path`, or with the ssh-agent if no key is given.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JumpHost {
+    pub host: String,
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub credential_id: Option<String>,
+    /// Overrides the username stored with the credential
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub ssh_key_path: Option<String>,
+}
+
+impl JumpHost {
+    fn credentials(&self) -> Result<ssh_auth::Credentials, String> {
+        if let Some(id) = &self.credential_id {
+            let mut credentials = ssh_auth::stored_credentials(id)?;
+            if let Some(username) = &self.username {
+                credentials.username = username.clone();
+            }
+            return Ok(credentials);
+        }
+
+        let username = self.username.clone()
+            .ok_or("No username or stored credential configured")?;
+        Ok(ssh_auth::Credentials {
+            username,
+            ssh_key_path: self.ssh_key_path.clone(),
+            auth_method: if self.ssh_key_path.is_some() { None } else { Some(ssh_auth::AuthMethod::Agent) },
+            ..Default::default()
+        })
+    }
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+/// Open a TCP connection to `target_host:target_port` through a chain of jump hosts.
+///
+/// Each hop is a full SSH session (host key check + auth) whose `direct-tcpip` channel
+/// is bridged onto a loopback socket, which in turn carries the next hop's session.
+/// The returned stream is ready to be handed to `Session::set_tcp_stream`.
+pub fn connect_via(
+    jump_hosts: &[JumpHost],
+    target_host: &str,
+    target_port: u16,
+    timeouts: &timeouts::ConnectionTimeouts,
+    prompts: ssh_auth::Prompts,
+) -> Result<TcpStream, String> {
+    let first = jump_hosts.first().ok_or("No jump hosts configured")?;
+    let mut stream = timeouts::connect_tcp(&first.host, first.port, timeouts.connect_timeout())
+        .map_err(|e| format!("Jump host: {}", e))?;
+
+    for (index, hop) in jump_hosts.iter().enumerate() {
+        let (next_host, next_port) = match jump_hosts.get(index + 1) {
+            Some(next) => (next.host.as_str(), next.port),
+            None => (target_host, target_port),
+        };
+
+        let sess = open_hop(hop, stream, timeouts, prompts)
+            .map_err(|e| format!("Jump host {}:{}: {}", hop.host, hop.port, e))?;
+
+        let channel = sess.channel_direct_tcpip(next_host, next_port, None)
+            .map_err(|e| format!(
+                "Jump host {}:{} could not open a tunnel to {}:{} - {}",
+                hop.host, hop.port, next_host, next_port, e
+            ))?;
+
+        let (local, bridged) = socket_pair()?;
+        spawn_bridge(sess, channel, bridged, Duration::from_secs(timeouts.keepalive_interval_secs));
+        stream = local;
+    }
+
+    Ok(stream)
+}
+
+fn open_hop(
+    hop: &JumpHost,
+    stream: TcpStream,
+    timeouts: &timeouts::ConnectionTimeouts,
+    prompts: ssh_auth::Prompts,
+) -> Result<Session, String> {
+    let credentials = hop.credentials()?;
+
+    let mut sess = Session::new()
+        .map_err(|e| format!("Failed to create SSH session: {}", e))?;
+    sess.set_tcp_stream(stream);
+    timeouts.start_auth(&sess);
+    sess.handshake()
+        .map_err(|e| format!("SSH handshake failed: {}", e))?;
+
+    known_hosts::verify_host_key(&sess, &hop.host, hop.port, prompts)?;
+    ssh_auth::authenticate(&sess, &credentials, prompts)?;
+
+    timeouts.finish_auth(&sess);
+    Ok(sess)
+}
+
+/// A connected pair of loopback sockets.
+///
+/// Any local process can connect to the listener before we do; only our own connection is
+/// accepted, or another process would be bridged into the authenticated tunnel.
+fn socket_pair() -> Result<(TcpStream, TcpStream), String> {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .map_err(|e| format!("Failed to bind tunnel socket: {}", e))?;
+    let addr = listener.local_addr()
+        .map_err(|e| format!("Failed to read tunnel socket address: {}", e))?;
+    let local = TcpStream::connect(addr)
+        .map_err(|e| format!("Failed to connect tunnel socket: {}", e))?;
+    let local_addr = local.local_addr()
+        .map_err(|e| format!("Failed to read tunnel socket address: {}", e))?;
+    for _ in 0..MAX_FOREIGN_CONNECTIONS {
+        let (accepted, peer) = listener.accept()
+            .map_err(|e| format!("Failed to accept tunnel socket: {}", e))?;
+        if peer == local_addr {
+            return Ok((local, accepted));
+        }
+    }
+    Err("Failed to accept tunnel socket: other local connections got in the way".to_string())
+}
+
+/// Run the hop's `direct-tcpip` channel against a loopback socket on its own thread.
+///
+/// The thread owns the hop's session, so the hop is torn down as soon as the next
+/// session (reading from the other end of the socket) goes away. A zero
+/// `keepalive_interval` disables keepalives on the hop.
+fn spawn_bridge(sess: Session, mut channel: Channel, mut socket: TcpStream, keepalive_interval: Duration) {
+    thread::spawn(move || {
+        sess.set_blocking(false);
+
+        let mut last_keepalive = Instant::now();
+        let _ = tunnel::pump(&mut channel, &mut socket, |_| {
+            if !keepalive_interval.is_zero() && last_keepalive.elapsed() >= keepalive_interval {
+                let _ = sess.keepalive_send();
+                last_keepalive = Instant::now();
+            }
+            true
+        });
+
+        let _ = channel.close();
+        let _ = sess.disconnect(None, "Tunnel closed", None);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_jump_host_port_defaults_to_22() {
+        let hop: JumpHost = serde_json::from_str(
+            r#"{"host":"bastion.example.com","credential_id":"bastion"}"#,
+        ).unwrap();
+        assert_eq!(hop.port, 22);
+        assert_eq!(hop.credential_id.as_deref(), Some("bastion"));
+        assert!(hop.username.is_none());
+    }
+
+    #[test]
+    fn test_jump_host_without_credential_uses_agent() {
+        let hop = JumpHost {
+            host: "bastion.example.com".to_string(),
+            port: 22,
+            credential_id: None,
+            username: Some("ops".to_string()),
+            ssh_key_path: None,
+        };
+        let credentials = hop.credentials().unwrap();
+        assert_eq!(credentials.username, "ops");
+        assert_eq!(credentials.auth_method, Some(ssh_auth::AuthMethod::Agent));
+    }
+
+    #[test]
+    fn test_socket_pair_is_connected() {
+        let (mut a, mut b) = socket_pair().unwrap();
+        assert_eq!(b.peer_addr().unwrap(), a.local_addr().unwrap());
+        a.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+}
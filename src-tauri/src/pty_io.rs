@@ -0,0 +1,756 @@
+// Event loop for one interactive SSH channel.
+//
+// The channel is owned by a single tokio task: output is read when the session's socket
+// becomes readable, input and resizes arrive through a bounded queue, so writers never
+// contend with the reader for a lock. This file only depends on std, tokio and serde so
+// the benchmark in benches/pty_io.rs can drive the same loop over a plain socket.
+//
+// Output is coalesced before it is emitted: the first chunk after a quiet period goes out
+// at once (keeps echo snappy), after that at most one event per OUTPUT_BATCH_WINDOW unless
+// MAX_OUTPUT_BATCH_BYTES pile up first. Once the frontend starts acking the bytes it has
+// rendered, reading pauses while too much output is unacknowledged, which leaves the rest
+// in the SSH window (or the PTY buffer) and slows the remote program down instead of the UI.
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Notify};
+
+/// Capacity of the per-session command queue; senders wait once it is full
+pub const COMMAND_QUEUE_CAPACITY: usize = 256;
+
+/// Stop taking new input while this much is still waiting for the channel
+const MAX_PENDING_WRITE_BYTES: usize = 1024 * 1024;
+
+// Other users of the session (SFTP, exec, forwards) may pull this channel's data off the
+// socket into libssh2's buffers without the socket ever becoming readable for us, so the
+// loop also wakes up on this interval. It also paces keepalive ticks.
+const IDLE_WAKEUP_INTERVAL: Duration = Duration::from_millis(50);
+
+const READ_BUFFER_SIZE: usize = 32768;
+
+/// At most one output event per window while output keeps coming (about one frame)
+pub const OUTPUT_BATCH_WINDOW: Duration = Duration::from_millis(16);
+
+/// Emit early once this much output has been coalesced
+pub const MAX_OUTPUT_BATCH_BYTES: usize = 64 * 1024;
+
+/// Stop reading once this many emitted bytes are unacknowledged...
+const FLOW_HIGH_WATERMARK: u64 = 1024 * 1024;
+
+/// ...and resume once the frontend has caught up to this
+const FLOW_LOW_WATERMARK: u64 = 256 * 1024;
+
+// A frontend that stops acking (a reloaded webview never sees the events in flight) must
+// not freeze the terminal for good: without ack progress for this long, reading resumes.
+const ACK_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often a paused blocking reader checks whether it may continue
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+pub enum IoCommand {
+    Write(Vec<u8>),
+    Resize { cols: u32, rows: u32 },
+    Close,
+}
+
+/// Why `drive` returned
+#[derive(Debug)]
+pub enum LoopExit {
+    /// `Close` was received or every sender was dropped
+    Closed,
+    /// The remote side closed the channel (e.g. the shell exited)
+    Eof,
+    Error(io::Error),
+}
+
+/// Snapshot of a session's output counters
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OutputStats {
+    /// Chunks read from the channel
+    pub reads: u64,
+    /// `pty-output` events emitted
+    pub events: u64,
+    /// Reads merged into an earlier read's event
+    pub coalesced_reads: u64,
+    /// Events the webview did not accept; their bytes are lost
+    pub dropped_events: u64,
+    pub bytes_emitted: u64,
+    pub bytes_acked: u64,
+    /// Whether the frontend acks, i.e. flow control is in effect
+    pub flow_control: bool,
+    pub paused: bool,
+    /// Times reading was paused for the frontend to catch up
+    pub pauses: u64,
+    /// Times reading resumed because acks stopped arriving
+    pub stalls: u64,
+}
+
+/// Flow control state and counters of one session's output, shared between its reader
+/// and the `pty_ack` / `pty_output_stats` commands.
+///
+/// Flow control starts with the first ack, so frontends that never ack are not throttled.
+#[derive(Default)]
+pub struct OutputFlow {
+    acking: AtomicBool,
+    paused: AtomicBool,
+    resume: Notify,
+    emitted: AtomicU64,
+    acked: AtomicU64,
+    reads: AtomicU64,
+    events: AtomicU64,
+    dropped_events: AtomicU64,
+    pauses: AtomicU64,
+    stalls: AtomicU64,
+    // Acked byte count when the current pause last saw progress, and when that was;
+    // None while output is flowing
+    stall_check: std::sync::Mutex<Option<(u64, Instant)>>,
+}
+
+impl OutputFlow {
+    /// The frontend has rendered `bytes` more bytes of output
+    pub fn ack(&self, bytes: u64) {
+        self.acking.store(true, Ordering::Relaxed);
+        let emitted = self.emitted.load(Ordering::Relaxed);
+        let _ = self.acked.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |acked| {
+            Some(acked.saturating_add(bytes).min(emitted))
+        });
+        if self.paused.load(Ordering::Relaxed) && self.unacked() <= FLOW_LOW_WATERMARK {
+            self.unpause();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn unacked(&self) -> u64 {
+        self.emitted.load(Ordering::Relaxed).saturating_sub(self.acked.load(Ordering::Relaxed))
+    }
+
+    pub fn stats(&self) -> OutputStats {
+        let reads = self.reads.load(Ordering::Relaxed);
+        let events = self.events.load(Ordering::Relaxed);
+        let dropped_events = self.dropped_events.load(Ordering::Relaxed);
+        OutputStats {
+            reads,
+            events,
+            coalesced_reads: reads.saturating_sub(events + dropped_events),
+            dropped_events,
+            bytes_emitted: self.emitted.load(Ordering::Relaxed),
+            bytes_acked: self.acked.load(Ordering::Relaxed),
+            flow_control: self.acking.load(Ordering::Relaxed),
+            paused: self.is_paused(),
+            pauses: self.pauses.load(Ordering::Relaxed),
+            stalls: self.stalls.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record_read(&self) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_event(&self, bytes: usize, delivered: bool) {
+        if !delivered {
+            self.dropped_events.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.events.fetch_add(1, Ordering::Relaxed);
+        self.emitted.fetch_add(bytes as u64, Ordering::Relaxed);
+        if self.acking.load(Ordering::Relaxed)
+            && self.unacked() >= FLOW_HIGH_WATERMARK
+            && !self.paused.swap(true, Ordering::Relaxed)
+        {
+            self.pauses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// While paused, give up on acks that stopped making progress a while ago
+    fn check_stall(&self, now: Instant) {
+        let mut check = self.stall_check.lock().unwrap_or_else(|e| e.into_inner());
+        if !self.is_paused() {
+            *check = None;
+            return;
+        }
+        let acked = self.acked.load(Ordering::Relaxed);
+        match *check {
+            Some((seen, since)) if seen == acked => {
+                if now.duration_since(since) >= ACK_STALL_TIMEOUT {
+                    *check = None;
+                    self.stalls.fetch_add(1, Ordering::Relaxed);
+                    self.acked.store(self.emitted.load(Ordering::Relaxed), Ordering::Relaxed);
+                    self.unpause();
+                }
+            }
+            _ => *check = Some((acked, now)),
+        }
+    }
+
+    fn unpause(&self) {
+        if self.paused.swap(false, Ordering::Relaxed) {
+            // Stores a permit if the reader is not waiting yet
+            self.resume.notify_one();
+        }
+    }
+}
+
+/// Output waiting to be emitted as one event
+#[derive(Default)]
+struct OutputBatch {
+    buffer: Vec<u8>,
+    last_flush: Option<Instant>,
+}
+
+impl OutputBatch {
+    fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    fn is_full(&self) -> bool {
+        self.buffer.len() >= MAX_OUTPUT_BATCH_BYTES
+    }
+
+    /// When the buffered output has to go out (`now` if it is overdue); None if there is none
+    fn deadline(&self, now: Instant) -> Option<Instant> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        match self.last_flush {
+            Some(at) if !self.is_full() => Some((at + OUTPUT_BATCH_WINDOW).max(now)),
+            _ => Some(now),
+        }
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.deadline(now).is_some_and(|deadline| deadline <= now)
+    }
+
+    fn flush(&mut self, now: Instant, flow: &OutputFlow, emit: &mut impl FnMut(&[u8]) -> bool) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let delivered = emit(&self.buffer);
+        flow.record_event(self.buffer.len(), delivered);
+        self.buffer.clear();
+        self.last_flush = Some(now);
+    }
+}
+
+/// The non-blocking channel the loop moves bytes through
+pub trait ChannelIo {
+    /// Must return WouldBlock instead of waiting
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    /// Must return WouldBlock instead of waiting
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
+    /// Must return WouldBlock instead of waiting; the loop then retries with the same size
+    fn resize(&mut self, cols: u32, rows: u32) -> io::Result<()>;
+    fn eof(&self) -> bool;
+    /// Periodic housekeeping such as keepalives; an error ends the loop. `inbound` says
+    /// whether anything arrived on the connection since the last tick, channel data or not:
+    /// libssh2 takes keepalive replies off the socket during `read` without returning them.
+    fn tick(&mut self, _inbound: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run the channel until it closes, fails or is told to stop.
+///
+/// `socket` must refer to the same connection the channel reads from (a clone of the
+/// session's TCP stream); it is only used for readiness. `emit` receives coalesced output
+/// and reports whether it was delivered.
+pub async fn drive(
+    io: &mut impl ChannelIo,
+    socket: &TcpStream,
+    commands: &mut mpsc::Receiver<IoCommand>,
+    flow: &OutputFlow,
+    mut emit: impl FnMut(&[u8]) -> bool,
+) -> LoopExit {
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    let mut pending: VecDeque<u8> = VecDeque::new();
+    // A size request the channel has started on has to be finished before the next one
+    let mut resizing: Option<(u32, u32)> = None;
+    let mut resize_wanted: Option<(u32, u32)> = None;
+    let mut batch = OutputBatch::default();
+    let mut inbound = false;
+
+    loop {
+        // Drain everything that is available, unless the frontend is too far behind
+        while !flow.is_paused() {
+            match io.read(&mut buffer) {
+                Ok(0) if io.eof() => {
+                    batch.flush(Instant::now(), flow, &mut emit);
+                    return LoopExit::Eof;
+                }
+                Ok(0) => break,
+                Ok(n) => {
+                    inbound = true;
+                    flow.record_read();
+                    batch.push(&buffer[..n]);
+                    if batch.is_full() {
+                        batch.flush(Instant::now(), flow, &mut emit);
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return LoopExit::Error(e),
+            }
+        }
+
+        let now = Instant::now();
+        if batch.is_due(now) {
+            batch.flush(now, flow, &mut emit);
+        }
+        flow.check_stall(now);
+
+        // Push out queued input until the channel stops taking it
+        while !pending.is_empty() {
+            let chunk = pending.make_contiguous();
+            match io.write(chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    pending.drain(..n);
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return LoopExit::Error(e),
+            }
+        }
+        while let Some((cols, rows)) = resizing.or_else(|| resize_wanted.take()) {
+            resizing = Some((cols, rows));
+            match io.resize(cols, rows) {
+                Ok(()) => resizing = None,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return LoopExit::Error(e),
+            }
+        }
+
+        if let Err(e) = io.tick(std::mem::take(&mut inbound)) {
+            return LoopExit::Error(e);
+        }
+
+        let paused = flow.is_paused();
+        let flushed = pending.is_empty() && resizing.is_none() && resize_wanted.is_none();
+        let interest = match (paused, flushed) {
+            (false, true) => Some(Interest::READABLE),
+            (false, false) => Some(Interest::READABLE | Interest::WRITABLE),
+            (true, false) => Some(Interest::WRITABLE),
+            (true, true) => None,
+        };
+        let wakeup = batch.deadline(now).map_or(now + IDLE_WAKEUP_INTERVAL, |deadline| deadline.min(now + IDLE_WAKEUP_INTERVAL));
+
+        tokio::select! {
+            command = commands.recv(), if pending.len() < MAX_PENDING_WRITE_BYTES => match command {
+                Some(IoCommand::Write(data)) => pending.extend(data),
+                // Only the latest size matters; it is sent along with the queued input
+                Some(IoCommand::Resize { cols, rows }) => resize_wanted = Some((cols, rows)),
+                Some(IoCommand::Close) | None => {
+                    batch.flush(Instant::now(), flow, &mut emit);
+                    return LoopExit::Closed;
+                }
+            },
+            ready = socket.ready(interest.unwrap_or(Interest::READABLE)), if interest.is_some() => {
+                match ready {
+                    Ok(ready) => inbound |= ready.is_readable(),
+                    Err(e) => return LoopExit::Error(e),
+                }
+                // libssh2 reads through its own handle, so tokio never sees WouldBlock on
+                // this one; clear the readiness by hand before draining at the top of the loop
+                let interest = interest.unwrap_or(Interest::READABLE);
+                let _ = socket.try_io(interest, || Err::<(), _>(ErrorKind::WouldBlock.into()));
+            },
+            _ = flow.resume.notified(), if paused => {},
+            _ = tokio::time::sleep_until(wakeup.into()) => {}
+        }
+    }
+}
+
+/// Coalesce and flow-control the output of a blocking reader (a local PTY) like `drive`
+/// does for SSH channels. Returns `Eof` when the reader is exhausted, `Closed` once
+/// `is_open` turns false.
+pub fn pump_blocking(
+    mut reader: impl Read + Send + 'static,
+    flow: Arc<OutputFlow>,
+    is_open: impl Fn() -> bool,
+    mut emit: impl FnMut(&[u8]) -> bool,
+) -> LoopExit {
+    // The reader blocks, so it gets a thread of its own and batching happens here
+    let (chunks, received) = std_mpsc::sync_channel::<io::Result<Vec<u8>>>(4);
+    let reader_flow = flow.clone();
+    thread::spawn(move || {
+        let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+        loop {
+            // Leaving output unread eventually blocks the program writing it
+            while reader_flow.is_paused() {
+                reader_flow.check_stall(Instant::now());
+                thread::sleep(PAUSE_POLL_INTERVAL);
+            }
+            let chunk = match reader.read(&mut buffer) {
+                Ok(0) => Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => Ok(buffer[..n].to_vec()),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(PAUSE_POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            if chunks.send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    let mut batch = OutputBatch::default();
+    loop {
+        let now = Instant::now();
+        let timeout = batch.deadline(now).map_or(IDLE_WAKEUP_INTERVAL, |deadline| {
+            deadline.saturating_duration_since(now).min(IDLE_WAKEUP_INTERVAL)
+        });
+        let exit = match received.recv_timeout(timeout) {
+            Ok(Ok(data)) => {
+                flow.record_read();
+                batch.push(&data);
+                None
+            }
+            Ok(Err(e)) if e.kind() == ErrorKind::UnexpectedEof => Some(LoopExit::Eof),
+            Ok(Err(e)) => Some(LoopExit::Error(e)),
+            Err(std_mpsc::RecvTimeoutError::Timeout) => None,
+            Err(std_mpsc::RecvTimeoutError::Disconnected) => Some(LoopExit::Eof),
+        };
+
+        if !is_open() {
+            return LoopExit::Closed;
+        }
+        let now = Instant::now();
+        if exit.is_some() || batch.is_due(now) {
+            batch.flush(now, &flow, &mut emit);
+        }
+        if let Some(exit) = exit {
+            return exit;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+
+    /// A loopback socket standing in for the SSH channel
+    struct SocketChannel {
+        stream: StdTcpStream,
+        eof: bool,
+        resized: Option<(u32, u32)>,
+        resize_attempts: Vec<(u32, u32)>,
+        // Resize requests left to answer with WouldBlock
+        busy_resizes: usize,
+    }
+
+    impl ChannelIo for SocketChannel {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.stream.read(buf)?;
+            self.eof = n == 0;
+            Ok(n)
+        }
+
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.stream.write(buf)
+        }
+
+        fn resize(&mut self, cols: u32, rows: u32) -> io::Result<()> {
+            self.resize_attempts.push((cols, rows));
+            if self.busy_resizes > 0 {
+                self.busy_resizes -= 1;
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.resized = Some((cols, rows));
+            Ok(())
+        }
+
+        fn eof(&self) -> bool {
+            self.eof
+        }
+    }
+
+    fn channel_pair() -> (SocketChannel, TcpStream, StdTcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = StdTcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.set_nonblocking(true).unwrap();
+        let readiness = TcpStream::from_std(client.try_clone().unwrap()).unwrap();
+        let channel = SocketChannel { stream: client, eof: false, resized: None, resize_attempts: Vec::new(), busy_resizes: 0 };
+        (channel, readiness, server)
+    }
+
+    #[tokio::test]
+    async fn test_output_input_and_close() {
+        let (mut channel, socket, mut server) = channel_pair();
+        let (tx, mut rx) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+
+        let server_thread = std::thread::spawn(move || {
+            server.write_all(b"login: ").unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"root\n");
+            server.write_all(b"# ").unwrap();
+            server
+        });
+
+        tx.send(IoCommand::Write(b"root\n".to_vec())).await.unwrap();
+        tx.send(IoCommand::Resize { cols: 120, rows: 40 }).await.unwrap();
+
+        let flow = OutputFlow::default();
+        let mut output = Vec::new();
+        let driver = async {
+            drive(&mut channel, &socket, &mut rx, &flow, |data| {
+                output.extend_from_slice(data);
+                true
+            }).await
+        };
+        let closer = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            tx.send(IoCommand::Close).await.unwrap();
+        };
+        let (exit, _) = tokio::join!(driver, closer);
+
+        assert!(matches!(exit, LoopExit::Closed));
+        assert_eq!(output, b"login: # ");
+        assert_eq!(channel.resized, Some((120, 40)));
+        drop(server_thread.join().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_blocked_resize_is_retried() {
+        let (mut channel, socket, _server) = channel_pair();
+        channel.busy_resizes = 2;
+        let (tx, mut rx) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+        tx.send(IoCommand::Resize { cols: 100, rows: 30 }).await.unwrap();
+        tx.send(IoCommand::Resize { cols: 120, rows: 40 }).await.unwrap();
+
+        let driver = async {
+            drive(&mut channel, &socket, &mut rx, &OutputFlow::default(), |_| true).await
+        };
+        let closer = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            tx.send(IoCommand::Close).await.unwrap();
+        };
+        let (exit, _) = tokio::join!(driver, closer);
+
+        assert!(matches!(exit, LoopExit::Closed));
+        // The blocked request is finished with its own size before the newer one goes out
+        assert_eq!(channel.resize_attempts, vec![(100, 30), (100, 30), (100, 30), (120, 40)]);
+        assert_eq!(channel.resized, Some((120, 40)));
+    }
+
+    /// Takes everything off the socket without returning channel data, like libssh2 does
+    /// with a keepalive reply
+    struct SwallowingChannel {
+        stream: StdTcpStream,
+        ticks: Vec<bool>,
+    }
+
+    impl ChannelIo for SwallowingChannel {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.stream.read(buf)? > 0 {}
+            Err(ErrorKind::WouldBlock.into())
+        }
+
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.stream.write(buf)
+        }
+
+        fn resize(&mut self, _cols: u32, _rows: u32) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn eof(&self) -> bool {
+            false
+        }
+
+        fn tick(&mut self, inbound: bool) -> io::Result<()> {
+            self.ticks.push(inbound);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tick_sees_replies_drained_by_read() {
+        let (channel, socket, mut server) = channel_pair();
+        let mut channel = SwallowingChannel { stream: channel.stream, ticks: Vec::new() };
+        let (tx, mut rx) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+
+        let driver = async {
+            drive(&mut channel, &socket, &mut rx, &OutputFlow::default(), |_| true).await
+        };
+        let replier = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            server.write_all(b"keepalive reply").unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            tx.send(IoCommand::Close).await.unwrap();
+        };
+        let (exit, _) = tokio::join!(driver, replier);
+
+        assert!(matches!(exit, LoopExit::Closed));
+        assert_eq!(channel.ticks.first(), Some(&false));
+        assert!(channel.ticks.contains(&true));
+    }
+
+    #[tokio::test]
+    async fn test_remote_close_is_eof() {
+        let (mut channel, socket, server) = channel_pair();
+        let (_tx, mut rx) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+        drop(server);
+
+        let exit = drive(&mut channel, &socket, &mut rx, &OutputFlow::default(), |_| true).await;
+        assert!(matches!(exit, LoopExit::Eof));
+    }
+
+    #[test]
+    fn test_batch_emits_first_chunk_then_coalesces() {
+        let flow = OutputFlow::default();
+        let mut events = Vec::new();
+        let mut emit = |data: &[u8]| {
+            events.push(data.to_vec());
+            true
+        };
+        let mut batch = OutputBatch::default();
+        let start = Instant::now();
+
+        // Nothing emitted recently: goes out at once
+        batch.push(b"$ ");
+        assert!(batch.is_due(start));
+        batch.flush(start, &flow, &mut emit);
+
+        // Right behind it: held back for the rest of the window
+        batch.push(b"a");
+        batch.push(b"b");
+        assert!(!batch.is_due(start + Duration::from_millis(5)));
+        assert!(batch.is_due(start + OUTPUT_BATCH_WINDOW));
+        batch.flush(start + OUTPUT_BATCH_WINDOW, &flow, &mut emit);
+
+        // Unless it is full
+        batch.push(&vec![b'x'; MAX_OUTPUT_BATCH_BYTES]);
+        assert!(batch.is_due(start + OUTPUT_BATCH_WINDOW));
+
+        assert_eq!(events, vec![b"$ ".to_vec(), b"ab".to_vec()]);
+        assert_eq!(flow.stats().events, 2);
+    }
+
+    #[test]
+    fn test_flow_control_starts_with_first_ack() {
+        let flow = OutputFlow::default();
+        flow.record_event(FLOW_HIGH_WATERMARK as usize * 2, true);
+        assert!(!flow.is_paused());
+
+        flow.ack(FLOW_HIGH_WATERMARK * 2);
+        flow.record_event(FLOW_HIGH_WATERMARK as usize, true);
+        assert!(flow.is_paused());
+        assert_eq!(flow.stats().pauses, 1);
+
+        // Still above the low watermark
+        flow.ack(FLOW_HIGH_WATERMARK - FLOW_LOW_WATERMARK - 1);
+        assert!(flow.is_paused());
+        flow.ack(1);
+        assert!(!flow.is_paused());
+
+        // Acks never run ahead of what was emitted
+        flow.ack(u64::MAX);
+        assert_eq!(flow.unacked(), 0);
+    }
+
+    #[test]
+    fn test_stalled_acks_resume_output() {
+        let flow = OutputFlow::default();
+        flow.ack(0);
+        flow.record_event(FLOW_HIGH_WATERMARK as usize, true);
+        let start = Instant::now();
+
+        flow.check_stall(start);
+        flow.check_stall(start + ACK_STALL_TIMEOUT / 2);
+        // Progress restarts the clock
+        flow.ack(1);
+        flow.check_stall(start + ACK_STALL_TIMEOUT);
+        assert!(flow.is_paused());
+
+        flow.check_stall(start + ACK_STALL_TIMEOUT * 2);
+        assert!(!flow.is_paused());
+        assert_eq!(flow.stats().stalls, 1);
+        assert_eq!(flow.unacked(), 0);
+    }
+
+    #[test]
+    fn test_dropped_events_are_counted() {
+        let flow = OutputFlow::default();
+        flow.record_read();
+        flow.record_read();
+        flow.record_event(10, false);
+        let stats = flow.stats();
+        assert_eq!(stats.dropped_events, 1);
+        assert_eq!(stats.events, 0);
+        assert_eq!(stats.bytes_emitted, 0);
+        assert_eq!(stats.coalesced_reads, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reading_pauses_until_acked() {
+        const TOTAL: usize = 3 * 1024 * 1024;
+        let (mut channel, socket, mut server) = channel_pair();
+        let (tx, mut rx) = mpsc::channel(COMMAND_QUEUE_CAPACITY);
+        let server_thread = std::thread::spawn(move || {
+            server.write_all(&vec![b'x'; TOTAL]).unwrap();
+            server
+        });
+
+        let flow = OutputFlow::default();
+        flow.ack(0);
+        let received = AtomicU64::new(0);
+        let driver = drive(&mut channel, &socket, &mut rx, &flow, |data| {
+            received.fetch_add(data.len() as u64, Ordering::Relaxed);
+            true
+        });
+        let frontend = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let stalled_at = received.load(Ordering::Relaxed);
+            assert!(flow.is_paused());
+            assert!(stalled_at >= FLOW_HIGH_WATERMARK);
+            assert!(stalled_at < FLOW_HIGH_WATERMARK + MAX_OUTPUT_BATCH_BYTES as u64);
+
+            // Render everything as it arrives
+            while received.load(Ordering::Relaxed) < TOTAL as u64 {
+                flow.ack(flow.unacked());
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            tx.send(IoCommand::Close).await.unwrap();
+        };
+        let (exit, _) = tokio::join!(driver, frontend);
+
+        assert!(matches!(exit, LoopExit::Closed));
+        assert_eq!(received.load(Ordering::Relaxed), TOTAL as u64);
+        assert!(flow.stats().pauses >= 1);
+        drop(server_thread.join().unwrap());
+    }
+
+    #[test]
+    fn test_pump_blocking_coalesces_reader_output() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let flow = Arc::new(OutputFlow::default());
+        let mut output = Vec::new();
+        let exit = pump_blocking(std::io::Cursor::new(data.clone()), flow.clone(), || true, |chunk| {
+            output.extend_from_slice(chunk);
+            true
+        });
+
+        assert!(matches!(exit, LoopExit::Eof));
+        assert_eq!(output, data);
+        let stats = flow.stats();
+        assert_eq!(stats.events + stats.coalesced_reads, stats.reads);
+        assert!(stats.events < stats.reads);
+    }
+}
@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Per-server auto-reconnect settings for SSH terminals (off unless the server opts in)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReconnectPolicy {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first attempt; doubled after every failure
+    #[serde(default = "default_initial_delay_ms")]
+    pub initial_delay_ms: u64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            enabled: false,
+            max_attempts: default_max_attempts(),
+            initial_delay_ms: default_initial_delay_ms(),
+            max_delay_ms: default_max_delay_ms(),
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_initial_delay_ms() -> u64 {
+    1000
+}
+
+fn default_max_delay_ms() -> u64 {
+    30000
+}
+
+impl ReconnectPolicy {
+    /// Backoff before the given attempt (1-based), or None once attempts are used up
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if !self.enabled || attempt == 0 || attempt > self.max_attempts {
+            return None;
+        }
+        let factor = 1u64.checked_shl(attempt - 1).unwrap_or(u64::MAX);
+        let delay = self.initial_delay_ms.saturating_mul(factor).min(self.max_delay_ms);
+        Some(Duration::from_millis(delay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disabled_by_default() {
+        let policy: ReconnectPolicy = serde_json::from_str("{}").unwrap();
+        assert!(!policy.enabled);
+        assert_eq!(policy.delay(1), None);
+    }
+
+    #[test]
+    fn test_exponential_backoff_is_capped() {
+        let policy = ReconnectPolicy {
+            enabled: true,
+            max_attempts: 8,
+            initial_delay_ms: 1000,
+            max_delay_ms: 10000,
+        };
+        let delays: Vec<u64> = (1..=8).map(|n| policy.delay(n).unwrap().as_millis() as u64).collect();
+        assert_eq!(delays, vec![1000, 2000, 4000, 8000, 10000, 10000, 10000, 10000]);
+        assert_eq!(policy.delay(9), None);
+    }
+
+    #[test]
+    fn test_large_attempt_numbers_do_not_overflow() {
+        let policy = ReconnectPolicy { enabled: true, max_attempts: 200, ..Default::default() };
+        assert_eq!(policy.delay(200), Some(Duration::from_millis(policy.max_delay_ms)));
+    }
+}
@@ -0,0 +1,450 @@
+use crate::terminal_encoding::{OutputEncoder, OutputFormat};
+use encoding_rs::Encoding;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::Window;
+
+// Longest a playback sleeps before checking whether it was stopped
+const PLAYBACK_STOP_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Playback speeds outside this range are clamped to it
+const MIN_PLAYBACK_SPEED: f64 = 0.1;
+const MAX_PLAYBACK_SPEED: f64 = 100.0;
+
+// Active recordings, keyed by the session they record
+static RECORDINGS: Lazy<Mutex<HashMap<String, Recorder>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Stop flags of running playbacks, keyed by playback id
+static PLAYBACKS: Lazy<Mutex<HashMap<String, Arc<AtomicBool>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RecordingOptions {
+    /// Where to write the .cast file; defaults to the app's recordings directory
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Also record keystrokes ("i" events); off by default since they may include passwords
+    #[serde(default)]
+    pub record_input: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingInfo {
+    pub session_id: String,
+    pub path: String,
+    /// Unix time the recording started
+    pub started_at: u64,
+    pub record_input: bool,
+    pub events: u64,
+    pub duration_secs: f64,
+    /// Set when writing failed; nothing was recorded after it
+    pub error: Option<String>,
+}
+
+/// First line of an asciicast v2 file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CastHeader {
+    pub version: u32,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<HashMap<String, String>>,
+}
+
+/// One event line: `[seconds since start, "o" | "i" | "r" | ..., data]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CastEvent(pub f64, pub String, pub String);
+
+struct Recorder {
+    writer: BufWriter<File>,
+    // Output arrives as raw bytes in the session's charset; casts are UTF-8
+    decoder: OutputEncoder,
+    started: Instant,
+    info: RecordingInfo,
+}
+
+impl Recorder {
+    fn create(path: &Path, header: &CastHeader, charset: &'static Encoding, info: RecordingInfo) -> Result<Self, String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+
+        let mut recorder = Recorder {
+            writer: BufWriter::new(file),
+            decoder: OutputEncoder::new(OutputFormat::Text, charset),
+            started: Instant::now(),
+            info,
+        };
+        let header = serde_json::to_string(header).map_err(|e| e.to_string())?;
+        recorder.write_line(&header);
+        match recorder.info.error.take() {
+            Some(e) => Err(e),
+            None => Ok(recorder),
+        }
+    }
+
+    fn event(&mut self, code: &str, data: &str) {
+        if data.is_empty() || self.info.error.is_some() {
+            return;
+        }
+        // Microsecond precision, like asciinema itself
+        let time = (self.started.elapsed().as_secs_f64() * 1e6).round() / 1e6;
+        match serde_json::to_string(&CastEvent(time, code.to_string(), data.to_string())) {
+            Ok(line) => {
+                self.write_line(&line);
+                self.info.events += 1;
+            }
+            Err(e) => self.info.error = Some(e.to_string()),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        // Flushed per line so a crash loses at most the event being written
+        let result = writeln!(self.writer, "{}", line).and_then(|_| self.writer.flush());
+        if let Err(e) = result {
+            self.info.error = Some(format!("Failed to write {}: {}", self.info.path, e));
+        }
+    }
+
+    fn finish(mut self) -> RecordingInfo {
+        let tail = self.decoder.finish();
+        self.event("o", &tail);
+        self.info.duration_secs = self.started.elapsed().as_secs_f64();
+        self.info
+    }
+}
+
+fn default_path(session_id: &str, started_at: u64) -> Result<PathBuf, String> {
+    let name: String = session_id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    Ok(crate::app_data_dir()?.join("recordings").join(format!("{}-{}.cast", name, started_at)))
+}
+
+/// Start recording a session whose terminal is currently `cols` x `rows`
+pub fn start(
+    session_id: &str,
+    options: &RecordingOptions,
+    (cols, rows): (u32, u32),
+    charset: &'static Encoding,
+) -> Result<RecordingInfo, String> {
+    let mut recordings = RECORDINGS.lock();
+    if recordings.contains_key(session_id) {
+        return Err(format!("Session {} is already being recorded", session_id));
+    }
+
+    let started_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let path = match &options.path {
+        Some(path) => PathBuf::from(path),
+        None => default_path(session_id, started_at)?,
+    };
+    let header = CastHeader {
+        version: 2,
+        width: cols,
+        height: rows,
+        timestamp: Some(started_at),
+        title: options.title.clone(),
+        env: Some(HashMap::from([("TERM".to_string(), "xterm-256color".to_string())])),
+    };
+    let info = RecordingInfo {
+        session_id: session_id.to_string(),
+        path: path.to_string_lossy().to_string(),
+        started_at,
+        record_input: options.record_input,
+        events: 0,
+        duration_secs: 0.0,
+        error: None,
+    };
+
+    let recorder = Recorder::create(&path, &header, charset, info.clone())?;
+    recordings.insert(session_id.to_string(), recorder);
+    Ok(info)
+}
+
+pub fn stop(session_id: &str) -> Result<RecordingInfo, String> {
+    RECORDINGS.lock().remove(session_id)
+        .map(Recorder::finish)
+        .ok_or_else(|| format!("Session {} is not being recorded", session_id))
+}
+
+pub fn status(session_id: &str) -> Option<RecordingInfo> {
+    RECORDINGS.lock().get(session_id).map(|recorder| {
+        let mut info = recorder.info.clone();
+        info.duration_secs = recorder.started.elapsed().as_secs_f64();
+        info
+    })
+}
+
+/// Output of a session, as read from its channel
+pub fn capture_output(session_id: &str, data: &[u8]) {
+    if let Some(recorder) = RECORDINGS.lock().get_mut(session_id) {
+        let text = recorder.decoder.encode(data);
+        recorder.event("o", &text);
+    }
+}
+
+/// Text typed or pasted into a session
+pub fn capture_input(session_id: &str, text: &str) {
+    if let Some(recorder) = RECORDINGS.lock().get_mut(session_id) {
+        if recorder.info.record_input {
+            recorder.event("i", text);
+        }
+    }
+}
+
+pub fn capture_resize(session_id: &str, cols: u32, rows: u32) {
+    if let Some(recorder) = RECORDINGS.lock().get_mut(session_id) {
+        recorder.event("r", &format!("{}x{}", cols, rows));
+    }
+}
+
+/// Read a cast file; lines that are not valid events are skipped
+pub fn load(path: &Path) -> Result<(CastHeader, Vec<CastEvent>), String> {
+    let file = File::open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let mut lines = BufReader::new(file).lines();
+
+    let header_line = lines.next()
+        .ok_or_else(|| format!("{} is empty", path.display()))?
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let header: CastHeader = serde_json::from_str(&header_line)
+        .map_err(|e| format!("{} is not an asciicast file: {}", path.display(), e))?;
+    if header.version != 2 {
+        return Err(format!("Unsupported asciicast version {}", header.version));
+    }
+
+    let mut events = Vec::new();
+    for line in lines {
+        let line = line.map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if let Ok(event) = serde_json::from_str::<CastEvent>(&line) {
+            events.push(event);
+        }
+    }
+    Ok((header, events))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaybackOptions {
+    pub path: String,
+    /// 2.0 plays twice as fast; clamped to 0.1..=100
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+    /// Shorten pauses longer than this (in recorded seconds)
+    #[serde(default)]
+    pub max_idle_secs: Option<f64>,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+/// When each event is due, relative to the start of playback
+fn schedule(events: &[CastEvent], speed: f64, max_idle_secs: Option<f64>) -> Result<Vec<Duration>, String> {
+    let speed = if speed.is_finite() && speed > 0.0 {
+        speed.clamp(MIN_PLAYBACK_SPEED, MAX_PLAYBACK_SPEED)
+    } else {
+        1.0
+    };
+    let mut previous = 0.0;
+    let mut elapsed = 0.0;
+    events.iter().map(|CastEvent(time, _, _)| {
+        let mut gap = (time - previous).max(0.0);
+        previous = previous.max(*time);
+        if let Some(max_idle) = max_idle_secs {
+            gap = gap.min(max_idle.max(0.0));
+        }
+        elapsed += gap / speed;
+        Duration::try_from_secs_f64(elapsed)
+            .map_err(|_| format!("Recording cannot be played back: event at {}s is out of range", time))
+    }).collect()
+}
+
+/// Re-emit a recording's output as `pty-output` events for `playback_id`, in the
+/// background. Resizes are emitted as `recording-playback-resize`, the end as
+/// `recording-playback-finished`. Returns the header so the caller can size the terminal.
+pub fn play(playback_id: &str, options: &PlaybackOptions, window: Window) -> Result<CastHeader, String> {
+    let (header, events) = load(Path::new(&options.path))?;
+    let due = schedule(&events, options.speed, options.max_idle_secs)?;
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    {
+        let mut playbacks = PLAYBACKS.lock();
+        if playbacks.contains_key(playback_id) {
+            return Err(format!("Playback {} is already running", playback_id));
+        }
+        playbacks.insert(playback_id.to_string(), stopped.clone());
+    }
+
+    let playback_id = playback_id.to_string();
+    thread::spawn(move || {
+        let started = Instant::now();
+        for (CastEvent(_, code, data), due) in events.iter().zip(due) {
+            // Sleep in steps so a stop request is noticed quickly
+            while !stopped.load(Ordering::Relaxed) && started.elapsed() < due {
+                thread::sleep((due - started.elapsed().min(due)).min(PLAYBACK_STOP_CHECK_INTERVAL));
+            }
+            if stopped.load(Ordering::Relaxed) {
+                break;
+            }
+
+            match code.as_str() {
+                "o" => {
+                    let _ = window.emit("pty-output", serde_json::json!({
+                        "session_id": playback_id,
+                        "data": data,
+                        "format": OutputFormat::Text.as_str(),
+                        "bytes": data.len()
+                    }));
+                }
+                "r" => {
+                    if let Some((cols, rows)) = data.split_once('x') {
+                        let _ = window.emit("recording-playback-resize", serde_json::json!({
+                            "session_id": playback_id,
+                            "cols": cols.parse::<u32>().unwrap_or(0),
+                            "rows": rows.parse::<u32>().unwrap_or(0)
+                        }));
+                    }
+                }
+                // Input and markers are not replayed
+                _ => {}
+            }
+        }
+
+        PLAYBACKS.lock().remove(&playback_id);
+        let _ = window.emit("recording-playback-finished", serde_json::json!({
+            "session_id": playback_id,
+            "stopped": stopped.load(Ordering::Relaxed)
+        }));
+    });
+
+    Ok(header)
+}
+
+pub fn stop_playback(playback_id: &str) -> Result<(), String> {
+    let stopped = PLAYBACKS.lock().get(playback_id).cloned()
+        .ok_or_else(|| format!("No playback with id {}", playback_id))?;
+    stopped.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_recording_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("nested").join("session.cast");
+        let options = RecordingOptions {
+            path: Some(path.to_string_lossy().to_string()),
+            title: Some("incident 42".to_string()),
+            record_input: true,
+        };
+
+        let info = start("rec-test-1", &options, (120, 40), UTF_8).unwrap();
+        assert!(start("rec-test-1", &options, (120, 40), UTF_8).is_err());
+        assert_eq!(info.path, path.to_string_lossy());
+
+        capture_output("rec-test-1", "$ 你".as_bytes());
+        capture_input("rec-test-1", "ls\r");
+        // A character split across two reads stays intact
+        capture_output("rec-test-1", &"好\r\n".as_bytes()[..1]);
+        capture_output("rec-test-1", &"好\r\n".as_bytes()[1..]);
+        capture_resize("rec-test-1", 100, 30);
+        capture_output("other-session", b"not recorded");
+
+        let info = stop("rec-test-1").unwrap();
+        assert_eq!(info.events, 4);
+        assert!(info.error.is_none());
+        assert!(stop("rec-test-1").is_err());
+
+        let (header, events) = load(&path).unwrap();
+        assert_eq!((header.version, header.width, header.height), (2, 120, 40));
+        assert_eq!(header.title.as_deref(), Some("incident 42"));
+
+        let kinds: Vec<(&str, &str)> = events.iter().map(|e| (e.1.as_str(), e.2.as_str())).collect();
+        assert_eq!(kinds, vec![("o", "$ 你"), ("i", "ls\r"), ("o", "好\r\n"), ("r", "100x30")]);
+        assert!(events.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    }
+
+    #[test]
+    fn test_input_is_not_recorded_by_default() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("quiet.cast");
+        let options = RecordingOptions { path: Some(path.to_string_lossy().to_string()), ..Default::default() };
+
+        start("rec-test-2", &options, (80, 24), UTF_8).unwrap();
+        capture_input("rec-test-2", "hunter2\r");
+        capture_output("rec-test-2", b"ok");
+        stop("rec-test-2").unwrap();
+
+        let (_, events) = load(&path).unwrap();
+        assert_eq!(events, vec![CastEvent(events[0].0, "o".to_string(), "ok".to_string())]);
+    }
+
+    #[test]
+    fn test_load_asciinema_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("demo.cast");
+        fs::write(&path, concat!(
+            "{\"version\": 2, \"width\": 80, \"height\": 24, \"timestamp\": 1504467315, \"env\": {\"SHELL\": \"/bin/zsh\"}}\n",
+            "[0.248848, \"o\", \"\\u001b[1;31mHello \\u001b[32mWorld!\\u001b[0m\\n\"]\n",
+            "[1.001376, \"o\", \"That was ok\\rThis is better.\"]\n",
+            "\n",
+        )).unwrap();
+
+        let (header, events) = load(&path).unwrap();
+        assert_eq!(header.width, 80);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].2, "\u{1b}[1;31mHello \u{1b}[32mWorld!\u{1b}[0m\n");
+
+        fs::write(&path, "{\"version\": 1, \"width\": 80, \"height\": 24}\n").unwrap();
+        assert_eq!(load(&path).unwrap_err(), "Unsupported asciicast version 1");
+    }
+
+    #[test]
+    fn test_schedule_speed_and_idle_limit() {
+        let events: Vec<CastEvent> = [0.5, 1.0, 11.0, 11.5]
+            .iter()
+            .map(|&t| CastEvent(t, "o".to_string(), "x".to_string()))
+            .collect();
+
+        let secs = |due: Result<Vec<Duration>, String>| due.unwrap().iter().map(|d| d.as_secs_f64()).collect::<Vec<_>>();
+
+        assert_eq!(secs(schedule(&events, 1.0, None)), vec![0.5, 1.0, 11.0, 11.5]);
+        assert_eq!(secs(schedule(&events, 2.0, None)), vec![0.25, 0.5, 5.5, 5.75]);
+        assert_eq!(secs(schedule(&events, 1.0, Some(2.0))), vec![0.5, 1.0, 3.0, 3.5]);
+        // Nonsense speeds fall back to real time, extreme ones are clamped
+        assert_eq!(secs(schedule(&events, 0.0, None)), vec![0.5, 1.0, 11.0, 11.5]);
+        assert_eq!(secs(schedule(&events, 1e-300, None)), vec![5.0, 10.0, 110.0, 115.0]);
+    }
+
+    #[test]
+    fn test_schedule_rejects_out_of_range_times() {
+        for time in [1e300, f64::INFINITY] {
+            let events = vec![CastEvent(time, "o".to_string(), "x".to_string())];
+            assert!(schedule(&events, 1.0, None).unwrap_err().starts_with("Recording cannot be played back"));
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use crate::terminal_encoding::{OutputEncoder, OutputFormat};
+use encoding_rs::Encoding;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+// Screen model of every open session, keyed by session id
+static SCREENS: Lazy<Mutex<HashMap<String, TerminalScreen>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// What the terminal shows right now, rebuilt from the session's output by a VT parser
+/// independently of the frontend. History beyond the visible rows is in `scrollback`.
+struct TerminalScreen {
+    parser: vt100::Parser<ScreenCallbacks>,
+    decoder: OutputEncoder,
+}
+
+impl TerminalScreen {
+    fn new((cols, rows): (u32, u32), charset: &'static Encoding) -> Self {
+        TerminalScreen {
+            parser: vt100::Parser::new_with_callbacks(clamp_size(rows), clamp_size(cols), 0, ScreenCallbacks::default()),
+            decoder: OutputEncoder::new(OutputFormat::Text, charset),
+        }
+    }
+
+    // The parser only understands UTF-8, so other charsets are converted first
+    fn push_output(&mut self, data: &[u8]) {
+        let text = self.decoder.encode(data);
+        self.parser.process(text.as_bytes());
+    }
+
+    fn resize(&mut self, cols: u32, rows: u32) {
+        self.parser.screen_mut().set_size(clamp_size(rows), clamp_size(cols));
+    }
+
+    fn snapshot(&self, session_id: &str, include_cells: bool) -> ScreenSnapshot {
+        let screen = self.parser.screen();
+        let (rows, cols) = screen.size();
+        let (cursor_row, cursor_col) = screen.cursor_position();
+        ScreenSnapshot {
+            session_id: session_id.to_string(),
+            cols,
+            rows,
+            cursor: CursorInfo { row: cursor_row, col: cursor_col, visible: !screen.hide_cursor() },
+            alternate_screen: screen.alternate_screen(),
+            title: self.parser.callbacks().title.clone(),
+            text: screen.contents(),
+            cells: include_cells.then(|| (0..rows).map(|row| cell_runs(screen, row, cols)).collect()),
+        }
+    }
+}
+
+fn clamp_size(n: u32) -> u16 {
+    n.clamp(1, u16::MAX as u32) as u16
+}
+
+// Command lines not picked up yet; a session that nobody asks kept from growing
+const MAX_PENDING_COMMAND_LINES: usize = 16;
+
+#[derive(Default)]
+struct ScreenCallbacks {
+    title: String,
+    // Where the shell said command input starts (OSC 133;B)
+    input_start: Option<InputStart>,
+    // Command line read off the screen at every OSC 133;C, oldest first; empty when
+    // there was no OSC 133;B to start from
+    command_lines: VecDeque<String>,
+}
+
+struct InputStart {
+    row: u16,
+    col: u16,
+    // Text before `col` on that row, to find the row again once it has scrolled
+    prompt: String,
+}
+
+impl InputStart {
+    fn at_cursor(screen: &vt100::Screen) -> Self {
+        let (row, col) = screen.cursor_position();
+        InputStart { row, col, prompt: screen.contents_between(row, 0, row, col) }
+    }
+
+    // Everything typed from the input start to the cursor, as the screen shows it after
+    // line editing (history recall, backspace and the like)
+    fn command_line(&self, screen: &vt100::Screen) -> String {
+        let (cursor_row, cursor_col) = screen.cursor_position();
+        // Enter scrolls the screen when the prompt is on the last row
+        let row = (0..=self.row.min(cursor_row)).rev()
+            .find(|&row| screen.contents_between(row, 0, row, self.col) == self.prompt);
+        match row {
+            Some(row) => screen.contents_between(row, self.col, cursor_row, cursor_col).trim().to_string(),
+            None => String::new(),
+        }
+    }
+}
+
+impl vt100::Callbacks for ScreenCallbacks {
+    fn set_window_title(&mut self, _: &mut vt100::Screen, title: &[u8]) {
+        self.title = String::from_utf8_lossy(title).to_string();
+    }
+
+    fn unhandled_osc(&mut self, screen: &mut vt100::Screen, params: &[&[u8]]) {
+        match params {
+            // The parser splits OSC on ';', so a title containing one ends up here
+            [b"0" | b"2", title @ ..] => {
+                self.title = String::from_utf8_lossy(&title.join(&b';')).to_string();
+            }
+            [b"133", b"B", ..] => self.input_start = Some(InputStart::at_cursor(screen)),
+            [b"133", b"C", ..] => {
+                let line = self.input_start.take()
+                    .map(|start| start.command_line(screen))
+                    .unwrap_or_default();
+                if self.command_lines.len() == MAX_PENDING_COMMAND_LINES {
+                    self.command_lines.pop_front();
+                }
+                self.command_lines.push_back(line);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CursorInfo {
+    pub row: u16,
+    pub col: u16,
+    pub visible: bool,
+}
+
+/// A terminal color other than the default: a palette index (0-255) or "#rrggbb"
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CellColor {
+    Indexed(u8),
+    Rgb(String),
+}
+
+impl CellColor {
+    fn from_vt(color: vt100::Color) -> Option<Self> {
+        match color {
+            vt100::Color::Default => None,
+            vt100::Color::Idx(index) => Some(CellColor::Indexed(index)),
+            vt100::Color::Rgb(r, g, b) => Some(CellColor::Rgb(format!("#{:02x}{:02x}{:02x}", r, g, b))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CellAttributes {
+    /// None is the terminal's default color
+    pub fg: Option<CellColor>,
+    pub bg: Option<CellColor>,
+    pub bold: bool,
+    pub dim: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub inverse: bool,
+}
+
+impl CellAttributes {
+    fn of(cell: &vt100::Cell) -> Self {
+        CellAttributes {
+            fg: CellColor::from_vt(cell.fgcolor()),
+            bg: CellColor::from_vt(cell.bgcolor()),
+            bold: cell.bold(),
+            dim: cell.dim(),
+            italic: cell.italic(),
+            underline: cell.underline(),
+            inverse: cell.inverse(),
+        }
+    }
+}
+
+/// Neighbouring cells of a row that share the same attributes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CellRun {
+    pub col: u16,
+    /// Columns covered; more than `text` has characters when it holds wide characters
+    pub width: u16,
+    /// Empty cells are spaces
+    pub text: String,
+    #[serde(flatten)]
+    pub attributes: CellAttributes,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScreenSnapshot {
+    pub session_id: String,
+    pub cols: u16,
+    pub rows: u16,
+    pub cursor: CursorInfo,
+    /// Full-screen programs (vim, less, top) draw on the alternate screen
+    pub alternate_screen: bool,
+    /// Last title set with OSC 0 or OSC 2
+    pub title: String,
+    /// Visible rows as plain text; a wrapped line is not split
+    pub text: String,
+    /// Every visible row as runs of cells, when asked for
+    pub cells: Option<Vec<Vec<CellRun>>>,
+}
+
+fn cell_runs(screen: &vt100::Screen, row: u16, cols: u16) -> Vec<CellRun> {
+    let mut runs: Vec<CellRun> = Vec::new();
+    for col in 0..cols {
+        let Some(cell) = screen.cell(row, col) else { continue };
+        // The right half of a wide character belongs to the run holding its left half
+        if cell.is_wide_continuation() {
+            if let Some(run) = runs.last_mut() {
+                run.width += 1;
+                continue;
+            }
+        }
+        let attributes = CellAttributes::of(cell);
+        let text = if cell.has_contents() { cell.contents() } else { " " };
+        match runs.last_mut() {
+            Some(run) if run.attributes == attributes => {
+                run.width += 1;
+                run.text.push_str(text);
+            }
+            _ => runs.push(CellRun { col, width: 1, text: text.to_string(), attributes }),
+        }
+    }
+    runs
+}
+
+pub fn open(session_id: &str, size: (u32, u32), charset: &'static Encoding) {
+    SCREENS.lock().insert(session_id.to_string(), TerminalScreen::new(size, charset));
+}
+
+pub fn close(session_id: &str) {
+    SCREENS.lock().remove(session_id);
+}
+
+pub fn capture_output(session_id: &str, data: &[u8]) {
+    if let Some(screen) = SCREENS.lock().get_mut(session_id) {
+        screen.push_output(data);
+    }
+}
+
+pub fn resize(session_id: &str, cols: u32, rows: u32) {
+    if let Some(screen) = SCREENS.lock().get_mut(session_id) {
+        screen.resize(cols, rows);
+    }
+}
+
+/// The command line of the oldest OSC 133;C not asked about yet, as it was on screen
+/// when the command started. Some("") when it could not be read.
+pub fn take_command_line(session_id: &str) -> Option<String> {
+    SCREENS.lock().get_mut(session_id)?.parser.callbacks_mut().command_lines.pop_front()
+}
+
+pub fn snapshot(session_id: &str, include_cells: bool) -> Result<ScreenSnapshot, String> {
+    SCREENS.lock().get(session_id)
+        .map(|screen| screen.snapshot(session_id, include_cells))
+        .ok_or_else(|| format!("No screen for session {}", session_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+
+    fn screen_with(size: (u32, u32), output: &[&[u8]]) -> TerminalScreen {
+        let mut screen = TerminalScreen::new(size, UTF_8);
+        for chunk in output {
+            screen.push_output(chunk);
+        }
+        screen
+    }
+
+    #[test]
+    fn test_text_and_cursor() {
+        let screen = screen_with((20, 5), &[b"$ ls\r\nREADME  src\r\n$ ", b"\x1b[2;9Hx"]);
+        let snapshot = screen.snapshot("s", false);
+        assert_eq!(snapshot.text, "$ ls\nREADME  xrc\n$ ");
+        assert_eq!((snapshot.cursor.row, snapshot.cursor.col, snapshot.cursor.visible), (1, 9, true));
+        assert!(snapshot.cells.is_none());
+
+        let hidden = screen_with((20, 5), &[b"\x1b[?25l"]).snapshot("s", false);
+        assert!(!hidden.cursor.visible);
+    }
+
+    #[test]
+    fn test_alternate_screen_restores_main() {
+        let mut screen = screen_with((20, 5), &[b"$ vim\r\n"]);
+        screen.push_output(b"\x1b[?1049h\x1b[H~ editing");
+        let snapshot = screen.snapshot("s", false);
+        assert!(snapshot.alternate_screen);
+        assert_eq!(snapshot.text, "~ editing");
+
+        screen.push_output(b"\x1b[?1049l");
+        let snapshot = screen.snapshot("s", false);
+        assert!(!snapshot.alternate_screen);
+        assert_eq!(snapshot.text, "$ vim");
+    }
+
+    #[test]
+    fn test_title() {
+        let mut screen = screen_with((20, 5), &[b"\x1b]0;root@web1: ~\x07"]);
+        assert_eq!(screen.snapshot("s", false).title, "root@web1: ~");
+        screen.push_output(b"\x1b]2;make; make install\x1b\\");
+        assert_eq!(screen.snapshot("s", false).title, "make; make install");
+    }
+
+    #[test]
+    fn test_command_line_after_editing() {
+        // Typed "ls", recalled "git status" from history over it, then Enter
+        let screen = screen_with((40, 5), &[
+            b"\x1b]133;A\x07user@web1:~$ \x1b]133;B\x07",
+            b"ls\x08\x08git status\x1b[K\r\n\x1b]133;C\x07",
+        ]);
+        assert_eq!(screen.parser.callbacks().command_lines, vec!["git status"]);
+    }
+
+    #[test]
+    fn test_command_line_after_scrolling() {
+        let mut screen = screen_with((20, 3), &[b"a\r\nb\r\n$ \x1b]133;B\x07"]);
+        // The prompt is on the last row; Enter scrolls it up one
+        screen.push_output(b"echo 1 2 3 4 5 6 7 8 9\r\n\x1b]133;C\x07");
+        screen.push_output(b"1 2 3 4 5 6 7 8 9\r\n\x1b]133;C\x07");
+        let lines: Vec<&String> = screen.parser.callbacks().command_lines.iter().collect();
+        // Wrapped across two rows; the second C had no B before it
+        assert_eq!(lines, vec!["echo 1 2 3 4 5 6 7 8 9", ""]);
+    }
+
+    #[test]
+    fn test_cell_runs() {
+        let screen = screen_with((10, 2), &[b"\x1b[1;31mERR\x1b[0m ok \x1b[38;2;255;128;0m\xe4\xbd\xa0"]);
+        let cells = screen.snapshot("s", true).cells.unwrap();
+        assert_eq!(cells.len(), 2);
+        let row: Vec<(u16, u16, &str)> = cells[0].iter().map(|run| (run.col, run.width, run.text.as_str())).collect();
+        assert_eq!(row, vec![(0, 3, "ERR"), (3, 4, " ok "), (7, 2, "你"), (9, 1, " ")]);
+        assert_eq!(cells[0][0].attributes.fg, Some(CellColor::Indexed(1)));
+        assert!(cells[0][0].attributes.bold && !cells[0][1].attributes.bold);
+        assert_eq!(cells[0][2].attributes.fg, Some(CellColor::Rgb("#ff8000".to_string())));
+        assert_eq!(cells[1].len(), 1);
+
+        let json = serde_json::to_value(&cells[0][2]).unwrap();
+        assert_eq!(json["fg"], "#ff8000");
+        assert_eq!(json["bg"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_registry_resize_and_charset() {
+        open("screen-test-1", (80, 24), encoding_rs::WINDOWS_1252);
+        capture_output("screen-test-1", b"caf\xe9");
+        resize("screen-test-1", 100, 30);
+        let shown = snapshot("screen-test-1", false).unwrap();
+        assert_eq!((shown.cols, shown.rows), (100, 30));
+        assert_eq!(shown.text, "café");
+        close("screen-test-1");
+        assert!(snapshot("screen-test-1", false).is_err());
+    }
+}
@@ -0,0 +1,421 @@
+use crate::terminal_encoding::{AnsiStripper, OutputEncoder, OutputFormat};
+use encoding_rs::Encoding;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+/// Lines kept per session; older lines are dropped
+pub const MAX_LINES: usize = 10_000;
+
+/// Text kept per session, whatever the number of lines; older lines are dropped
+pub const MAX_BYTES: usize = 4 * 1024 * 1024;
+
+/// Output without a newline for this long is cut into a line of its own, so a binary
+/// dump cannot grow the current line without bound
+pub const MAX_LINE_BYTES: usize = 16 * 1024;
+
+const DEFAULT_RANGE_LINES: usize = 1000;
+const DEFAULT_MAX_MATCHES: usize = 500;
+
+// Scrollback of every open session, keyed by session id
+static BUFFERS: Lazy<Mutex<HashMap<String, Scrollback>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A session's output as plain text lines (escape sequences removed). Lines are numbered
+/// from the start of the session, so numbers stay valid while old lines are dropped.
+struct Scrollback {
+    // Shared so a search can take a copy of the lines without copying the text
+    lines: VecDeque<Arc<str>>,
+    /// Number of `lines[0]`
+    first_line: u64,
+    /// Total length of `lines`
+    bytes: usize,
+    /// The line the cursor is on, not yet ended by a newline
+    current: String,
+    // A \r was the last character seen; whether it overwrites depends on what follows
+    pending_cr: bool,
+    max_lines: usize,
+    max_bytes: usize,
+    decoder: OutputEncoder,
+    stripper: AnsiStripper,
+}
+
+impl Scrollback {
+    fn new(charset: &'static Encoding, max_lines: usize, max_bytes: usize) -> Self {
+        Scrollback {
+            lines: VecDeque::new(),
+            first_line: 0,
+            bytes: 0,
+            current: String::new(),
+            pending_cr: false,
+            max_lines: max_lines.max(1),
+            max_bytes,
+            decoder: OutputEncoder::new(OutputFormat::Text, charset),
+            stripper: AnsiStripper::default(),
+        }
+    }
+
+    fn push_output(&mut self, data: &[u8]) -> Vec<OscMark> {
+        let text = self.decoder.encode(data);
+        let (plain, osc) = self.stripper.strip_with_osc(&text);
+        let mut osc = osc.into_iter().peekable();
+        let mut marks = Vec::new();
+        for (offset, c) in plain.char_indices() {
+            while let Some((_, payload)) = osc.next_if(|(at, _)| *at <= offset) {
+                marks.push(self.mark(payload));
+            }
+            if self.pending_cr {
+                self.pending_cr = false;
+                // A lone \r returns to the start of the line, e.g. for progress bars; the
+                // redrawn line replaces the old one (close enough for plain-text history)
+                if c != '\n' {
+                    self.current.clear();
+                }
+            }
+            match c {
+                '\n' => self.end_line(),
+                '\r' => self.pending_cr = true,
+                c => {
+                    self.current.push(c);
+                    if self.current.len() >= MAX_LINE_BYTES {
+                        self.end_line();
+                    }
+                }
+            }
+        }
+        marks.extend(osc.map(|(_, payload)| self.mark(payload)));
+        marks
+    }
+
+    fn mark(&self, payload: String) -> OscMark {
+        OscMark {
+            line: self.end_line_number(),
+            column: self.current.chars().count(),
+            payload,
+        }
+    }
+
+    fn end_line(&mut self) {
+        let line: Arc<str> = std::mem::take(&mut self.current).into();
+        self.bytes += line.len();
+        self.lines.push_back(line);
+        // The newest line is always kept, even if it alone is over `max_bytes`
+        while self.lines.len() > self.max_lines || (self.bytes > self.max_bytes && self.lines.len() > 1) {
+            if let Some(dropped) = self.lines.pop_front() {
+                self.bytes -= dropped.len();
+                self.first_line += 1;
+            }
+        }
+    }
+
+    /// Number the next completed line will get
+    fn end_line_number(&self) -> u64 {
+        self.first_line + self.lines.len() as u64
+    }
+
+    fn range(&self, start: Option<u64>, count: usize) -> (u64, Vec<String>) {
+        let end = self.end_line_number();
+        let start = start
+            .unwrap_or_else(|| end.saturating_sub(count as u64))
+            .clamp(self.first_line, end);
+        let lines = self.lines.iter()
+            .skip((start - self.first_line) as usize)
+            .take(count)
+            .map(|line| line.to_string())
+            .collect();
+        (start, lines)
+    }
+
+    /// Completed lines and the current one, with their numbers
+    fn numbered_lines(&self) -> Vec<(u64, Arc<str>)> {
+        let current = (!self.current.is_empty()).then(|| (self.end_line_number(), Arc::from(self.current.as_str())));
+        (self.first_line..)
+            .zip(self.lines.iter().cloned())
+            .chain(current)
+            .collect()
+    }
+}
+
+/// An OSC string found in the output, and where in the scrollback it was
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OscMark {
+    /// Number of the line being written when it arrived
+    pub line: u64,
+    /// Characters already on that line
+    pub column: usize,
+    /// Everything between ESC ] and the terminator, e.g. "133;D;0"
+    pub payload: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrollbackRange {
+    pub session_id: String,
+    /// Oldest line still kept
+    pub first_line: u64,
+    /// One past the newest completed line
+    pub end_line: u64,
+    /// Number of `lines[0]`
+    pub start: u64,
+    pub lines: Vec<String>,
+    /// The line being written (e.g. the prompt), not part of `lines` yet
+    pub current: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchRequest {
+    pub pattern: String,
+    /// Search only this session; all sessions when unset
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub case_insensitive: bool,
+    #[serde(default)]
+    pub max_results: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchMatch {
+    pub session_id: String,
+    pub line_number: u64,
+    pub line: String,
+    /// Offsets in UTF-16 code units, so `line.slice(start, end)` in JavaScript is the match
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub matches: Vec<SearchMatch>,
+    /// More matches were found than `max_results`
+    pub truncated: bool,
+}
+
+pub fn open(session_id: &str, charset: &'static Encoding) {
+    BUFFERS.lock().insert(session_id.to_string(), Scrollback::new(charset, MAX_LINES, MAX_BYTES));
+}
+
+pub fn close(session_id: &str) {
+    BUFFERS.lock().remove(session_id);
+}
+
+/// Returns the OSC strings in `data`, placed in the scrollback
+pub fn capture_output(session_id: &str, data: &[u8]) -> Vec<OscMark> {
+    match BUFFERS.lock().get_mut(session_id) {
+        Some(buffer) => buffer.push_output(data),
+        None => Vec::new(),
+    }
+}
+
+/// Forget everything up to now, like `clear` in the frontend
+pub fn clear(session_id: &str) -> Result<(), String> {
+    let mut buffers = BUFFERS.lock();
+    let buffer = buffers.get_mut(session_id)
+        .ok_or_else(|| format!("No scrollback for session {}", session_id))?;
+    buffer.first_line = buffer.end_line_number();
+    buffer.lines.clear();
+    buffer.bytes = 0;
+    buffer.current.clear();
+    Ok(())
+}
+
+/// Up to `count` lines starting at line `start`, or the last `count` lines
+pub fn range(session_id: &str, start: Option<u64>, count: Option<usize>) -> Result<ScrollbackRange, String> {
+    let buffers = BUFFERS.lock();
+    let buffer = buffers.get(session_id)
+        .ok_or_else(|| format!("No scrollback for session {}", session_id))?;
+    let (start, lines) = buffer.range(start, count.unwrap_or(DEFAULT_RANGE_LINES));
+    Ok(ScrollbackRange {
+        session_id: session_id.to_string(),
+        first_line: buffer.first_line,
+        end_line: buffer.end_line_number(),
+        start,
+        lines,
+        current: buffer.current.clone(),
+    })
+}
+
+pub fn search(request: &SearchRequest) -> Result<SearchResult, String> {
+    let regex = RegexBuilder::new(&request.pattern)
+        .case_insensitive(request.case_insensitive)
+        .build()
+        .map_err(|e| format!("Invalid pattern: {}", e))?;
+    let max_results = request.max_results.unwrap_or(DEFAULT_MAX_MATCHES);
+
+    let mut session_ids = match &request.session_id {
+        Some(session_id) => vec![session_id.clone()],
+        None => BUFFERS.lock().keys().cloned().collect(),
+    };
+    session_ids.sort();
+
+    let mut result = SearchResult { matches: Vec::new(), truncated: false };
+    for session_id in &session_ids {
+        // One session at a time, and only the lines are copied, so output keeps flowing
+        // while the pattern runs
+        let lines = match BUFFERS.lock().get(session_id) {
+            Some(buffer) => buffer.numbered_lines(),
+            None if request.session_id.is_some() => return Err(format!("No scrollback for session {}", session_id)),
+            // Closed since the list was taken
+            None => continue,
+        };
+        if search_lines(&lines, session_id, &regex, max_results, &mut result) {
+            result.truncated = true;
+            break;
+        }
+    }
+    Ok(result)
+}
+
+// Returns true once more than `max_results` matches were found
+fn search_lines(lines: &[(u64, Arc<str>)], session_id: &str, regex: &Regex, max_results: usize, result: &mut SearchResult) -> bool {
+    for (line_number, line) in lines {
+        for found in regex.find_iter(line) {
+            if result.matches.len() == max_results {
+                return true;
+            }
+            let start = line[..found.start()].encode_utf16().count();
+            result.matches.push(SearchMatch {
+                session_id: session_id.to_string(),
+                line_number: *line_number,
+                line: line.to_string(),
+                start,
+                end: start + found.as_str().encode_utf16().count(),
+            });
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+
+    fn buffer_with(output: &[&[u8]], max_lines: usize) -> Scrollback {
+        let mut buffer = Scrollback::new(UTF_8, max_lines, MAX_BYTES);
+        for chunk in output {
+            buffer.push_output(chunk);
+        }
+        buffer
+    }
+
+    fn lines(buffer: &Scrollback) -> Vec<&str> {
+        buffer.lines.iter().map(|line| &**line).collect()
+    }
+
+    #[test]
+    fn test_lines_are_plain_text() {
+        let buffer = buffer_with(&[b"\x1b[1muser@web1\x1b[0m$ ls\r", b"\nREADME\r\n\x1b[32m$ \x1b[0m"], 100);
+        assert_eq!(lines(&buffer), vec!["user@web1$ ls", "README"]);
+        assert_eq!(buffer.current, "$ ");
+    }
+
+    #[test]
+    fn test_carriage_return_overwrites_line() {
+        let buffer = buffer_with(&[b"10%\r50%\r", b"100%\r\ndone\n"], 100);
+        assert_eq!(lines(&buffer), vec!["100%", "done"]);
+    }
+
+    #[test]
+    fn test_ring_buffer_keeps_numbering() {
+        let output: Vec<u8> = (0..25).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+        let buffer = buffer_with(&[&output], 10);
+        assert_eq!(buffer.first_line, 15);
+        assert_eq!(buffer.end_line_number(), 25);
+
+        let (start, lines) = buffer.range(Some(20), 3);
+        assert_eq!((start, lines), (20, vec!["line 20".to_string(), "line 21".to_string(), "line 22".to_string()]));
+        // Lines that were dropped are skipped, not an error
+        assert_eq!(buffer.range(Some(3), 2).0, 15);
+        // Without a start: the newest lines
+        assert_eq!(buffer.range(None, 2).1, vec!["line 23", "line 24"]);
+    }
+
+    #[test]
+    fn test_osc_marks_are_placed() {
+        let mut buffer = buffer_with(&[b"boot\n"], 100);
+        let marks = buffer.push_output(b"\x1b]133;A\x07$ \x1b]133;B\x07ls\r\n\x1b]133;C\x07a\nb\n\x1b]133;D;0\x07");
+        let placed: Vec<(u64, usize, &str)> = marks.iter().map(|m| (m.line, m.column, m.payload.as_str())).collect();
+        assert_eq!(placed, vec![(1, 0, "133;A"), (1, 2, "133;B"), (2, 0, "133;C"), (4, 0, "133;D;0")]);
+        assert_eq!(lines(&buffer), vec!["boot", "$ ls", "a", "b"]);
+    }
+
+    #[test]
+    fn test_long_lines_are_cut() {
+        let buffer = buffer_with(&[&vec![b'x'; MAX_LINE_BYTES * 2 + 5]], 100);
+        assert_eq!(buffer.lines.len(), 2);
+        assert_eq!(buffer.current.len(), 5);
+    }
+
+    #[test]
+    fn test_ring_buffer_is_bounded_by_bytes() {
+        let mut buffer = Scrollback::new(UTF_8, 100, 20);
+        buffer.push_output(b"aaaaaaaaa
+bbbbbbbbb
+ccccccccc
+");
+        assert_eq!(lines(&buffer), vec!["bbbbbbbbb", "ccccccccc"]);
+        assert_eq!((buffer.first_line, buffer.bytes), (1, 18));
+
+        // A line over the limit on its own replaces everything else
+        buffer.push_output(&[b'x'; 30]);
+        buffer.push_output(b"
+");
+        assert_eq!((buffer.lines.len(), buffer.first_line, buffer.bytes), (1, 3, 30));
+    }
+
+    #[test]
+    fn test_search_across_sessions() {
+        open("sb-test-a", UTF_8);
+        open("sb-test-b", UTF_8);
+        capture_output("sb-test-a", b"ok\nERROR: disk full\n");
+        capture_output("sb-test-b", "ünïcode Error here\n$ tail error.log".as_bytes());
+
+        let request = SearchRequest {
+            pattern: r"error\b".to_string(),
+            session_id: None,
+            case_insensitive: true,
+            max_results: None,
+        };
+        let found: Vec<(String, u64, usize, usize)> = search(&request).unwrap().matches.into_iter()
+            .filter(|m| m.session_id.starts_with("sb-test-"))
+            .map(|m| (m.session_id, m.line_number, m.start, m.end))
+            .collect();
+        assert_eq!(found, vec![
+            ("sb-test-a".to_string(), 1, 0, 5),
+            ("sb-test-b".to_string(), 0, 8, 13),
+            // The unfinished current line is searched too
+            ("sb-test-b".to_string(), 1, 7, 12),
+        ]);
+
+        let only_a = SearchRequest { session_id: Some("sb-test-a".to_string()), max_results: Some(0), ..request };
+        let result = search(&only_a).unwrap();
+        assert!(result.matches.is_empty() && result.truncated);
+
+        assert!(search(&SearchRequest { pattern: "(".to_string(), ..only_a }).unwrap_err().starts_with("Invalid pattern"));
+        close("sb-test-a");
+        close("sb-test-b");
+        assert!(range("sb-test-a", None, None).is_err());
+    }
+
+    #[test]
+    fn test_utf16_offsets() {
+        open("sb-test-c", UTF_8);
+        capture_output("sb-test-c", "🦀 crab\n".as_bytes());
+        let request = SearchRequest {
+            pattern: "crab".to_string(),
+            session_id: Some("sb-test-c".to_string()),
+            case_insensitive: false,
+            max_results: None,
+        };
+        let matches = search(&request).unwrap().matches;
+        // The emoji is two UTF-16 code units
+        assert_eq!((matches[0].start, matches[0].end), (3, 7));
+
+        clear("sb-test-c").unwrap();
+        assert!(search(&request).unwrap().matches.is_empty());
+        assert_eq!(range("sb-test-c", None, None).unwrap().first_line, 1);
+        close("sb-test-c");
+    }
+}
@@ -5,7 +5,7 @@ use aes_gcm::{
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use argon2::password_hash::{SaltString, rand_core::RngCore};
 use base64::{Engine as _, engine::general_purpose};
-use rusqlite::{Connection, Result as SqliteResult};
+use rusqlite::{Connection, OptionalExtension, Result as SqliteResult};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -49,6 +49,26 @@ impl SecureDatabase {
             [],
         )?;
 
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS automation_scripts (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                steps TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS macros (
+                name TEXT PRIMARY KEY,
+                source TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
         Ok(SecureDatabase {
             conn,
             encryption_key: None,
@@ -264,6 +284,113 @@ impl SecureDatabase {
         Ok(())
     }
 
+    /// Read a plain (unencrypted) app setting from the config table
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>, String> {
+        self.conn.query_row(
+            "SELECT value FROM config WHERE key = ?1",
+            [key],
+            |row| row.get(0),
+        ).optional().map_err(|e| format!("Failed to read setting {}: {}", key, e))
+    }
+
+    /// Store a plain (unencrypted) app setting in the config table
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<(), String> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO config (key, value) VALUES (?1, ?2)",
+            [key, value],
+        ).map_err(|e| format!("Failed to store setting {}: {}", key, e))?;
+        Ok(())
+    }
+
+    /// Store an automation script; `steps` is its JSON
+    pub fn store_script(&self, id: &str, name: &str, steps: &str) -> Result<(), String> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        self.conn.execute(
+            "INSERT INTO automation_scripts (id, name, steps, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(id) DO UPDATE SET
+                name = excluded.name, steps = excluded.steps, updated_at = excluded.updated_at",
+            (id, name, steps, now, now),
+        ).map_err(|e| format!("Failed to store script: {}", e))?;
+        Ok(())
+    }
+
+    pub fn get_script(&self, id: &str) -> Result<StoredScript, String> {
+        self.conn.query_row(
+            "SELECT id, name, steps, updated_at FROM automation_scripts WHERE id = ?1",
+            [id],
+            StoredScript::from_row,
+        ).optional()
+            .map_err(|e| format!("Failed to read script: {}", e))?
+            .ok_or_else(|| format!("Script {} not found", id))
+    }
+
+    /// All scripts, by name
+    pub fn list_scripts(&self) -> Result<Vec<StoredScript>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, name, steps, updated_at FROM automation_scripts ORDER BY name, id"
+        ).map_err(|e| format!("Failed to prepare query: {}", e))?;
+        let scripts = stmt.query_map([], StoredScript::from_row)
+            .and_then(|rows| rows.collect::<SqliteResult<Vec<_>>>())
+            .map_err(|e| format!("Failed to list scripts: {}", e))?;
+        Ok(scripts)
+    }
+
+    pub fn delete_script(&self, id: &str) -> Result<(), String> {
+        self.conn.execute(
+            "DELETE FROM automation_scripts WHERE id = ?1",
+            [id],
+        ).map_err(|e| format!("Failed to delete script: {}", e))?;
+        Ok(())
+    }
+
+    /// Store a macro's source, replacing the macro with the same name
+    pub fn store_macro(&self, name: &str, source: &str) -> Result<(), String> {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        self.conn.execute(
+            "INSERT OR REPLACE INTO macros (name, source, updated_at) VALUES (?1, ?2, ?3)",
+            (name, source, now),
+        ).map_err(|e| format!("Failed to store macro: {}", e))?;
+        Ok(())
+    }
+
+    pub fn get_macro(&self, name: &str) -> Result<StoredMacro, String> {
+        self.conn.query_row(
+            "SELECT name, source, updated_at FROM macros WHERE name = ?1",
+            [name],
+            StoredMacro::from_row,
+        ).optional()
+            .map_err(|e| format!("Failed to read macro: {}", e))?
+            .ok_or_else(|| format!("Macro {} not found", name))
+    }
+
+    /// All macros, by name
+    pub fn list_macros(&self) -> Result<Vec<StoredMacro>, String> {
+        let mut stmt = self.conn.prepare(
+            "SELECT name, source, updated_at FROM macros ORDER BY name"
+        ).map_err(|e| format!("Failed to prepare query: {}", e))?;
+        let macros = stmt.query_map([], StoredMacro::from_row)
+            .and_then(|rows| rows.collect::<SqliteResult<Vec<_>>>())
+            .map_err(|e| format!("Failed to list macros: {}", e))?;
+        Ok(macros)
+    }
+
+    pub fn delete_macro(&self, name: &str) -> Result<(), String> {
+        self.conn.execute(
+            "DELETE FROM macros WHERE name = ?1",
+            [name],
+        ).map_err(|e| format!("Failed to delete macro: {}", e))?;
+        Ok(())
+    }
+
     /// Check if database is unlocked
     pub fn is_unlocked(&self) -> bool {
         self.encryption_key.is_some()
@@ -279,6 +406,44 @@ pub struct StoredCredential {
     pub passphrase_encrypted: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredScript {
+    pub id: String,
+    pub name: String,
+    /// JSON array of steps
+    pub steps: String,
+    pub updated_at: i64,
+}
+
+impl StoredScript {
+    fn from_row(row: &rusqlite::Row) -> SqliteResult<Self> {
+        Ok(StoredScript {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            steps: row.get(2)?,
+            updated_at: row.get(3)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredMacro {
+    pub name: String,
+    /// Rhai source
+    pub source: String,
+    pub updated_at: i64,
+}
+
+impl StoredMacro {
+    fn from_row(row: &rusqlite::Row) -> SqliteResult<Self> {
+        Ok(StoredMacro {
+            name: row.get(0)?,
+            source: row.get(1)?,
+            updated_at: row.get(2)?,
+        })
+    }
+}
+
 // Global database functions
 pub fn init_database(db_path: PathBuf) -> Result<(), String> {
     let db = SecureDatabase::init(db_path)
@@ -412,6 +577,60 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_settings_round_trip() {
+        let (_temp_dir, db) = create_test_db();
+        assert_eq!(db.get_setting("theme").unwrap(), None);
+        db.set_setting("theme", "dark").unwrap();
+        db.set_setting("theme", "light").unwrap();
+        assert_eq!(db.get_setting("theme").unwrap().as_deref(), Some("light"));
+        // Settings are readable without unlocking
+        assert!(!db.is_unlocked());
+    }
+
+    #[test]
+    fn test_scripts_round_trip() {
+        let (_temp_dir, db) = create_test_db();
+        db.store_script("s2", "sudo", "[]").unwrap();
+        db.store_script("s1", "deploy", "[{\"type\":\"send\",\"text\":\"ls\\r\"}]").unwrap();
+        db.store_script("s2", "become root", "[]").unwrap();
+
+        let names: Vec<String> = db.list_scripts().unwrap().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["become root", "deploy"]);
+        assert!(db.get_script("s1").unwrap().steps.contains("ls\\r"));
+
+        db.delete_script("s1").unwrap();
+        assert_eq!(db.get_script("s1").unwrap_err(), "Script s1 not found");
+    }
+
+    #[test]
+    fn test_macros_round_trip() {
+        let (_temp_dir, db) = create_test_db();
+        db.store_macro("uptime-all", "log(1)").unwrap();
+        db.store_macro("uptime-all", "log(2)").unwrap();
+        db.store_macro("backup", "").unwrap();
+
+        let names: Vec<String> = db.list_macros().unwrap().into_iter().map(|m| m.name).collect();
+        assert_eq!(names, vec!["backup", "uptime-all"]);
+        assert_eq!(db.get_macro("uptime-all").unwrap().source, "log(2)");
+        db.delete_macro("backup").unwrap();
+        assert!(db.get_macro("backup").is_err());
+    }
+
+    #[test]
+    fn test_updating_script_keeps_created_at() {
+        let (_temp_dir, db) = create_test_db();
+        db.store_script("deploy", "Deploy", "[]").unwrap();
+        db.conn.execute("UPDATE automation_scripts SET created_at = 1 WHERE id = 'deploy'", []).unwrap();
+
+        db.store_script("deploy", "Deploy v2", r#"[{"type": "send", "text": "x"}]"#).unwrap();
+        let created_at: i64 = db.conn.query_row(
+            "SELECT created_at FROM automation_scripts WHERE id = 'deploy'", [], |row| row.get(0),
+        ).unwrap();
+        assert_eq!(created_at, 1);
+        assert_eq!(db.get_script("deploy").unwrap().name, "Deploy v2");
+    }
+
     #[test]
     fn test_derive_key_deterministic() {
         let password = "test_password";
@@ -0,0 +1,515 @@
+use crate::terminal_encoding::{AnsiStripper, OutputEncoder, OutputFormat};
+use encoding_rs::Encoding;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Key of the settings in the database's config table
+pub const SETTINGS_KEY: &str = "session_log_settings";
+
+static SETTINGS: Lazy<Mutex<LogSettings>> = Lazy::new(|| Mutex::new(LogSettings::default()));
+
+// Open logs, keyed by session id
+static LOGS: Lazy<Mutex<HashMap<String, SessionLog>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Sessions to the same host started within one second that still get a file of their own
+const MAX_NAME_ATTEMPTS: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Escape sequences and control characters removed
+    #[default]
+    Plain,
+    /// Output exactly as the terminal received it (decoded to UTF-8)
+    Raw,
+}
+
+/// Global logging settings; a server can override `enabled` for its own sessions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Defaults to "logs" next to the database
+    #[serde(default)]
+    pub directory: Option<String>,
+    #[serde(default)]
+    pub format: LogFormat,
+    /// Prefix every line with the UTC time its first character arrived
+    #[serde(default)]
+    pub timestamps: bool,
+    /// Rotate once a file reaches this size; 0 disables rotation
+    #[serde(default = "default_max_file_bytes")]
+    pub max_file_bytes: u64,
+    /// Rotated files kept per session (name.1.log is the newest)
+    #[serde(default = "default_max_rotated_files")]
+    pub max_rotated_files: u32,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            enabled: false,
+            directory: None,
+            format: LogFormat::default(),
+            timestamps: false,
+            max_file_bytes: default_max_file_bytes(),
+            max_rotated_files: default_max_rotated_files(),
+        }
+    }
+}
+
+fn default_max_file_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_max_rotated_files() -> u32 {
+    5
+}
+
+pub fn settings() -> LogSettings {
+    SETTINGS.lock().clone()
+}
+
+/// Applies to sessions started from now on
+pub fn set_settings(settings: LogSettings) {
+    *SETTINGS.lock() = settings;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogInfo {
+    pub session_id: String,
+    /// File currently written to
+    pub path: String,
+    pub bytes_written: u64,
+    /// Set when writing failed; nothing was logged after it
+    pub error: Option<String>,
+}
+
+struct SessionLog {
+    path: PathBuf,
+    decoder: OutputEncoder,
+    stripper: Option<AnsiStripper>,
+    timestamps: bool,
+    at_line_start: bool,
+    // Text on its way to the writer thread, which owns the file
+    lines: mpsc::Sender<String>,
+    writer: thread::JoinHandle<()>,
+    state: Arc<LogState>,
+}
+
+// What the writer thread reports back
+#[derive(Default)]
+struct LogState {
+    bytes_written: AtomicU64,
+    error: Mutex<Option<String>>,
+}
+
+impl SessionLog {
+    fn write_output(&mut self, data: &[u8]) {
+        let text = self.decoder.encode(data);
+        self.write_text(&text);
+    }
+
+    fn write_text(&mut self, text: &str) {
+        if self.state.error.lock().is_some() {
+            return;
+        }
+        let text = match &mut self.stripper {
+            Some(stripper) => {
+                let mut plain = stripper.strip(text);
+                plain.retain(|c| c != '\r');
+                plain
+            }
+            None => text.to_string(),
+        };
+        if text.is_empty() {
+            return;
+        }
+
+        let mut line = String::with_capacity(text.len() + 32);
+        let stamp = self.timestamps.then(|| format!("[{}] ", format_utc(unix_now(), true)));
+        for piece in text.split_inclusive('\n') {
+            if self.at_line_start {
+                if let Some(stamp) = &stamp {
+                    line.push_str(stamp);
+                }
+            }
+            line.push_str(piece);
+            self.at_line_start = piece.ends_with('\n');
+        }
+        // Only fails once the writer has stopped, and it records why
+        let _ = self.lines.send(line);
+    }
+
+    fn info(&self, session_id: &str) -> LogInfo {
+        self.state.info(session_id, &self.path)
+    }
+
+    /// Wait for everything sent so far to reach the file
+    fn close(self, session_id: &str) -> LogInfo {
+        let SessionLog { path, lines, writer, state, .. } = self;
+        drop(lines);
+        let _ = writer.join();
+        state.info(session_id, &path)
+    }
+}
+
+impl LogState {
+    fn info(&self, session_id: &str, path: &Path) -> LogInfo {
+        LogInfo {
+            session_id: session_id.to_string(),
+            path: path.to_string_lossy().to_string(),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            error: self.error.lock().clone(),
+        }
+    }
+}
+
+// The file side of a log, owned by its writer thread so output capture never waits on disk
+struct LogFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    // Size of the current file
+    size: u64,
+    // Snapshot taken when the session started
+    settings: LogSettings,
+    state: Arc<LogState>,
+}
+
+impl LogFile {
+    fn write(&mut self, text: &str) -> Result<(), String> {
+        self.writer.write_all(text.as_bytes())
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        self.size += text.len() as u64;
+        self.state.bytes_written.fetch_add(text.len() as u64, Ordering::Relaxed);
+
+        if self.settings.max_file_bytes > 0 && self.size >= self.settings.max_file_bytes {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        self.writer.flush()
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))
+    }
+
+    /// name.log becomes name.1.log, name.1.log becomes name.2.log and so on; the oldest
+    /// beyond `max_rotated_files` is deleted
+    fn rotate(&mut self) -> Result<(), String> {
+        self.flush()?;
+        let keep = self.settings.max_rotated_files;
+        let oldest = rotated_path(&self.path, keep);
+        if oldest != self.path {
+            let _ = fs::remove_file(&oldest);
+        }
+        for n in (1..keep).rev() {
+            let from = rotated_path(&self.path, n);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, n + 1))
+                    .map_err(|e| format!("Failed to rotate {}: {}", from.display(), e))?;
+            }
+        }
+        if keep > 0 {
+            fs::rename(&self.path, rotated_path(&self.path, 1))
+                .map_err(|e| format!("Failed to rotate {}: {}", self.path.display(), e))?;
+        } else {
+            fs::remove_file(&self.path)
+                .map_err(|e| format!("Failed to rotate {}: {}", self.path.display(), e))?;
+        }
+
+        self.writer = BufWriter::new(open_append(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+
+    // Flushed whenever the queue runs dry: a log that loses its tail in a crash is no use
+    // for an audit. Stops at the first error, which `info` reports from then on.
+    fn run(mut self, lines: mpsc::Receiver<String>) {
+        while let Ok(line) = lines.recv() {
+            let mut result = self.write(&line);
+            while result.is_ok() {
+                match lines.try_recv() {
+                    Ok(line) => result = self.write(&line),
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = result.and_then(|_| self.flush()) {
+                *self.state.error.lock() = Some(e);
+                return;
+            }
+        }
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, String> {
+    OpenOptions::new().create(true).append(true).open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))
+}
+
+// Create a log file no other session uses: tabs to the same host opened in the same second
+// get "-2", "-3", ... appended
+fn create_log_file(directory: &Path, user: &str, host: &str, unix_secs: u64) -> Result<(PathBuf, File), String> {
+    fs::create_dir_all(directory)
+        .map_err(|e| format!("Failed to create {}: {}", directory.display(), e))?;
+    for n in 1..=MAX_NAME_ATTEMPTS {
+        let path = directory.join(log_file_name(user, host, unix_secs, n));
+        // A rotated file means the name was taken by a session that is still writing
+        if rotated_path(&path, 1).exists() {
+            continue;
+        }
+        match OpenOptions::new().append(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("Failed to open {}: {}", path.display(), e)),
+        }
+    }
+    Err(format!("No free log file name for {}@{} in {}", user, host, directory.display()))
+}
+
+/// name.log -> name.N.log; N = 0 is the file itself
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!("{}.{}.log", stem, n))
+}
+
+/// "<user>@<host>_<YYYY-MM-DD>_<HHMMSS>.log" (UTC), with unsafe characters replaced;
+/// "..._<HHMMSS>-<n>.log" for the n-th log started in the same second
+fn log_file_name(user: &str, host: &str, unix_secs: u64, n: u32) -> String {
+    let clean = |s: &str| -> String {
+        s.chars()
+            .map(|c| if c.is_alphanumeric() || matches!(c, '.' | '-' | '_') { c } else { '_' })
+            .collect()
+    };
+    let (year, month, day, hour, minute, second) = utc_parts(unix_secs);
+    let suffix = if n > 1 { format!("-{}", n) } else { String::new() };
+    format!(
+        "{}@{}_{:04}-{:02}-{:02}_{:02}{:02}{:02}{}.log",
+        clean(user), clean(host), year, month, day, hour, minute, second, suffix
+    )
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// (year, month, day, hour, minute, second) in UTC
+fn utc_parts(unix_secs: u64) -> (i64, u32, u32, u32, u32, u32) {
+    // Days to civil date, after Howard Hinnant's days_from_civil inverse
+    let days = (unix_secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    let secs = unix_secs % 86400;
+    (year, month, day, (secs / 3600) as u32, (secs / 60 % 60) as u32, (secs % 60) as u32)
+}
+
+/// "2026-10-16 18:56:58Z", or just the date
+fn format_utc(unix_secs: u64, with_time: bool) -> String {
+    let (year, month, day, hour, minute, second) = utc_parts(unix_secs);
+    if with_time {
+        format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+    } else {
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+/// Start logging a session if `server_setting` (or, when unset, the global setting) says
+/// so. Returns None when logging is off for this session.
+pub fn start(
+    session_id: &str,
+    user: &str,
+    host: &str,
+    server_setting: Option<bool>,
+    settings: &LogSettings,
+    charset: &'static Encoding,
+) -> Result<Option<LogInfo>, String> {
+    if !server_setting.unwrap_or(settings.enabled) {
+        return Ok(None);
+    }
+
+    let directory = match &settings.directory {
+        Some(directory) if !directory.trim().is_empty() => PathBuf::from(directory),
+        _ => crate::app_data_dir()?.join("logs"),
+    };
+    let started = unix_now();
+    let (path, file) = create_log_file(&directory, user, host, started)?;
+    let state = Arc::new(LogState::default());
+    let mut file = LogFile {
+        path: path.clone(),
+        writer: BufWriter::new(file),
+        size: 0,
+        settings: settings.clone(),
+        state: state.clone(),
+    };
+
+    // Written here so a log that cannot be written fails the start; bypasses stripping and
+    // timestamps, goes through rotation like everything else
+    let banner = format!("# Session {} {}@{} started {}\n", session_id, user, host, format_utc(started, true));
+    file.write(&banner).and_then(|_| file.flush())?;
+
+    let (lines, received) = mpsc::channel();
+    let log = SessionLog {
+        path,
+        decoder: OutputEncoder::new(OutputFormat::Text, charset),
+        stripper: (settings.format == LogFormat::Plain).then(AnsiStripper::default),
+        timestamps: settings.timestamps,
+        at_line_start: true,
+        lines,
+        writer: thread::spawn(move || file.run(received)),
+        state,
+    };
+    let info = log.info(session_id);
+    LOGS.lock().insert(session_id.to_string(), log);
+    Ok(Some(info))
+}
+
+pub fn capture_output(session_id: &str, data: &[u8]) {
+    if let Some(log) = LOGS.lock().get_mut(session_id) {
+        log.write_output(data);
+    }
+}
+
+/// Returns once everything logged has been written
+pub fn stop(session_id: &str) -> Option<LogInfo> {
+    let mut log = LOGS.lock().remove(session_id)?;
+    let tail = log.decoder.finish();
+    log.write_text(&tail);
+    Some(log.close(session_id))
+}
+
+pub fn status(session_id: &str) -> Option<LogInfo> {
+    LOGS.lock().get(session_id).map(|log| log.info(session_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+    use tempfile::TempDir;
+
+    fn settings_in(dir: &TempDir) -> LogSettings {
+        LogSettings {
+            enabled: true,
+            directory: Some(dir.path().to_string_lossy().to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn read(path: &str) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn test_utc_formatting() {
+        assert_eq!(format_utc(0, true), "1970-01-01 00:00:00Z");
+        assert_eq!(format_utc(1_504_467_315, true), "2017-09-03 19:35:15Z");
+        assert_eq!(format_utc(951_782_400, false), "2000-02-29");
+        assert_eq!(log_file_name("root", "db-1.example.com", 1_504_467_315, 1), "root@db-1.example.com_2017-09-03_193515.log");
+        assert_eq!(log_file_name("a b", "fe80::1", 0, 1), "a_b@fe80__1_1970-01-01_000000.log");
+        assert_eq!(log_file_name("root", "web1", 0, 2), "root@web1_1970-01-01_000000-2.log");
+    }
+
+    #[test]
+    fn test_sessions_started_together_get_their_own_files() {
+        let dir = TempDir::new().unwrap();
+        let settings = settings_in(&dir);
+        let first = start("log-test-5", "root", "web1", None, &settings, UTF_8).unwrap().unwrap();
+        let second = start("log-test-6", "root", "web1", None, &settings, UTF_8).unwrap().unwrap();
+        assert_ne!(first.path, second.path);
+
+        capture_output("log-test-5", b"from five\n");
+        capture_output("log-test-6", b"from six\n");
+        stop("log-test-5");
+        stop("log-test-6");
+        assert!(read(&first.path).ends_with("from five\n"));
+        assert!(!read(&first.path).contains("six"));
+        assert!(read(&second.path).starts_with("# Session log-test-6 "));
+    }
+
+    #[test]
+    fn test_server_setting_overrides_global() {
+        let dir = TempDir::new().unwrap();
+        let mut settings = settings_in(&dir);
+        assert!(start("log-test-1", "root", "web1", Some(false), &settings, UTF_8).unwrap().is_none());
+
+        settings.enabled = false;
+        assert!(start("log-test-1", "root", "web1", None, &settings, UTF_8).unwrap().is_none());
+        let info = start("log-test-1", "root", "web1", Some(true), &settings, UTF_8).unwrap().unwrap();
+        assert!(status("log-test-1").is_some());
+        stop("log-test-1");
+        assert!(read(&info.path).starts_with("# Session log-test-1 root@web1 started "));
+    }
+
+    #[test]
+    fn test_plain_log_with_timestamps() {
+        let dir = TempDir::new().unwrap();
+        let settings = LogSettings { timestamps: true, ..settings_in(&dir) };
+        let info = start("log-test-2", "root", "web1", None, &settings, UTF_8).unwrap().unwrap();
+
+        capture_output("log-test-2", b"\x1b[32m$\x1b[0m echo hi\r\nhi\r\n$ \xe4");
+        capture_output("log-test-2", "\u{4f60}好\r\n".as_bytes().split_at(1).1);
+        let info_after = stop("log-test-2").unwrap();
+        assert!(info_after.error.is_none());
+
+        let log = read(&info.path);
+        let lines: Vec<&str> = log.lines().skip(1).collect();
+        assert_eq!(lines.len(), 3);
+        // "[YYYY-MM-DD HH:MM:SSZ] " is 23 characters
+        assert!(lines.iter().all(|line| line.starts_with('[') && &line[21..23] == "] "));
+        assert_eq!(lines.iter().map(|line| &line[23..]).collect::<Vec<_>>(), vec!["$ echo hi", "hi", "$ 你好"]);
+    }
+
+    #[test]
+    fn test_raw_log_keeps_escape_sequences() {
+        let dir = TempDir::new().unwrap();
+        let settings = LogSettings { format: LogFormat::Raw, ..settings_in(&dir) };
+        let info = start("log-test-3", "root", "web1", None, &settings, UTF_8).unwrap().unwrap();
+        capture_output("log-test-3", b"\x1b[31mred\x1b[0m\r\n");
+        stop("log-test-3");
+        assert!(read(&info.path).ends_with("\x1b[31mred\x1b[0m\r\n"));
+    }
+
+    #[test]
+    fn test_size_based_rotation() {
+        let dir = TempDir::new().unwrap();
+        let settings = LogSettings { max_file_bytes: 100, max_rotated_files: 2, ..settings_in(&dir) };
+        start("log-test-4", "root", "web1", None, &settings, UTF_8).unwrap().unwrap();
+        for i in 0..10 {
+            capture_output("log-test-4", format!("{:059}\n", i).as_bytes());
+        }
+        let info = stop("log-test-4").unwrap();
+        assert!(info.error.is_none());
+
+        let path = PathBuf::from(&info.path);
+        let mut names: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        let stem = path.file_stem().unwrap().to_string_lossy().to_string();
+        assert_eq!(names, vec![format!("{}.1.log", stem), format!("{}.2.log", stem), format!("{}.log", stem)]);
+
+        // Newest lines in the current file, the two before it in .1 and .2
+        assert_eq!(read(&info.path), format!("{:059}\n", 9));
+        assert_eq!(read(rotated_path(&path, 1).to_str().unwrap()), format!("{:059}\n{:059}\n", 7, 8));
+        assert_eq!(read(rotated_path(&path, 2).to_str().unwrap()), format!("{:059}\n{:059}\n", 5, 6));
+    }
+}
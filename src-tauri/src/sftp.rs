@@ -0,0 +1,311 @@
+use crate::tunnel;
+use base64::{Engine as _, engine::general_purpose};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use ssh2::{FileStat, FileType, OpenFlags, OpenType, Session, Sftp};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Upper bound for `read_file`, which returns the whole file in one IPC message
+pub const DEFAULT_MAX_READ_BYTES: u64 = 16 * 1024 * 1024;
+
+// One SFTP subsystem channel per SSH session, opened on first use. Its slot stays locked
+// for a whole operation: libssh2 tracks one request per handle, and retries of two calls
+// on the same handle would get mixed up.
+static SFTP_HANDLES: Lazy<Mutex<HashMap<String, SftpSlot>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+type SftpSlot = Arc<Mutex<Option<Sftp>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteEntry {
+    pub name: String,
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: Option<u64>,
+    /// Permission bits only (e.g. 0o755)
+    pub permissions: Option<u32>,
+    /// `ls -l` style mode string, e.g. "drwxr-xr-x"
+    pub mode: String,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Unix timestamps in seconds
+    pub modified: Option<u64>,
+    pub accessed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileContent {
+    pub path: String,
+    pub size: u64,
+    /// Base64-encoded file bytes
+    pub data: String,
+    /// True if the file was larger than the requested limit
+    pub truncated: bool,
+}
+
+impl RemoteEntry {
+    fn from_stat(path: &Path, stat: &FileStat) -> Self {
+        let kind = match stat.file_type() {
+            FileType::Directory => EntryKind::Directory,
+            FileType::RegularFile => EntryKind::File,
+            FileType::Symlink => EntryKind::Symlink,
+            _ => EntryKind::Other,
+        };
+
+        RemoteEntry {
+            name: path.file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| path.to_string_lossy().to_string()),
+            path: path.to_string_lossy().to_string(),
+            kind,
+            size: stat.size,
+            permissions: stat.perm.map(|p| p & 0o7777),
+            mode: stat.perm.map(mode_string).unwrap_or_default(),
+            uid: stat.uid,
+            gid: stat.gid,
+            modified: stat.mtime,
+            accessed: stat.atime,
+        }
+    }
+}
+
+/// Render a raw st_mode the way `ls -l` does
+fn mode_string(perm: u32) -> String {
+    let kind = match perm & 0o170000 {
+        0o040000 => 'd',
+        0o120000 => 'l',
+        0o020000 => 'c',
+        0o060000 => 'b',
+        0o010000 => 'p',
+        0o140000 => 's',
+        _ => '-',
+    };
+
+    let mut mode = String::with_capacity(10);
+    mode.push(kind);
+    for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (perm >> shift) & 0o7;
+        mode.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        mode.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        mode.push(match (bits & 0o1 != 0, perm & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    mode
+}
+
+/// Run `f` on the session's SFTP channel (opened on first use), one call at a time per
+/// session. Blocks until the server answers.
+pub fn with_sftp<T>(session_id: &str, sess: &Session, f: impl FnOnce(&Sftp) -> Result<T, String>) -> Result<T, String> {
+    let slot = SFTP_HANDLES.lock().entry(session_id.to_string()).or_default().clone();
+    let mut sftp = slot.lock();
+    if sftp.is_none() {
+        *sftp = Some(
+            tunnel::retry_would_block(|| sess.sftp())
+                .map_err(|e| format!("Failed to start SFTP subsystem: {}", e))?,
+        );
+    }
+    f(sftp.as_ref().expect("opened above"))
+}
+
+/// Drop the cached SFTP channel of a session (used when the session disconnects)
+pub fn close_session(session_id: &str) {
+    SFTP_HANDLES.lock().remove(session_id);
+}
+
+/// Empty path or "~" means the login directory
+fn resolve_path(sftp: &Sftp, path: &str) -> Result<PathBuf, String> {
+    if path.is_empty() || path == "~" {
+        return tunnel::retry_would_block(|| sftp.realpath(Path::new(".")))
+            .map_err(|e| format!("Failed to resolve home directory: {}", e));
+    }
+    Ok(PathBuf::from(path))
+}
+
+pub fn list_dir(sftp: &Sftp, path: &str) -> Result<Vec<RemoteEntry>, String> {
+    let dir = resolve_path(sftp, path)?;
+    let entries = tunnel::retry_would_block(|| sftp.readdir(&dir))
+        .map_err(|e| format!("Failed to list {}: {}", dir.display(), e))?;
+
+    let mut entries: Vec<RemoteEntry> = entries.iter()
+        .map(|(path, stat)| RemoteEntry::from_stat(path, stat))
+        .collect();
+    sort_entries(&mut entries);
+    Ok(entries)
+}
+
+/// Directories first, then case-insensitive by name
+fn sort_entries(entries: &mut [RemoteEntry]) {
+    entries.sort_by(|a, b| {
+        (a.kind != EntryKind::Directory)
+            .cmp(&(b.kind != EntryKind::Directory))
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+    });
+}
+
+pub fn stat(sftp: &Sftp, path: &str) -> Result<RemoteEntry, String> {
+    let path = resolve_path(sftp, path)?;
+    let stat = tunnel::retry_would_block(|| sftp.lstat(&path))
+        .map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+    Ok(RemoteEntry::from_stat(&path, &stat))
+}
+
+pub fn mkdir(sftp: &Sftp, path: &str, mode: Option<i32>) -> Result<(), String> {
+    tunnel::retry_would_block(|| sftp.mkdir(Path::new(path), mode.unwrap_or(0o755)))
+        .map_err(|e| format!("Failed to create directory {}: {}", path, e))
+}
+
+pub fn rename(sftp: &Sftp, from: &str, to: &str) -> Result<(), String> {
+    tunnel::retry_would_block(|| sftp.rename(Path::new(from), Path::new(to), None))
+        .map_err(|e| format!("Failed to rename {} to {}: {}", from, to, e))
+}
+
+/// Remove a file, symlink or directory (directories only when empty unless `recursive`)
+pub fn remove(sftp: &Sftp, path: &str, recursive: bool) -> Result<(), String> {
+    let path = Path::new(path);
+    let stat = tunnel::retry_would_block(|| sftp.lstat(path))
+        .map_err(|e| format!("Failed to stat {}: {}", path.display(), e))?;
+
+    if stat.file_type() != FileType::Directory {
+        return tunnel::retry_would_block(|| sftp.unlink(path))
+            .map_err(|e| format!("Failed to remove {}: {}", path.display(), e));
+    }
+
+    if recursive {
+        let children = tunnel::retry_would_block(|| sftp.readdir(path))
+            .map_err(|e| format!("Failed to list {}: {}", path.display(), e))?;
+        for (child, _) in children {
+            remove(sftp, &child.to_string_lossy(), true)?;
+        }
+    }
+
+    tunnel::retry_would_block(|| sftp.rmdir(path))
+        .map_err(|e| format!("Failed to remove directory {}: {}", path.display(), e))
+}
+
+pub fn chmod(sftp: &Sftp, path: &str, mode: u32) -> Result<(), String> {
+    let stat = FileStat {
+        size: None,
+        uid: None,
+        gid: None,
+        perm: Some(mode & 0o7777),
+        atime: None,
+        mtime: None,
+    };
+    tunnel::retry_would_block(|| sftp.setstat(Path::new(path), stat.clone()))
+        .map_err(|e| format!("Failed to change mode of {}: {}", path, e))
+}
+
+pub fn read_file(sftp: &Sftp, path: &str, max_bytes: u64) -> Result<FileContent, String> {
+    let mut file = tunnel::retry_would_block(|| sftp.open(Path::new(path)))
+        .map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    let size = tunnel::retry_would_block(|| file.stat())
+        .map(|s| s.size.unwrap_or(0))
+        .map_err(|e| format!("Failed to stat {}: {}", path, e))?;
+
+    let mut data = Vec::new();
+    let mut buffer = vec![0u8; 32768];
+    while (data.len() as u64) < max_bytes {
+        let want = buffer.len().min((max_bytes - data.len() as u64) as usize);
+        match tunnel::read_blocking(&mut file, &mut buffer[..want]) {
+            Ok(0) => break,
+            Ok(n) => data.extend_from_slice(&buffer[..n]),
+            Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+        }
+    }
+
+    Ok(FileContent {
+        path: path.to_string(),
+        size,
+        truncated: size > data.len() as u64,
+        data: general_purpose::STANDARD.encode(&data),
+    })
+}
+
+/// Create or replace a file with the given (base64) contents
+pub fn write_file(sftp: &Sftp, path: &str, data: &str, mode: Option<i32>) -> Result<(), String> {
+    let bytes = general_purpose::STANDARD.decode(data)
+        .map_err(|e| format!("Failed to decode file contents: {}", e))?;
+
+    let mut file = tunnel::retry_would_block(|| {
+        sftp.open_mode(
+            Path::new(path),
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+            mode.unwrap_or(0o644),
+            OpenType::File,
+        )
+    })
+    .map_err(|e| format!("Failed to open {} for writing: {}", path, e))?;
+
+    tunnel::write_all_blocking(&mut file, &bytes)
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+
+    tunnel::retry_would_block(|| file.close())
+        .map_err(|e| format!("Failed to close {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat_with(perm: u32, size: u64) -> FileStat {
+        FileStat {
+            size: Some(size),
+            uid: Some(1000),
+            gid: Some(1000),
+            perm: Some(perm),
+            atime: None,
+            mtime: Some(1_700_000_000),
+        }
+    }
+
+    #[test]
+    fn test_mode_string() {
+        assert_eq!(mode_string(0o040755), "drwxr-xr-x");
+        assert_eq!(mode_string(0o100644), "-rw-r--r--");
+        assert_eq!(mode_string(0o120777), "lrwxrwxrwx");
+        assert_eq!(mode_string(0o104755), "-rwsr-xr-x");
+        assert_eq!(mode_string(0o041777), "drwxrwxrwt");
+        assert_eq!(mode_string(0o102644), "-rw-r-Sr--");
+    }
+
+    #[test]
+    fn test_entry_from_stat() {
+        let entry = RemoteEntry::from_stat(Path::new("/var/log/syslog"), &stat_with(0o100640, 4096));
+        assert_eq!(entry.name, "syslog");
+        assert_eq!(entry.path, "/var/log/syslog");
+        assert_eq!(entry.kind, EntryKind::File);
+        assert_eq!(entry.permissions, Some(0o640));
+        assert_eq!(entry.size, Some(4096));
+        assert_eq!(entry.modified, Some(1_700_000_000));
+    }
+
+    #[test]
+    fn test_sort_entries_directories_first() {
+        let mut entries = vec![
+            RemoteEntry::from_stat(Path::new("/b.txt"), &stat_with(0o100644, 1)),
+            RemoteEntry::from_stat(Path::new("/Zeta"), &stat_with(0o040755, 0)),
+            RemoteEntry::from_stat(Path::new("/A.txt"), &stat_with(0o100644, 1)),
+            RemoteEntry::from_stat(Path::new("/alpha"), &stat_with(0o040755, 0)),
+        ];
+        sort_entries(&mut entries);
+
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["alpha", "Zeta", "A.txt", "b.txt"]);
+    }
+}
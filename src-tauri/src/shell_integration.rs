@@ -0,0 +1,261 @@
+use crate::scrollback::OscMark;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Finished commands kept per session; older ones are dropped
+pub const MAX_HISTORY: usize = 1000;
+
+/// Typed into bash or zsh to make it report prompts, commands and the working directory
+/// with OSC 133 and OSC 7. The leading space keeps it out of bash's history when
+/// HISTCONTROL ignores space-prefixed lines.
+pub const SNIPPET: &str = concat!(
+    " if [ -n \"$ZSH_VERSION\" ]; then ",
+    "__nt_precmd() { local e=$?; print -n \"\\e]133;D;$e\\a\\e]7;file://$HOST$PWD\\a\\e]133;A\\a\"; }; ",
+    "__nt_preexec() { print -n \"\\e]133;C\\a\"; }; ",
+    "precmd_functions=(__nt_precmd $precmd_functions); preexec_functions+=(__nt_preexec); ",
+    "PS1=\"$PS1\"$'%{\\e]133;B\\a%}'; ",
+    "elif [ -n \"$BASH_VERSION\" ]; then ",
+    "__nt_prompt() { local e=$?; printf '\\033]133;D;%s\\007\\033]7;file://%s%s\\007\\033]133;A\\007' \"$e\" \"$HOSTNAME\" \"$PWD\"; return $e; }; ",
+    "PROMPT_COMMAND=\"__nt_prompt${PROMPT_COMMAND:+;$PROMPT_COMMAND}\"; ",
+    "PS1=\"$PS1\"'\\[\\e]133;B\\a\\]'; PS0='\\e]133;C\\a'; ",
+    "fi\r",
+);
+
+// Shell state of every open session, keyed by session id
+static SESSIONS: Lazy<Mutex<HashMap<String, ShellState>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Default)]
+struct ShellState {
+    cwd: Option<String>,
+    running: Option<CommandRecord>,
+    history: VecDeque<CommandRecord>,
+    next_id: u64,
+}
+
+/// One command, from OSC 133;C (output starts) to OSC 133;D (finished)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandRecord {
+    pub session_id: String,
+    /// Counts up from 0 within the session
+    pub id: u64,
+    /// As typed, read off the screen; None when the shell did not mark where input starts
+    pub command: Option<String>,
+    /// Working directory last reported with OSC 7
+    pub cwd: Option<String>,
+    /// Unix time in milliseconds
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    /// None while running, and when the shell did not report it
+    pub exit_code: Option<i32>,
+    /// The command's output is scrollback lines `output_start_line..output_end_line`
+    pub output_start_line: u64,
+    pub output_end_line: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShellHistory {
+    pub session_id: String,
+    pub cwd: Option<String>,
+    pub running: Option<CommandRecord>,
+    /// Oldest first
+    pub commands: Vec<CommandRecord>,
+}
+
+impl ShellState {
+    // Returns the commands the marks finished
+    fn apply(&mut self, session_id: &str, marks: Vec<OscMark>, now: u64) -> Vec<CommandRecord> {
+        let mut finished = Vec::new();
+        for mark in marks {
+            let mut fields = mark.payload.splitn(3, ';');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("7"), Some(_), _) => {
+                    self.cwd = mark.payload.split_once(';').and_then(|(_, url)| cwd_from_url(url));
+                }
+                // A new prompt without OSC 133;D: the shell does not report exit codes
+                (Some("133"), Some("A"), _) => {
+                    finished.extend(self.finish(&mark, None, now));
+                }
+                (Some("133"), Some("C"), _) => {
+                    finished.extend(self.finish(&mark, None, now));
+                    let command = crate::screen::take_command_line(session_id).filter(|line| !line.is_empty());
+                    self.running = Some(CommandRecord {
+                        session_id: session_id.to_string(),
+                        id: self.next_id,
+                        command,
+                        cwd: self.cwd.clone(),
+                        started_at: now,
+                        finished_at: None,
+                        exit_code: None,
+                        output_start_line: mark.line,
+                        output_end_line: None,
+                    });
+                    self.next_id += 1;
+                }
+                (Some("133"), Some("D"), rest) => {
+                    // "133;D;<exit code>", possibly followed by more ;-separated options
+                    let exit_code = rest.and_then(|rest| rest.split(';').next()?.parse().ok());
+                    finished.extend(self.finish(&mark, exit_code, now));
+                }
+                _ => {}
+            }
+        }
+        finished
+    }
+
+    fn finish(&mut self, mark: &OscMark, exit_code: Option<i32>, now: u64) -> Option<CommandRecord> {
+        let mut command = self.running.take()?;
+        command.finished_at = Some(now);
+        command.exit_code = exit_code;
+        // Output that did not end with a newline still counts its last line
+        command.output_end_line = Some(mark.line + u64::from(mark.column > 0));
+        if self.history.len() == MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(command.clone());
+        Some(command)
+    }
+}
+
+/// The path of an OSC 7 "file://host/path" URL, percent-decoded
+fn cwd_from_url(url: &str) -> Option<String> {
+    let rest = url.strip_prefix("file://")?;
+    let path = &rest[rest.find('/')?..];
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = (bytes[i] == b'%')
+            .then(|| path.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    Some(String::from_utf8_lossy(&decoded).to_string())
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+pub fn open(session_id: &str) {
+    SESSIONS.lock().insert(session_id.to_string(), ShellState::default());
+}
+
+pub fn close(session_id: &str) {
+    SESSIONS.lock().remove(session_id);
+}
+
+/// Track the shell through the OSC strings found in its output. Returns the commands
+/// that finished.
+pub fn handle_marks(session_id: &str, marks: Vec<OscMark>) -> Vec<CommandRecord> {
+    if marks.is_empty() {
+        return Vec::new();
+    }
+    match SESSIONS.lock().get_mut(session_id) {
+        Some(state) => state.apply(session_id, marks, unix_millis()),
+        None => Vec::new(),
+    }
+}
+
+/// The last `limit` finished commands (all when unset), the running one and the cwd
+pub fn history(session_id: &str, limit: Option<usize>) -> Result<ShellHistory, String> {
+    let sessions = SESSIONS.lock();
+    let state = sessions.get(session_id)
+        .ok_or_else(|| format!("No shell history for session {}", session_id))?;
+    let skip = limit.map_or(0, |limit| state.history.len().saturating_sub(limit));
+    Ok(ShellHistory {
+        session_id: session_id.to_string(),
+        cwd: state.cwd.clone(),
+        running: state.running.clone(),
+        commands: state.history.iter().skip(skip).cloned().collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+
+    fn mark(line: u64, column: usize, payload: &str) -> OscMark {
+        OscMark { line, column, payload: payload.to_string() }
+    }
+
+    #[test]
+    fn test_cwd_from_url() {
+        assert_eq!(cwd_from_url("file://web1/home/me/My%20Files").as_deref(), Some("/home/me/My Files"));
+        assert_eq!(cwd_from_url("file:///tmp").as_deref(), Some("/tmp"));
+        assert_eq!(cwd_from_url("file://web1/100%").as_deref(), Some("/100%"));
+        assert_eq!(cwd_from_url("http://web1/tmp"), None);
+    }
+
+    #[test]
+    fn test_command_lifecycle() {
+        let mut state = ShellState::default();
+        let finished = state.apply("sh-test", vec![
+            mark(0, 0, "7;file://web1/srv"),
+            // The shell's first prompt reports the exit code of nothing
+            mark(0, 0, "133;D;0"),
+            mark(0, 0, "133;A"),
+            mark(0, 2, "133;B"),
+            mark(1, 0, "133;C"),
+        ], 1000);
+        assert!(finished.is_empty());
+        assert_eq!(state.running.as_ref().unwrap().cwd.as_deref(), Some("/srv"));
+
+        let finished = state.apply("sh-test", vec![mark(3, 4, "133;D;127;aid=1"), mark(4, 0, "133;A")], 1500);
+        assert_eq!(finished.len(), 1);
+        let command = &finished[0];
+        assert_eq!((command.id, command.exit_code, command.finished_at), (0, Some(127), Some(1500)));
+        // Lines 1 and 2, and the unterminated line 3
+        assert_eq!((command.output_start_line, command.output_end_line), (1, Some(4)));
+        assert!(state.running.is_none());
+
+        // A shell that only marks prompts and command starts
+        state.apply("sh-test", vec![mark(5, 0, "133;C")], 2000);
+        let finished = state.apply("sh-test", vec![mark(6, 0, "133;A")], 2600);
+        assert_eq!((finished[0].id, finished[0].exit_code), (1, None));
+        assert_eq!(state.history.len(), 2);
+    }
+
+    #[test]
+    fn test_output_to_history() {
+        crate::screen::open("sh-test-1", (80, 24), UTF_8);
+        crate::scrollback::open("sh-test-1", UTF_8);
+        open("sh-test-1");
+        let mut finished = Vec::new();
+        for chunk in [
+            &b"\x1b]7;file://web1/tmp\x07\x1b]133;A\x07$ \x1b]133;B\x07"[..],
+            b"ls\r\n\x1b]133;C\x07a.txt\r\nb.txt\r\n\x1b]133;D;0\x07\x1b]133;A\x07$ ",
+        ] {
+            crate::screen::capture_output("sh-test-1", chunk);
+            let marks = crate::scrollback::capture_output("sh-test-1", chunk);
+            finished.extend(handle_marks("sh-test-1", marks));
+        }
+
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].command.as_deref(), Some("ls"));
+        assert_eq!(finished[0].cwd.as_deref(), Some("/tmp"));
+        let (start, end) = (finished[0].output_start_line, finished[0].output_end_line.unwrap());
+        let output = crate::scrollback::range("sh-test-1", Some(start), Some((end - start) as usize)).unwrap();
+        assert_eq!(output.lines, vec!["a.txt", "b.txt"]);
+
+        let recorded = history("sh-test-1", Some(10)).unwrap();
+        assert_eq!((recorded.commands.len(), recorded.cwd.as_deref()), (1, Some("/tmp")));
+        close("sh-test-1");
+        crate::scrollback::close("sh-test-1");
+        crate::screen::close("sh-test-1");
+        assert!(history("sh-test-1", None).is_err());
+    }
+}
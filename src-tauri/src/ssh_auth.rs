@@ -0,0 +1,349 @@
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use ssh2::{KeyboardInteractivePrompt, Prompt, Session};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+use tauri::Window;
+
+// How long a keyboard-interactive prompt waits for the user (OTP apps and Duo pushes can be slow)
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(180);
+
+// Answers to one keyboard-interactive round; `None` means the user cancelled
+type PromptAnswers = Option<Vec<String>>;
+
+// Keyboard-interactive prompts waiting for `pty_auth_respond`, keyed by session_id
+static PENDING_PROMPTS: Lazy<Mutex<HashMap<String, mpsc::Sender<PromptAnswers>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Who answers a connection's host key and keyboard-interactive prompts
+#[derive(Clone, Copy)]
+pub enum Prompts<'a> {
+    /// The terminal view of `session_id`, via `pty-hostkey-prompt` and `pty-auth-prompt`
+    Frontend { session_id: &'a str, window: &'a Window },
+    /// Nobody is watching (cluster and macro runs): unknown host keys are refused and
+    /// keyboard-interactive only gets the stored password
+    Unattended,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    Password,
+    Key,
+    Agent,
+    #[serde(rename = "keyboard-interactive")]
+    KeyboardInteractive,
+}
+
+/// Credentials for one SSH login. Flattened into connection params, so the
+/// frontend keeps sending `username`, `password`, `ssh_key_path`, ... at the top level.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Option<String>,
+    pub ssh_key_path: Option<String>,
+    pub ssh_key_passphrase: Option<String>,
+    /// Explicit auth method; when omitted, a key is used if given, otherwise the password
+    #[serde(default)]
+    pub auth_method: Option<AuthMethod>,
+}
+
+impl Credentials {
+    /// Map a method name advertised by the server to something these credentials can attempt
+    fn usable_method(&self, advertised: &str) -> Option<AuthMethod> {
+        match advertised {
+            "publickey" if self.ssh_key_path.is_some() => Some(AuthMethod::Key),
+            "publickey" if self.auth_method == Some(AuthMethod::Agent) => Some(AuthMethod::Agent),
+            "password" if self.password.is_some() => Some(AuthMethod::Password),
+            "keyboard-interactive" => Some(AuthMethod::KeyboardInteractive),
+            _ => None,
+        }
+    }
+}
+
+/// Load a credential saved in secure storage (the database must be unlocked)
+pub fn stored_credentials(id: &str) -> Result<Credentials, String> {
+    crate::secure_storage::with_database(|db| {
+        let stored = db.get_credential(id)?;
+
+        Ok(Credentials {
+            username: stored.username.clone().unwrap_or_default(),
+            password: db.decrypt_password(stored.password_encrypted)?,
+            ssh_key_path: stored.ssh_key_path,
+            ssh_key_passphrase: db.decrypt_password(stored.passphrase_encrypted)?,
+            auth_method: None,
+        })
+    })
+}
+
+/// Pick the first method, in the server's order, that has not been tried yet
+fn next_method(advertised: &str, tried: &[AuthMethod], creds: &Credentials) -> Option<AuthMethod> {
+    advertised
+        .split(',')
+        .map(str::trim)
+        .filter_map(|name| creds.usable_method(name))
+        .find(|method| !tried.contains(method))
+}
+
+/// Authenticate an already handshaked session.
+///
+/// An explicit `auth_method` is attempted first; after that (or when none is set) methods are
+/// tried in the order advertised by the server until it reports the session authenticated,
+/// so partial-success chains like publickey + keyboard-interactive OTP work.
+pub fn authenticate(sess: &Session, creds: &Credentials, prompts: Prompts) -> Result<(), String> {
+    let mut prompter = FrontendPrompter {
+        prompts,
+        password: creds.password.as_deref(),
+    };
+    let mut tried: Vec<AuthMethod> = Vec::new();
+    let mut last_error: Option<String> = None;
+    let mut next = creds.auth_method;
+
+    while !sess.authenticated() {
+        let method = match next.take() {
+            Some(method) => method,
+            None => {
+                let advertised = match sess.auth_methods(&creds.username) {
+                    Ok(list) => list.to_string(),
+                    // "none" authentication succeeded
+                    Err(_) if sess.authenticated() => break,
+                    Err(e) => return Err(format!("Failed to query authentication methods: {}", e)),
+                };
+                match next_method(&advertised, &tried, creds) {
+                    Some(method) => method,
+                    None => break,
+                }
+            }
+        };
+
+        tried.push(method);
+        if let Err(e) = try_method(sess, creds, method, &mut prompter) {
+            last_error = Some(e);
+        }
+    }
+
+    if !sess.authenticated() {
+        return Err(last_error.unwrap_or_else(|| "No authentication method provided".to_string()));
+    }
+
+    Ok(())
+}
+
+fn try_method(
+    sess: &Session,
+    creds: &Credentials,
+    method: AuthMethod,
+    prompter: &mut FrontendPrompter,
+) -> Result<(), String> {
+    match method {
+        AuthMethod::Agent => authenticate_with_agent(sess, &creds.username),
+        AuthMethod::Key => {
+            let key_path = creds.ssh_key_path.as_deref()
+                .ok_or("No SSH key path provided")?;
+            sess.userauth_pubkey_file(
+                &creds.username,
+                None,
+                Path::new(key_path),
+                creds.ssh_key_passphrase.as_deref(),
+            )
+            .map_err(|e| format!("SSH key authentication failed: {}", e))
+        }
+        AuthMethod::Password => {
+            let password = creds.password.as_deref()
+                .ok_or("No authentication method provided")?;
+            sess.userauth_password(&creds.username, password)
+                .map_err(|e| format!("Password authentication failed: {}", e))
+        }
+        AuthMethod::KeyboardInteractive => {
+            // libssh2's timeout covers the whole call, time spent in the prompt included, so
+            // the auth timeout would cut off a user still typing an OTP or approving a push.
+            // The prompt has its own limit.
+            let auth_timeout = sess.timeout();
+            sess.set_timeout(0);
+            let result = sess.userauth_keyboard_interactive(&creds.username, prompter);
+            sess.set_timeout(auth_timeout);
+            result.map_err(|e| format!("Keyboard-interactive authentication failed: {}", e))
+        }
+    }
+}
+
+/// Try every identity held by the local ssh-agent until the server accepts one
+fn authenticate_with_agent(sess: &Session, username: &str) -> Result<(), String> {
+    let mut agent = sess.agent()
+        .map_err(|e| format!("Failed to initialize SSH agent: {}", e))?;
+    agent.connect()
+        .map_err(|e| format!("Failed to connect to SSH agent: {}", e))?;
+    agent.list_identities()
+        .map_err(|e| format!("Failed to list SSH agent identities: {}", e))?;
+
+    let identities = agent.identities()
+        .map_err(|e| format!("Failed to read SSH agent identities: {}", e))?;
+    if identities.is_empty() {
+        let _ = agent.disconnect();
+        return Err("SSH agent has no identities loaded".to_string());
+    }
+
+    for identity in &identities {
+        if agent.userauth(username, identity).is_ok() && sess.authenticated() {
+            let _ = agent.disconnect();
+            return Ok(());
+        }
+    }
+
+    let _ = agent.disconnect();
+    Err(format!(
+        "SSH agent authentication failed: none of the {} agent identities were accepted",
+        identities.len()
+    ))
+}
+
+fn is_password_prompt(text: &str) -> bool {
+    text.to_lowercase().contains("password")
+}
+
+/// Answers keyboard-interactive challenges by round-tripping them to the frontend.
+///
+/// The first password prompt is answered from the stored password, so a plain
+/// password + OTP setup only asks the user for the OTP. Unattended, nothing else is answered.
+struct FrontendPrompter<'a> {
+    prompts: Prompts<'a>,
+    password: Option<&'a str>,
+}
+
+impl KeyboardInteractivePrompt for FrontendPrompter<'_> {
+    fn prompt<'b>(&mut self, username: &str, instructions: &str, prompts: &[Prompt<'b>]) -> Vec<String> {
+        if prompts.is_empty() {
+            return Vec::new();
+        }
+
+        if prompts.len() == 1 && !prompts[0].echo && is_password_prompt(&prompts[0].text) {
+            if let Some(password) = self.password.take() {
+                return vec![password.to_string()];
+            }
+        }
+
+        // An empty answer makes libssh2 fail the attempt, which ends authentication cleanly
+        let Prompts::Frontend { session_id, window } = self.prompts else {
+            return Vec::new();
+        };
+
+        let (tx, rx) = mpsc::channel();
+        PENDING_PROMPTS.lock().insert(session_id.to_string(), tx);
+
+        let prompt_list: Vec<serde_json::Value> = prompts.iter()
+            .map(|p| serde_json::json!({ "text": p.text, "echo": p.echo }))
+            .collect();
+        let _ = window.emit("pty-auth-prompt", serde_json::json!({
+            "session_id": session_id,
+            "username": username,
+            "instructions": instructions,
+            "prompts": prompt_list
+        }));
+
+        let responses = rx.recv_timeout(PROMPT_TIMEOUT).ok().flatten();
+        PENDING_PROMPTS.lock().remove(session_id);
+        responses.unwrap_or_default()
+    }
+}
+
+/// Deliver the user's answers (or `None` to cancel) to a waiting keyboard-interactive prompt
+pub fn respond(session_id: &str, responses: PromptAnswers) -> Result<(), String> {
+    let sender = PENDING_PROMPTS.lock().remove(session_id)
+        .ok_or("No authentication prompt pending for this session")?;
+    sender.send(responses)
+        .map_err(|_| "Authentication prompt is no longer waiting".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_method_follows_server_order() {
+        let creds = Credentials {
+            username: "root".to_string(),
+            password: Some("secret".to_string()),
+            ssh_key_path: Some("/home/root/.ssh/id_ed25519".to_string()),
+            ..Default::default()
+        };
+        let advertised = "publickey,password,keyboard-interactive";
+
+        assert_eq!(next_method(advertised, &[], &creds), Some(AuthMethod::Key));
+        assert_eq!(
+            next_method(advertised, &[AuthMethod::Key], &creds),
+            Some(AuthMethod::Password)
+        );
+        assert_eq!(
+            next_method(advertised, &[AuthMethod::Key, AuthMethod::Password], &creds),
+            Some(AuthMethod::KeyboardInteractive)
+        );
+        assert_eq!(
+            next_method(
+                advertised,
+                &[AuthMethod::Key, AuthMethod::Password, AuthMethod::KeyboardInteractive],
+                &creds
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_next_method_skips_methods_without_credentials() {
+        let creds = Credentials {
+            username: "root".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            next_method("publickey,password,keyboard-interactive", &[], &creds),
+            Some(AuthMethod::KeyboardInteractive)
+        );
+        assert_eq!(next_method("publickey,password", &[], &creds), None);
+    }
+
+    #[test]
+    fn test_agent_only_used_for_publickey_when_requested() {
+        let mut creds = Credentials {
+            username: "root".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(next_method("publickey", &[], &creds), None);
+
+        creds.auth_method = Some(AuthMethod::Agent);
+        assert_eq!(next_method("publickey", &[], &creds), Some(AuthMethod::Agent));
+    }
+
+    #[test]
+    fn test_is_password_prompt() {
+        assert!(is_password_prompt("Password: "));
+        assert!(is_password_prompt("root@bastion's password:"));
+        assert!(!is_password_prompt("Verification code: "));
+    }
+
+    #[test]
+    fn test_unattended_prompter_only_answers_the_password() {
+        let mut prompter = FrontendPrompter { prompts: Prompts::Unattended, password: Some("secret") };
+        let password = [Prompt { text: "Password: ".into(), echo: false }];
+        let otp = [Prompt { text: "Verification code: ".into(), echo: false }];
+        assert_eq!(prompter.prompt("root", "", &password), vec!["secret"]);
+        // Returns at once instead of waiting for an answer nobody will give
+        assert!(prompter.prompt("root", "", &otp).is_empty());
+        assert!(PENDING_PROMPTS.lock().is_empty());
+    }
+
+    #[test]
+    fn test_respond_without_pending_prompt_fails() {
+        assert!(respond("no-such-session", Some(vec!["123456".to_string()])).is_err());
+    }
+
+    #[test]
+    fn test_credentials_optional_fields_may_be_omitted() {
+        let creds: Credentials = serde_json::from_str(
+            r#"{"username":"deploy","auth_method":"keyboard-interactive"}"#,
+        ).unwrap();
+        assert_eq!(creds.username, "deploy");
+        assert_eq!(creds.auth_method, Some(AuthMethod::KeyboardInteractive));
+    }
+}
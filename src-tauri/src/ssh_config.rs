@@ -0,0 +1,611 @@
+use crate::proxy_jump::JumpHost;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// Guards against Include loops and ProxyJump aliases that refer back to themselves
+const MAX_INCLUDE_DEPTH: usize = 8;
+const MAX_JUMP_DEPTH: usize = 8;
+
+/// One `Host` block (or the implicit block before the first `Host` line)
+#[derive(Debug, Clone, Default)]
+struct HostBlock {
+    patterns: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+impl HostBlock {
+    /// OpenSSH semantics: any positive pattern must match and no negated pattern may match
+    fn matches(&self, alias: &str) -> bool {
+        let mut matched = false;
+        for pattern in &self.patterns {
+            if let Some(negated) = pattern.strip_prefix('!') {
+                if wildcard_match(negated, alias) {
+                    return false;
+                }
+            } else if wildcard_match(pattern, alias) {
+                matched = true;
+            }
+        }
+        matched
+    }
+}
+
+/// A parsed ssh_config file
+#[derive(Debug, Clone, Default)]
+pub struct SshConfig {
+    blocks: Vec<HostBlock>,
+}
+
+/// A `ProxyJump` entry: `[user@]host[:port]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JumpSpec {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+/// Everything the config says about one alias, with first-match-wins semantics applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedHost {
+    pub alias: String,
+    pub host_name: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub identity_files: Vec<String>,
+    pub proxy_jump: Vec<JumpSpec>,
+}
+
+/// A Host block imported as a server entry for the server list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportedServer {
+    pub id: String,
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub ssh_key_path: Option<String>,
+    pub jump_hosts: Vec<JumpHost>,
+}
+
+/// ~/.ssh/config
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".ssh").join("config"))
+}
+
+impl SshConfig {
+    /// Load a config file, following `Include` directives
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut lines = Vec::new();
+        read_with_includes(path, 0, &mut lines)?;
+        Ok(Self::parse(&lines.join("\n")))
+    }
+
+    /// Parse config text. `Include` lines are ignored here; use `load` to follow them.
+    pub fn parse(content: &str) -> Self {
+        let mut blocks = vec![HostBlock {
+            patterns: vec!["*".to_string()],
+            options: Vec::new(),
+        }];
+
+        for line in content.lines() {
+            let Some((key, value)) = split_line(line) else {
+                continue;
+            };
+
+            match key.as_str() {
+                "host" => blocks.push(HostBlock {
+                    patterns: split_words(&value),
+                    options: Vec::new(),
+                }),
+                // Match criteria are not supported; an empty pattern list never applies
+                "match" => blocks.push(HostBlock::default()),
+                "include" => {}
+                _ => {
+                    if let Some(block) = blocks.last_mut() {
+                        block.options.push((key, value));
+                    }
+                }
+            }
+        }
+
+        SshConfig { blocks }
+    }
+
+    /// Concrete aliases named in `Host` lines (wildcard and negated patterns are skipped)
+    pub fn aliases(&self) -> Vec<String> {
+        let mut aliases: Vec<String> = Vec::new();
+        for pattern in self.blocks.iter().flat_map(|b| b.patterns.iter()) {
+            let concrete = !pattern.contains(['*', '?', '!']);
+            if concrete && !aliases.contains(pattern) {
+                aliases.push(pattern.clone());
+            }
+        }
+        aliases
+    }
+
+    /// Resolve an alias the way `ssh -G` would for the options we support
+    pub fn resolve(&self, alias: &str) -> ResolvedHost {
+        let mut options: HashMap<String, String> = HashMap::new();
+        let mut identity_files: Vec<String> = Vec::new();
+
+        for block in self.blocks.iter().filter(|b| b.matches(alias)) {
+            for (key, value) in &block.options {
+                if key == "identityfile" {
+                    identity_files.push(value.clone());
+                } else {
+                    options.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+            }
+        }
+
+        let host_name = options.get("hostname")
+            .map(|h| h.replace("%h", alias))
+            .unwrap_or_else(|| alias.to_string());
+        let port = options.get("port").and_then(|p| p.parse().ok()).unwrap_or(22);
+        let user = options.get("user").cloned();
+
+        let tokens = Tokens {
+            alias,
+            host_name: &host_name,
+            port,
+            remote_user: user.as_deref(),
+        };
+        let identity_files = identity_files.iter()
+            .filter(|f| !f.eq_ignore_ascii_case("none"))
+            .map(|f| tokens.expand(f))
+            .collect();
+
+        let proxy_jump = match options.get("proxyjump") {
+            Some(value) if !value.eq_ignore_ascii_case("none") => {
+                value.split(',').filter_map(parse_jump_spec).collect()
+            }
+            _ => Vec::new(),
+        };
+
+        ResolvedHost {
+            alias: alias.to_string(),
+            host_name,
+            port,
+            user,
+            identity_files,
+            proxy_jump,
+        }
+    }
+
+    /// Expand a host's ProxyJump entries into jump hosts, resolving aliases recursively
+    pub fn jump_hosts(&self, resolved: &ResolvedHost) -> Result<Vec<JumpHost>, String> {
+        self.jump_hosts_at_depth(resolved, 0)
+    }
+
+    fn jump_hosts_at_depth(&self, resolved: &ResolvedHost, depth: usize) -> Result<Vec<JumpHost>, String> {
+        if depth > MAX_JUMP_DEPTH {
+            return Err(format!("ProxyJump chain for {} is too deep", resolved.alias));
+        }
+
+        let mut hops = Vec::new();
+        for spec in &resolved.proxy_jump {
+            let hop = self.resolve(&spec.host);
+            // A bastion's own ProxyJump comes before it in the chain
+            hops.extend(self.jump_hosts_at_depth(&hop, depth + 1)?);
+            hops.push(JumpHost {
+                host: hop.host_name.clone(),
+                port: spec.port.unwrap_or(hop.port),
+                credential_id: None,
+                username: spec.user.clone().or(hop.user.clone()).or_else(local_username),
+                ssh_key_path: first_identity_file(&hop.identity_files),
+            });
+        }
+        Ok(hops)
+    }
+
+    /// Turn every concrete Host alias into a server entry
+    pub fn import_servers(&self) -> Result<Vec<ImportedServer>, String> {
+        self.aliases()
+            .into_iter()
+            .map(|alias| {
+                let resolved = self.resolve(&alias);
+                Ok(ImportedServer {
+                    id: format!("ssh-config-{}", alias),
+                    name: alias.clone(),
+                    host: resolved.host_name.clone(),
+                    port: resolved.port,
+                    username: resolved.user.clone().or_else(local_username).unwrap_or_default(),
+                    ssh_key_path: first_identity_file(&resolved.identity_files),
+                    jump_hosts: self.jump_hosts(&resolved)?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The first configured identity file that exists, falling back to the first configured one
+pub fn first_identity_file(identity_files: &[String]) -> Option<String> {
+    identity_files.iter()
+        .find(|f| Path::new(f).exists())
+        .or_else(|| identity_files.first())
+        .cloned()
+}
+
+/// The local login name, which ssh uses when no `User` is configured
+pub fn local_username() -> Option<String> {
+    std::env::var("USER").or_else(|_| std::env::var("USERNAME")).ok()
+}
+
+fn read_with_includes(path: &Path, depth: usize, lines: &mut Vec<String>) -> Result<(), String> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(format!("Too many nested Include directives in {}", path.display()));
+    }
+
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+    for line in content.lines() {
+        match split_line(line) {
+            Some((key, value)) if key == "include" => {
+                for pattern in split_words(&value) {
+                    for included in expand_include(&pattern) {
+                        // Missing includes are not an error for ssh either
+                        if included.is_file() {
+                            read_with_includes(&included, depth + 1, lines)?;
+                        }
+                    }
+                }
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve an Include argument: `~` expansion, relative to ~/.ssh, and `*`/`?` in the file name
+fn expand_include(pattern: &str) -> Vec<PathBuf> {
+    let expanded = expand_tilde(pattern);
+    let path = if Path::new(&expanded).is_absolute() {
+        PathBuf::from(expanded)
+    } else {
+        match dirs::home_dir() {
+            Some(home) => home.join(".ssh").join(expanded),
+            None => PathBuf::from(expanded),
+        }
+    };
+
+    let file_pattern = path.file_name().map(|f| f.to_string_lossy().to_string()).unwrap_or_default();
+    if !file_pattern.contains(['*', '?']) {
+        return vec![path];
+    }
+
+    let Some(dir) = path.parent() else {
+        return Vec::new();
+    };
+    let mut matches: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| {
+                    p.file_name()
+                        .map(|f| wildcard_match(&file_pattern, &f.to_string_lossy()))
+                        .unwrap_or(false)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    matches.sort();
+    matches
+}
+
+/// Split a config line into a lowercased keyword and its value (`Key value` or `Key=value`)
+fn split_line(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let key_end = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+    let key = line[..key_end].to_lowercase();
+    let rest = line[key_end..].trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest).trim();
+
+    Some((key, unquote(rest)))
+}
+
+fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') && value.matches('"').count() == 2 {
+        value[1..value.len() - 1].to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Split a value into whitespace-separated words, honouring double quotes
+fn split_words(value: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in value.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    words.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        words.push(current);
+    }
+    words
+}
+
+/// Glob match supporting `*` and `?`, as used by Host patterns
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Parse one ProxyJump entry: `[user@]host[:port]` or `ssh://[user@]host[:port]`
+fn parse_jump_spec(spec: &str) -> Option<JumpSpec> {
+    let spec = spec.trim();
+    let spec = spec.strip_prefix("ssh://").unwrap_or(spec);
+    if spec.is_empty() {
+        return None;
+    }
+
+    let (user, host_port) = match spec.rsplit_once('@') {
+        Some((user, rest)) => (Some(user.to_string()), rest),
+        None => (None, spec),
+    };
+
+    let (host, port) = if let Some(bracketed) = host_port.strip_prefix('[') {
+        // [IPv6]:port
+        let (host, rest) = bracketed.split_once(']')?;
+        (host.to_string(), rest.strip_prefix(':').and_then(|p| p.parse().ok()))
+    } else {
+        match host_port.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') => (host.to_string(), port.parse().ok()),
+            _ => (host_port.to_string(), None),
+        }
+    };
+
+    Some(JumpSpec { user, host, port })
+}
+
+fn expand_tilde(path: &str) -> String {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).to_string_lossy().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+/// Values for the `%` tokens ssh allows in IdentityFile
+struct Tokens<'a> {
+    alias: &'a str,
+    host_name: &'a str,
+    port: u16,
+    remote_user: Option<&'a str>,
+}
+
+impl Tokens<'_> {
+    fn expand(&self, value: &str) -> String {
+        let value = expand_tilde(value);
+        let mut out = String::new();
+        let mut chars = value.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('%') => out.push('%'),
+                Some('h') => out.push_str(self.host_name),
+                Some('n') => out.push_str(self.alias),
+                Some('p') => out.push_str(&self.port.to_string()),
+                Some('r') => out.push_str(self.remote_user.unwrap_or_default()),
+                Some('u') => out.push_str(&local_username().unwrap_or_default()),
+                Some('d') => {
+                    if let Some(home) = dirs::home_dir() {
+                        out.push_str(&home.to_string_lossy());
+                    }
+                }
+                Some(other) => {
+                    out.push('%');
+                    out.push(other);
+                }
+                None => out.push('%'),
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const SAMPLE: &str = r#"
+# Global defaults
+ServerAliveInterval 30
+
+Host web-* !web-legacy
+    User deploy
+    IdentityFile /keys/web_ed25519
+
+Host web-1
+    HostName 10.0.0.11
+    Port 2222
+
+Host db
+    HostName=db.internal
+    User postgres
+    ProxyJump bastion,ops@bastion2:2200
+
+Host bastion
+    HostName bastion.example.com
+    User jump
+    ForwardAgent yes
+
+Host *
+    User fallback
+    Port 22
+"#;
+
+    #[test]
+    fn test_first_value_wins() {
+        let config = SshConfig::parse(SAMPLE);
+        let resolved = config.resolve("web-1");
+
+        assert_eq!(resolved.host_name, "10.0.0.11");
+        assert_eq!(resolved.port, 2222);
+        assert_eq!(resolved.user.as_deref(), Some("deploy"));
+        assert_eq!(resolved.identity_files, vec!["/keys/web_ed25519".to_string()]);
+    }
+
+    #[test]
+    fn test_negated_pattern_excludes_host() {
+        let config = SshConfig::parse(SAMPLE);
+        let resolved = config.resolve("web-legacy");
+
+        assert_eq!(resolved.user.as_deref(), Some("fallback"));
+        assert!(resolved.identity_files.is_empty());
+    }
+
+    #[test]
+    fn test_unknown_alias_uses_wildcard_block() {
+        let config = SshConfig::parse(SAMPLE);
+        let resolved = config.resolve("somewhere.example.com");
+
+        assert_eq!(resolved.host_name, "somewhere.example.com");
+        assert_eq!(resolved.port, 22);
+        assert_eq!(resolved.user.as_deref(), Some("fallback"));
+    }
+
+    #[test]
+    fn test_proxy_jump_resolves_aliases() {
+        let config = SshConfig::parse(SAMPLE);
+        let resolved = config.resolve("db");
+
+        assert_eq!(resolved.proxy_jump, vec![
+            JumpSpec { user: None, host: "bastion".to_string(), port: None },
+            JumpSpec { user: Some("ops".to_string()), host: "bastion2".to_string(), port: Some(2200) },
+        ]);
+
+        let hops = config.jump_hosts(&resolved).unwrap();
+        assert_eq!(hops.len(), 2);
+        assert_eq!(hops[0].host, "bastion.example.com");
+        assert_eq!(hops[0].username.as_deref(), Some("jump"));
+        assert_eq!(hops[1].host, "bastion2");
+        assert_eq!(hops[1].port, 2200);
+        assert_eq!(hops[1].username.as_deref(), Some("ops"));
+    }
+
+    #[test]
+    fn test_recursive_proxy_jump_is_rejected() {
+        let config = SshConfig::parse("Host a\n  ProxyJump b\nHost b\n  ProxyJump a\n");
+        let resolved = config.resolve("a");
+        assert!(config.jump_hosts(&resolved).is_err());
+    }
+
+    #[test]
+    fn test_aliases_skip_patterns() {
+        let config = SshConfig::parse(SAMPLE);
+        assert_eq!(config.aliases(), vec!["web-1", "db", "bastion"]);
+    }
+
+    #[test]
+    fn test_import_servers() {
+        let config = SshConfig::parse(SAMPLE);
+        let servers = config.import_servers().unwrap();
+
+        let bastion = servers.iter().find(|s| s.name == "bastion").unwrap();
+        assert_eq!(bastion.id, "ssh-config-bastion");
+        assert_eq!(bastion.host, "bastion.example.com");
+        assert_eq!(bastion.username, "jump");
+
+        let db = servers.iter().find(|s| s.name == "db").unwrap();
+        assert_eq!(db.jump_hosts.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_jump_spec() {
+        assert_eq!(parse_jump_spec("host"), Some(JumpSpec { user: None, host: "host".to_string(), port: None }));
+        assert_eq!(
+            parse_jump_spec("ssh://me@host:2022"),
+            Some(JumpSpec { user: Some("me".to_string()), host: "host".to_string(), port: Some(2022) })
+        );
+        assert_eq!(
+            parse_jump_spec("[fe80::1]:2200"),
+            Some(JumpSpec { user: None, host: "fe80::1".to_string(), port: Some(2200) })
+        );
+        assert_eq!(parse_jump_spec(""), None);
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*", "anything"));
+        assert!(wildcard_match("web-*", "web-12"));
+        assert!(wildcard_match("db?", "db1"));
+        assert!(wildcard_match("*.example.com", "a.b.example.com"));
+        assert!(!wildcard_match("web-*", "db-1"));
+        assert!(!wildcard_match("db?", "db12"));
+    }
+
+    #[test]
+    fn test_identity_file_tokens() {
+        let config = SshConfig::parse("Host box\n  HostName box.example.com\n  User admin\n  IdentityFile /keys/%r@%h_%p\n");
+        let resolved = config.resolve("box");
+        assert_eq!(resolved.identity_files, vec!["/keys/admin@box.example.com_22".to_string()]);
+    }
+
+    #[test]
+    fn test_quoted_values_and_equals_syntax() {
+        let config = SshConfig::parse("Host \"my box\" other\n  IdentityFile=\"/path with spaces/key\"\n");
+        assert_eq!(config.aliases(), vec!["my box", "other"]);
+        assert_eq!(config.resolve("other").identity_files, vec!["/path with spaces/key".to_string()]);
+    }
+
+    #[test]
+    fn test_load_follows_includes() {
+        let temp_dir = TempDir::new().unwrap();
+        let conf_d = temp_dir.path().join("conf.d");
+        std::fs::create_dir(&conf_d).unwrap();
+        std::fs::write(conf_d.join("a.conf"), "Host included\n  HostName included.example.com\n").unwrap();
+
+        let main_config = temp_dir.path().join("config");
+        std::fs::write(
+            &main_config,
+            format!("Include {}/*.conf\nHost main\n  HostName main.example.com\n", conf_d.display()),
+        ).unwrap();
+
+        let config = SshConfig::load(&main_config).unwrap();
+        assert_eq!(config.resolve("included").host_name, "included.example.com");
+        assert_eq!(config.resolve("main").host_name, "main.example.com");
+    }
+}
@@ -0,0 +1,446 @@
+// Conversion between the bytes a remote shell or local PTY speaks and what the frontend
+// sees: output is decoded to UTF-8 (or passed through as base64), keystrokes are encoded
+// back to the connection's charset.
+
+use base64::{Engine as _, engine::general_purpose};
+use encoding_rs::{Encoding, EncoderResult, UTF_8};
+use serde::{Deserialize, Serialize};
+
+/// How terminal output is carried in `pty-output` events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// UTF-8 text; invalid bytes become U+FFFD
+    #[default]
+    Text,
+    /// The raw bytes, base64 encoded, for frontends that decode themselves
+    Base64,
+}
+
+impl OutputFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputFormat::Text => "text",
+            OutputFormat::Base64 => "base64",
+        }
+    }
+}
+
+/// Resolve a connection's `encoding` option. Accepts the usual labels and aliases
+/// ("gbk", "shift_jis", "euc-kr", "latin1", ...); None or empty means UTF-8.
+///
+/// Labels follow the WHATWG Encoding Standard, so "iso-8859-1" / "latin1" decode as
+/// windows-1252 (identical except for the rarely used C1 control range).
+pub fn charset(label: Option<&str>) -> Result<&'static Encoding, String> {
+    let Some(label) = label.map(str::trim).filter(|label| !label.is_empty()) else {
+        return Ok(UTF_8);
+    };
+    let charset = Encoding::for_label(label.as_bytes())
+        .ok_or_else(|| format!("Unknown encoding: {}", label))?;
+    // UTF-16 and the "replacement" encoding cannot be used to encode keystrokes
+    if charset.output_encoding() != charset {
+        return Err(format!("Encoding {} is not supported for terminals", charset.name()));
+    }
+    Ok(charset)
+}
+
+/// Convert text typed or pasted in the frontend to the connection's charset. Characters
+/// the charset cannot represent are sent as '?'.
+pub fn encode_input(charset: &'static Encoding, text: &str) -> Vec<u8> {
+    if charset == UTF_8 {
+        return text.as_bytes().to_vec();
+    }
+
+    let mut encoder = charset.new_encoder();
+    let mut bytes = Vec::new();
+    let mut rest = text;
+    loop {
+        let needed = encoder.max_buffer_length_from_utf8_without_replacement(rest.len())
+            .unwrap_or(rest.len() * 4);
+        bytes.reserve(needed);
+        let (result, read) = encoder.encode_from_utf8_to_vec_without_replacement(rest, &mut bytes, true);
+        rest = &rest[read..];
+        match result {
+            EncoderResult::InputEmpty => return bytes,
+            EncoderResult::Unmappable(_) => bytes.push(b'?'),
+            EncoderResult::OutputFull => {}
+        }
+    }
+}
+
+/// UTF-8 decoder for a byte stream that arrives in arbitrary chunks.
+///
+/// A sequence cut off at the end of a chunk is held back and completed by the next one,
+/// instead of turning into replacement characters on both sides of the cut.
+#[derive(Debug, Default)]
+pub struct Utf8StreamDecoder {
+    // Start of an incomplete sequence, at most 3 bytes
+    partial: Vec<u8>,
+}
+
+impl Utf8StreamDecoder {
+    pub fn decode(&mut self, input: &[u8]) -> String {
+        let joined;
+        let mut rest = if self.partial.is_empty() {
+            input
+        } else {
+            joined = [std::mem::take(&mut self.partial).as_slice(), input].concat();
+            &joined[..]
+        };
+
+        let mut text = String::with_capacity(rest.len());
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    return text;
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    // Cannot fail: the prefix was just validated
+                    text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match e.error_len() {
+                        // Invalid, same replacement as String::from_utf8_lossy
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[len..];
+                        }
+                        // Valid so far but cut off by the end of the chunk
+                        None => {
+                            self.partial = after.to_vec();
+                            return text;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// End of stream: an incomplete sequence left over becomes a replacement character
+    pub fn finish(&mut self) -> String {
+        let partial = std::mem::take(&mut self.partial);
+        String::from_utf8_lossy(&partial).into_owned()
+    }
+}
+
+enum StreamDecoder {
+    Utf8(Utf8StreamDecoder),
+    // encoding_rs decoders carry split sequences over between calls themselves
+    Charset(encoding_rs::Decoder),
+}
+
+impl StreamDecoder {
+    fn decode(&mut self, input: &[u8], last: bool) -> String {
+        match self {
+            StreamDecoder::Utf8(decoder) if last => {
+                let mut text = decoder.decode(input);
+                text.push_str(&decoder.finish());
+                text
+            }
+            StreamDecoder::Utf8(decoder) => decoder.decode(input),
+            StreamDecoder::Charset(decoder) => {
+                let capacity = decoder.max_utf8_buffer_length(input.len()).unwrap_or(input.len() * 3 + 16);
+                let mut text = String::with_capacity(capacity);
+                let _ = decoder.decode_to_string(input, &mut text, last);
+                if last {
+                    // A decoder is done after its last call; start over for later input
+                    *decoder = decoder.encoding().new_decoder_without_bom_handling();
+                }
+                text
+            }
+        }
+    }
+}
+
+/// Turns one session's output chunks into `pty-output` payload data
+pub struct OutputEncoder {
+    format: OutputFormat,
+    charset: &'static Encoding,
+    decoder: StreamDecoder,
+}
+
+impl OutputEncoder {
+    pub fn new(format: OutputFormat, charset: &'static Encoding) -> Self {
+        let decoder = if charset == UTF_8 {
+            StreamDecoder::Utf8(Utf8StreamDecoder::default())
+        } else {
+            StreamDecoder::Charset(charset.new_decoder_without_bom_handling())
+        };
+        OutputEncoder { format, charset, decoder }
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    pub fn encode(&mut self, data: &[u8]) -> String {
+        self.convert(data, false)
+    }
+
+    /// Whatever is still held back at the end of the stream
+    pub fn finish(&mut self) -> String {
+        self.convert(&[], true)
+    }
+
+    fn convert(&mut self, data: &[u8], last: bool) -> String {
+        match self.format {
+            OutputFormat::Text => self.decoder.decode(data, last),
+            // UTF-8 passes through untouched; anything else is transcoded first
+            OutputFormat::Base64 if self.charset == UTF_8 => general_purpose::STANDARD.encode(data),
+            OutputFormat::Base64 => {
+                let text = self.decoder.decode(data, last);
+                if text.is_empty() { String::new() } else { general_purpose::STANDARD.encode(text) }
+            }
+        }
+    }
+}
+
+/// Removes escape sequences and control characters other than \n, \r and \t from a
+/// stream of terminal text. Keeps state between chunks, so a sequence split across reads
+/// is still recognised.
+#[derive(Debug, Default)]
+pub struct AnsiStripper {
+    state: StripState,
+    // Payload of the OSC string being read, when OSC strings are kept
+    osc: Option<String>,
+}
+
+// OSC strings longer than this are cut; the ones worth keeping are short
+const MAX_OSC_BYTES: usize = 4096;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum StripState {
+    #[default]
+    Text,
+    Escape,
+    /// ESC followed by intermediate bytes, e.g. the charset designation ESC ( B
+    EscapeIntermediate,
+    Csi,
+    /// OSC, DCS, APC, PM and SOS strings, ended by BEL or ST
+    String,
+    StringEscape,
+}
+
+impl AnsiStripper {
+    pub fn strip(&mut self, text: &str) -> String {
+        self.strip_inner(text, None)
+    }
+
+    /// Like `strip`, also returning the payload of every OSC string (between ESC ] and its
+    /// terminator) with the byte offset in the plain text it appeared at
+    pub fn strip_with_osc(&mut self, text: &str) -> (String, Vec<(usize, String)>) {
+        let mut osc = Vec::new();
+        let plain = self.strip_inner(text, Some(&mut osc));
+        (plain, osc)
+    }
+
+    fn strip_inner(&mut self, text: &str, mut osc: Option<&mut Vec<(usize, String)>>) -> String {
+        let mut plain = String::with_capacity(text.len());
+        for c in text.chars() {
+            if let Some(payload) = &mut self.osc {
+                if self.state == StripState::String && !matches!(c, '\x07' | '\x1b') && payload.len() < MAX_OSC_BYTES {
+                    payload.push(c);
+                }
+            }
+            let previous = self.state;
+            self.state = match (self.state, c) {
+                (StripState::Text, '\x1b') => StripState::Escape,
+                (StripState::Text, '\u{9b}') => StripState::Csi,
+                (StripState::Text, '\n' | '\r' | '\t') => {
+                    plain.push(c);
+                    StripState::Text
+                }
+                (StripState::Text, c) if c.is_control() => StripState::Text,
+                (StripState::Text, c) => {
+                    plain.push(c);
+                    StripState::Text
+                }
+                (StripState::Escape, '[') => StripState::Csi,
+                (StripState::Escape, ']' | 'P' | '_' | '^' | 'X') => StripState::String,
+                (StripState::Escape | StripState::EscapeIntermediate, ' '..='/') => StripState::EscapeIntermediate,
+                (StripState::Escape | StripState::EscapeIntermediate, _) => StripState::Text,
+                (StripState::Csi, '@'..='~') => StripState::Text,
+                (StripState::Csi, _) => StripState::Csi,
+                (StripState::String, '\x07') => StripState::Text,
+                (StripState::String, '\x1b') => StripState::StringEscape,
+                (StripState::String, _) => StripState::String,
+                (StripState::StringEscape, '\\') => StripState::Text,
+                (StripState::StringEscape, _) => StripState::String,
+            };
+            match (previous, self.state) {
+                (StripState::Escape, StripState::String) if c == ']' && osc.is_some() => self.osc = Some(String::new()),
+                (StripState::String | StripState::StringEscape, StripState::Text) => {
+                    if let (Some(payload), Some(osc)) = (self.osc.take(), osc.as_deref_mut()) {
+                        osc.push((plain.len(), payload));
+                    }
+                }
+                _ => {}
+            }
+        }
+        plain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "ls -la\r\nمرحبا بالعالم 你好，世界 こんにちは 🦀 ok\r\n";
+
+    fn decode_chunks(chunks: &[&[u8]]) -> String {
+        let mut decoder = Utf8StreamDecoder::default();
+        let mut text: String = chunks.iter().map(|chunk| decoder.decode(chunk)).collect();
+        text.push_str(&decoder.finish());
+        text
+    }
+
+    #[test]
+    fn test_split_at_every_offset() {
+        let bytes = SAMPLE.as_bytes();
+        for at in 0..=bytes.len() {
+            let (first, second) = bytes.split_at(at);
+            assert_eq!(decode_chunks(&[first, second]), SAMPLE, "split at byte {}", at);
+        }
+    }
+
+    #[test]
+    fn test_split_twice_at_every_offset_pair() {
+        let bytes = "a你🦀b".as_bytes();
+        for i in 0..=bytes.len() {
+            for j in i..=bytes.len() {
+                let chunks = [&bytes[..i], &bytes[i..j], &bytes[j..]];
+                assert_eq!(decode_chunks(&chunks), "a你🦀b", "split at bytes {} and {}", i, j);
+            }
+        }
+    }
+
+    #[test]
+    fn test_byte_at_a_time() {
+        let chunks: Vec<&[u8]> = SAMPLE.as_bytes().chunks(1).collect();
+        assert_eq!(decode_chunks(&chunks), SAMPLE);
+    }
+
+    #[test]
+    fn test_invalid_bytes_match_lossy_conversion() {
+        let inputs: [&[u8]; 4] = [
+            b"bad \xff byte",
+            b"\xe4\xbd lone lead bytes \xf0\x9f",
+            b"overlong \xc0\xaf and surrogate \xed\xa0\x80",
+            b"\x80\x80 continuation bytes",
+        ];
+        for input in inputs {
+            let expected = String::from_utf8_lossy(input);
+            for at in 0..=input.len() {
+                let (first, second) = input.split_at(at);
+                assert_eq!(decode_chunks(&[first, second]), expected, "{:?} split at byte {}", input, at);
+            }
+        }
+    }
+
+    #[test]
+    fn test_incomplete_tail_is_held_back_until_finish() {
+        let mut decoder = Utf8StreamDecoder::default();
+        assert_eq!(decoder.decode(b"ok \xe4\xbd"), "ok ");
+        assert_eq!(decoder.finish(), "\u{FFFD}");
+        assert_eq!(decoder.finish(), "");
+    }
+
+    #[test]
+    fn test_base64_format_passes_raw_bytes() {
+        let mut encoder = OutputEncoder::new(OutputFormat::Base64, UTF_8);
+        assert_eq!(encoder.encode(b"\xe4\xbd"), "5L0=");
+        assert_eq!(encoder.encode(b"\xa0"), "oA==");
+        assert_eq!(encoder.finish(), "");
+        assert_eq!(serde_json::from_str::<OutputFormat>("\"base64\"").unwrap(), OutputFormat::Base64);
+    }
+
+    #[test]
+    fn test_charset_labels() {
+        assert_eq!(charset(None).unwrap(), UTF_8);
+        assert_eq!(charset(Some(" ")).unwrap(), UTF_8);
+        assert_eq!(charset(Some("GBK")).unwrap(), encoding_rs::GBK);
+        assert_eq!(charset(Some("sjis")).unwrap(), encoding_rs::SHIFT_JIS);
+        assert_eq!(charset(Some("latin1")).unwrap(), encoding_rs::WINDOWS_1252);
+        assert!(charset(Some("utf-16le")).is_err());
+        assert_eq!(charset(Some("klingon")).unwrap_err(), "Unknown encoding: klingon");
+    }
+
+    #[test]
+    fn test_legacy_output_split_at_every_offset() {
+        let cases = [
+            (encoding_rs::GBK, "你好，世界 ls\r\n"),
+            (encoding_rs::SHIFT_JIS, "こんにちは ﾃｽﾄ\r\n"),
+            (encoding_rs::WINDOWS_1252, "café über\r\n"),
+        ];
+        for (charset, sample) in cases {
+            let (bytes, _, unmappable) = charset.encode(sample);
+            assert!(!unmappable);
+            for at in 0..=bytes.len() {
+                let mut encoder = OutputEncoder::new(OutputFormat::Text, charset);
+                let (first, second) = bytes.split_at(at);
+                let text = encoder.encode(first) + encoder.encode(second).as_str() + encoder.finish().as_str();
+                assert_eq!(text, sample, "{} split at byte {}", charset.name(), at);
+            }
+        }
+    }
+
+    #[test]
+    fn test_legacy_output_in_base64_is_transcoded() {
+        let mut encoder = OutputEncoder::new(OutputFormat::Base64, encoding_rs::GBK);
+        // "你" is C4 E3 in GBK
+        assert_eq!(encoder.encode(b"\xc4"), "");
+        assert_eq!(encoder.encode(b"\xe3"), general_purpose::STANDARD.encode("你"));
+    }
+
+    #[test]
+    fn test_encode_input() {
+        assert_eq!(encode_input(UTF_8, "你好"), "你好".as_bytes());
+        assert_eq!(encode_input(encoding_rs::GBK, "echo 你好"), b"echo \xc4\xe3\xba\xc3");
+        assert_eq!(encode_input(encoding_rs::SHIFT_JIS, "ｱ"), b"\xb1");
+        // Not representable in windows-1252
+        assert_eq!(encode_input(encoding_rs::WINDOWS_1252, "é你!"), b"\xe9?!");
+        let long = "中".repeat(10_000);
+        assert_eq!(encode_input(encoding_rs::GBK, &long).len(), 20_000);
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        let mut stripper = AnsiStripper::default();
+        let text = "\x1b[1;32muser@host\x1b[0m:\x1b[34m~\x1b[0m$ ls\r\n\x1b]0;title\x07a\tb\x1b(B\x1b=\x08\n";
+        assert_eq!(stripper.strip(text), "user@host:~$ ls\r\na\tb\n");
+    }
+
+    #[test]
+    fn test_strip_ansi_split_at_every_offset() {
+        let text = "\x1b[38;5;208mwarn\x1b[0m \x1b]8;;http://x\x1b\\link\x1b]8;;\x1b\\ ok\r\n";
+        let expected = AnsiStripper::default().strip(text);
+        assert_eq!(expected, "warn link ok\r\n");
+        for at in 0..=text.len() {
+            if !text.is_char_boundary(at) {
+                continue;
+            }
+            let mut stripper = AnsiStripper::default();
+            let plain = stripper.strip(&text[..at]) + stripper.strip(&text[at..]).as_str();
+            assert_eq!(plain, expected, "split at {}", at);
+        }
+    }
+
+    #[test]
+    fn test_strip_keeps_osc_payloads() {
+        let text = "\x1b]133;A\x07$ \x1b]133;B\x1b\\ls\r\n\x1b]7;file://web1/tmp\x07\x1b[0m";
+        let (plain, osc) = AnsiStripper::default().strip_with_osc(text);
+        assert_eq!(plain, "$ ls\r\n");
+        assert_eq!(osc, vec![
+            (0, "133;A".to_string()),
+            (2, "133;B".to_string()),
+            (6, "7;file://web1/tmp".to_string()),
+        ]);
+
+        // Split inside the payload
+        let mut stripper = AnsiStripper::default();
+        let (_, first) = stripper.strip_with_osc("x\x1b]133;");
+        let (_, second) = stripper.strip_with_osc("D;1\x07y");
+        assert!(first.is_empty());
+        assert_eq!(second, vec![(0, "133;D;1".to_string())]);
+    }
+}
@@ -0,0 +1,269 @@
+use serde::{Deserialize, Serialize};
+use ssh2::Session;
+use std::io::ErrorKind;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+/// Per-connection timeouts and keepalive settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionTimeouts {
+    /// TCP connect, per resolved address
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// Applied to every libssh2 call during handshake and authentication
+    #[serde(default = "default_auth_timeout_secs")]
+    pub auth_timeout_secs: u64,
+    /// 0 disables keepalives and dead-peer detection
+    #[serde(default = "default_keepalive_interval_secs")]
+    pub keepalive_interval_secs: u64,
+    /// Unanswered keepalives before the session is declared dead (like ServerAliveCountMax)
+    #[serde(default = "default_keepalive_max_missed")]
+    pub keepalive_max_missed: u32,
+}
+
+impl Default for ConnectionTimeouts {
+    fn default() -> Self {
+        ConnectionTimeouts {
+            connect_timeout_secs: default_connect_timeout_secs(),
+            auth_timeout_secs: default_auth_timeout_secs(),
+            keepalive_interval_secs: default_keepalive_interval_secs(),
+            keepalive_max_missed: default_keepalive_max_missed(),
+        }
+    }
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    15
+}
+
+fn default_auth_timeout_secs() -> u64 {
+    30
+}
+
+fn default_keepalive_interval_secs() -> u64 {
+    60
+}
+
+fn default_keepalive_max_missed() -> u32 {
+    3
+}
+
+impl ConnectionTimeouts {
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs.max(1))
+    }
+
+    /// Bound the blocking handshake/auth calls that follow
+    pub fn start_auth(&self, sess: &Session) {
+        sess.set_timeout((self.auth_timeout_secs.saturating_mul(1000)).min(u32::MAX as u64) as u32);
+    }
+
+    /// Lift the auth timeout again (long transfers and commands must not trip it) and
+    /// enable keepalives
+    pub fn finish_auth(&self, sess: &Session) {
+        sess.set_timeout(0);
+        if self.keepalive_interval_secs > 0 {
+            // want_reply, so the server answers and the answer shows up on the socket
+            sess.set_keepalive(true, self.keepalive_interval_secs.min(u32::MAX as u64) as u32);
+        }
+    }
+}
+
+/// TcpStream::connect with a timeout, trying every address the host resolves to
+pub fn connect_tcp(host: &str, port: u16, timeout: Duration) -> Result<TcpStream, String> {
+    let addrs = (host, port).to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve {}: {}", host, e))?;
+
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(match last_error {
+        Some(e) if e.kind() == ErrorKind::TimedOut => format!(
+            "Failed to connect to {}:{} - timed out after {}s", host, port, timeout.as_secs()
+        ),
+        Some(e) => format!("Failed to connect to {}:{} - {}", host, port, e),
+        None => format!("Failed to resolve {}: no addresses", host),
+    })
+}
+
+/// What the keepalive monitor wants done on this tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeepaliveAction {
+    Idle,
+    Send,
+    /// Number of keepalives that went unanswered
+    Dead(u32),
+}
+
+/// Dead-peer detection for an interactive session.
+///
+/// libssh2 swallows keepalive replies, usually before anyone else sees them on the socket, so
+/// the reader reports whether the connection was readable since the last tick. Any inbound
+/// bytes count as a sign of life; a peek at a clone of the socket adds bytes libssh2 has not
+/// read yet and notices a closed connection. A keepalive is sent after each quiet interval;
+/// `max_missed` keepalives without a byte in return mean the peer is gone.
+pub struct KeepaliveMonitor {
+    socket: TcpStream,
+    interval: Duration,
+    max_missed: u32,
+    last_inbound: Instant,
+    last_sent: Option<Instant>,
+    missed: u32,
+}
+
+impl KeepaliveMonitor {
+    /// None when keepalives are disabled or the socket cannot be cloned
+    pub fn new(socket: &TcpStream, timeouts: &ConnectionTimeouts) -> Option<Self> {
+        if timeouts.keepalive_interval_secs == 0 {
+            return None;
+        }
+        let socket = socket.try_clone().ok()?;
+        // libssh2 already made the shared socket non-blocking; make sure peek never waits
+        socket.set_nonblocking(true).ok()?;
+
+        Some(KeepaliveMonitor {
+            socket,
+            interval: Duration::from_secs(timeouts.keepalive_interval_secs),
+            max_missed: timeouts.keepalive_max_missed.max(1),
+            last_inbound: Instant::now(),
+            last_sent: None,
+            missed: 0,
+        })
+    }
+
+    /// Call regularly from the session's reader, with whether anything arrived since the
+    /// last call; Err means the session is dead
+    pub fn tick(&mut self, sess: &Session, inbound: bool) -> Result<(), String> {
+        let mut byte = [0u8; 1];
+        let inbound = match self.socket.peek(&mut byte) {
+            Ok(0) => return Err("Server closed the connection".to_string()),
+            Ok(_) => true,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => inbound,
+            Err(e) => return Err(format!("Connection error: {}", e)),
+        };
+
+        match self.poll(Instant::now(), inbound) {
+            KeepaliveAction::Idle => Ok(()),
+            KeepaliveAction::Send => match sess.keepalive_send() {
+                Ok(_) => Ok(()),
+                Err(ref e) if crate::tunnel::is_would_block(e) => Ok(()),
+                Err(e) => Err(format!("Failed to send keepalive: {}", e)),
+            },
+            KeepaliveAction::Dead(missed) => Err(format!(
+                "No response from server after {} keepalives ({}s apart)",
+                missed, self.interval.as_secs()
+            )),
+        }
+    }
+
+    fn poll(&mut self, now: Instant, inbound: bool) -> KeepaliveAction {
+        if inbound {
+            self.last_inbound = now;
+            self.missed = 0;
+        }
+
+        let quiet_since = self.last_sent.map_or(self.last_inbound, |sent| sent.max(self.last_inbound));
+        if now.duration_since(quiet_since) < self.interval {
+            return KeepaliveAction::Idle;
+        }
+
+        // The previous keepalive went out after the last inbound byte and got no answer
+        if self.last_sent.is_some_and(|sent| sent >= self.last_inbound) {
+            self.missed += 1;
+            if self.missed >= self.max_missed {
+                return KeepaliveAction::Dead(self.missed);
+            }
+        }
+        self.last_sent = Some(now);
+        KeepaliveAction::Send
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn monitor(interval_secs: u64, max_missed: u32) -> (KeepaliveMonitor, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let timeouts = ConnectionTimeouts {
+            keepalive_interval_secs: interval_secs,
+            keepalive_max_missed: max_missed,
+            ..Default::default()
+        };
+        (KeepaliveMonitor::new(&client, &timeouts).unwrap(), server)
+    }
+
+    #[test]
+    fn test_timeouts_defaults() {
+        let timeouts: ConnectionTimeouts = serde_json::from_str("{}").unwrap();
+        assert_eq!(timeouts.connect_timeout_secs, 15);
+        assert_eq!(timeouts.keepalive_interval_secs, 60);
+        assert_eq!(timeouts.keepalive_max_missed, 3);
+    }
+
+    #[test]
+    fn test_connect_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(connect_tcp("127.0.0.1", port, Duration::from_secs(1)).is_ok());
+
+        drop(listener);
+        let err = connect_tcp("127.0.0.1", port, Duration::from_secs(1)).unwrap_err();
+        assert!(err.contains(&format!("127.0.0.1:{}", port)));
+    }
+
+    #[test]
+    fn test_keepalive_disabled() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let timeouts = ConnectionTimeouts { keepalive_interval_secs: 0, ..Default::default() };
+        assert!(KeepaliveMonitor::new(&client, &timeouts).is_none());
+    }
+
+    #[test]
+    fn test_unanswered_keepalives_declare_peer_dead() {
+        let (mut monitor, _server) = monitor(10, 3);
+        let start = monitor.last_inbound;
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(monitor.poll(at(5), false), KeepaliveAction::Idle);
+        assert_eq!(monitor.poll(at(10), false), KeepaliveAction::Send);
+        assert_eq!(monitor.poll(at(15), false), KeepaliveAction::Idle);
+        assert_eq!(monitor.poll(at(20), false), KeepaliveAction::Send);
+        assert_eq!(monitor.poll(at(30), false), KeepaliveAction::Send);
+        assert_eq!(monitor.poll(at(40), false), KeepaliveAction::Dead(3));
+    }
+
+    #[test]
+    fn test_inbound_traffic_resets_missed_keepalives() {
+        let (mut monitor, _server) = monitor(10, 2);
+        let start = monitor.last_inbound;
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(monitor.poll(at(10), false), KeepaliveAction::Send);
+        assert_eq!(monitor.poll(at(20), false), KeepaliveAction::Send);
+        // The server answers
+        assert_eq!(monitor.poll(at(21), true), KeepaliveAction::Idle);
+        assert_eq!(monitor.poll(at(31), false), KeepaliveAction::Send);
+        assert_eq!(monitor.poll(at(41), false), KeepaliveAction::Send);
+        assert_eq!(monitor.poll(at(51), false), KeepaliveAction::Dead(2));
+    }
+
+    #[test]
+    fn test_tick_detects_closed_socket() {
+        let (mut monitor, server) = monitor(10, 3);
+        drop(server);
+        // Give the FIN a moment to arrive
+        std::thread::sleep(Duration::from_millis(50));
+        let sess = Session::new().unwrap();
+        assert_eq!(monitor.tick(&sess, false).unwrap_err(), "Server closed the connection");
+    }
+}
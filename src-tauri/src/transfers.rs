@@ -0,0 +1,658 @@
+use crate::{exec, tunnel};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use ssh2::{OpenFlags, OpenType, Session, Sftp};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tauri::Window;
+
+// Transfers beyond this many wait in the queue
+const MAX_ACTIVE_TRANSFERS: usize = 3;
+// Reconnect attempts after a dropped connection before a transfer is marked failed
+const MAX_RECONNECT_ATTEMPTS: u32 = 3;
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(2);
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+const CHUNK_SIZE: usize = 32768;
+// Hashing a multi-gigabyte file on the server takes a while
+const CHECKSUM_TIMEOUT_SECS: u64 = 600;
+
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
+
+static MANAGER: Lazy<Mutex<Manager>> = Lazy::new(|| Mutex::new(Manager::default()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferDirection {
+    Upload,
+    Download,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferProtocol {
+    #[default]
+    Sftp,
+    /// No resume support; a retry starts the file over
+    Scp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferRequest {
+    pub direction: TransferDirection,
+    pub local_path: String,
+    pub remote_path: String,
+    #[serde(default)]
+    pub protocol: TransferProtocol,
+    /// Continue from the bytes already present at the destination
+    #[serde(default)]
+    pub resume: bool,
+    /// Compare SHA-256 of both sides once the copy is done (needs `sha256sum` on the server)
+    #[serde(default)]
+    pub verify: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferInfo {
+    pub id: String,
+    pub session_id: String,
+    pub direction: TransferDirection,
+    pub protocol: TransferProtocol,
+    pub local_path: String,
+    pub remote_path: String,
+    pub status: TransferStatus,
+    pub bytes_transferred: u64,
+    pub total_bytes: Option<u64>,
+    /// Bytes per second over the current run
+    pub rate: f64,
+    pub eta_secs: Option<u64>,
+    pub error: Option<String>,
+    /// Hex SHA-256 of the file, set when verification ran
+    pub checksum: Option<String>,
+}
+
+/// Where a transfer gets its SSH connection from.
+///
+/// `live` is the interactive session, used for the first attempt; `reconnect` opens a fresh
+/// session with the same credentials whenever the connection drops mid-transfer.
+pub struct SessionSource {
+    pub live: Option<Session>,
+    pub reconnect: Box<dyn Fn() -> Result<Session, String> + Send + Sync>,
+}
+
+struct Transfer {
+    request: Mutex<TransferRequest>,
+    info: Mutex<TransferInfo>,
+    source: Mutex<Option<SessionSource>>,
+    cancel: AtomicBool,
+    window: Window,
+}
+
+#[derive(Default)]
+struct Manager {
+    transfers: HashMap<String, Arc<Transfer>>,
+    queue: VecDeque<String>,
+    active: usize,
+}
+
+/// Why a copy attempt stopped early
+enum Interrupt {
+    Cancelled,
+    /// Retrying cannot help (local I/O, missing remote file, ...)
+    Fatal(String),
+    /// The SSH connection misbehaved; worth reconnecting and resuming
+    Connection(String),
+}
+
+impl Transfer {
+    fn snapshot(&self) -> TransferInfo {
+        self.info.lock().clone()
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    fn emit_progress(&self) {
+        let info = self.snapshot();
+        let _ = self.window.emit("transfer-progress", serde_json::json!({
+            "transfer_id": info.id,
+            "session_id": info.session_id,
+            "bytes": info.bytes_transferred,
+            "total": info.total_bytes,
+            "rate": info.rate,
+            "eta_secs": info.eta_secs,
+            "status": info.status
+        }));
+    }
+
+    fn emit_finished(&self) {
+        let info = self.snapshot();
+        let _ = self.window.emit("transfer-finished", serde_json::json!({
+            "transfer_id": info.id,
+            "session_id": info.session_id,
+            "status": info.status,
+            "error": info.error,
+            "checksum": info.checksum
+        }));
+    }
+}
+
+/// Tracks throughput of one run and throttles progress events
+struct ProgressMeter {
+    started: Instant,
+    start_bytes: u64,
+    last_emit: Option<Instant>,
+}
+
+impl ProgressMeter {
+    fn new(start_bytes: u64) -> Self {
+        ProgressMeter { started: Instant::now(), start_bytes, last_emit: None }
+    }
+
+    fn update(&mut self, transfer: &Transfer, bytes: u64) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { bytes.saturating_sub(self.start_bytes) as f64 / elapsed } else { 0.0 };
+
+        {
+            let mut info = transfer.info.lock();
+            info.bytes_transferred = bytes;
+            info.rate = rate;
+            info.eta_secs = info.total_bytes.and_then(|total| estimate_eta(total.saturating_sub(bytes), rate));
+        }
+
+        if self.last_emit.is_none_or(|t| t.elapsed() >= PROGRESS_INTERVAL) {
+            self.last_emit = Some(Instant::now());
+            transfer.emit_progress();
+        }
+    }
+}
+
+/// Seconds left at the given rate, or None while the rate is unknown
+fn estimate_eta(remaining: u64, rate: f64) -> Option<u64> {
+    if remaining == 0 {
+        return Some(0);
+    }
+    if rate <= 0.0 {
+        return None;
+    }
+    Some((remaining as f64 / rate).ceil() as u64)
+}
+
+/// Queue a transfer; it starts as soon as a slot is free
+pub fn enqueue(session_id: &str, request: TransferRequest, source: SessionSource, window: Window) -> TransferInfo {
+    let id = format!("xfer-{}", NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed));
+    let info = TransferInfo {
+        id: id.clone(),
+        session_id: session_id.to_string(),
+        direction: request.direction,
+        protocol: request.protocol,
+        local_path: request.local_path.clone(),
+        remote_path: request.remote_path.clone(),
+        status: TransferStatus::Queued,
+        bytes_transferred: 0,
+        total_bytes: None,
+        rate: 0.0,
+        eta_secs: None,
+        error: None,
+        checksum: None,
+    };
+
+    let transfer = Arc::new(Transfer {
+        request: Mutex::new(request),
+        info: Mutex::new(info.clone()),
+        source: Mutex::new(Some(source)),
+        cancel: AtomicBool::new(false),
+        window,
+    });
+
+    {
+        let mut manager = MANAGER.lock();
+        manager.transfers.insert(id.clone(), transfer.clone());
+        manager.queue.push_back(id);
+    }
+    transfer.emit_progress();
+    pump_queue();
+    info
+}
+
+/// All transfers (optionally only those of one session), oldest first
+pub fn list(session_id: Option<&str>) -> Vec<TransferInfo> {
+    let mut transfers: Vec<TransferInfo> = MANAGER.lock().transfers.values()
+        .map(|t| t.snapshot())
+        .filter(|info| session_id.is_none_or(|id| info.session_id == id))
+        .collect();
+    transfers.sort_by_key(|info| transfer_number(&info.id));
+    transfers
+}
+
+fn transfer_number(id: &str) -> u64 {
+    id.trim_start_matches("xfer-").parse().unwrap_or(0)
+}
+
+pub fn cancel(transfer_id: &str) -> Result<(), String> {
+    let transfer = {
+        let mut manager = MANAGER.lock();
+        let transfer = manager.transfers.get(transfer_id).cloned()
+            .ok_or_else(|| format!("Transfer {} not found", transfer_id))?;
+        if !matches!(transfer.info.lock().status, TransferStatus::Queued | TransferStatus::Running) {
+            return Err(format!("Transfer {} has already finished", transfer_id));
+        }
+        let was_queued = manager.queue.iter().any(|id| id == transfer_id);
+        manager.queue.retain(|id| id != transfer_id);
+        transfer.cancel.store(true, Ordering::Relaxed);
+        if !was_queued {
+            // A running transfer notices the flag and reports itself
+            return Ok(());
+        }
+        transfer
+    };
+
+    transfer.info.lock().status = TransferStatus::Cancelled;
+    transfer.emit_finished();
+    Ok(())
+}
+
+/// Re-queue a failed or cancelled transfer, continuing from where it stopped
+pub fn resume(transfer_id: &str, source: SessionSource) -> Result<TransferInfo, String> {
+    let transfer = MANAGER.lock().transfers.get(transfer_id).cloned()
+        .ok_or_else(|| format!("Transfer {} not found", transfer_id))?;
+
+    {
+        let mut info = transfer.info.lock();
+        match info.status {
+            TransferStatus::Failed | TransferStatus::Cancelled => {}
+            status => return Err(format!("Transfer {} is {:?} and cannot be resumed", transfer_id, status)),
+        }
+        info.status = TransferStatus::Queued;
+        info.error = None;
+        info.rate = 0.0;
+        info.eta_secs = None;
+    }
+    transfer.request.lock().resume = true;
+    *transfer.source.lock() = Some(source);
+    transfer.cancel.store(false, Ordering::Relaxed);
+
+    MANAGER.lock().queue.push_back(transfer_id.to_string());
+    transfer.emit_progress();
+    pump_queue();
+    Ok(transfer.snapshot())
+}
+
+/// Cancel everything belonging to a session (used when the session disconnects)
+pub fn cancel_for_session(session_id: &str) {
+    let ids: Vec<String> = MANAGER.lock().transfers.values()
+        .map(|t| t.snapshot())
+        .filter(|info| info.session_id == session_id
+            && matches!(info.status, TransferStatus::Queued | TransferStatus::Running))
+        .map(|info| info.id)
+        .collect();
+    for id in ids {
+        let _ = cancel(&id);
+    }
+}
+
+/// Start queued transfers while there are free slots
+fn pump_queue() {
+    let mut manager = MANAGER.lock();
+    while manager.active < MAX_ACTIVE_TRANSFERS {
+        let Some(id) = manager.queue.pop_front() else { break };
+        let Some(transfer) = manager.transfers.get(&id).cloned() else { continue };
+        manager.active += 1;
+
+        thread::spawn(move || {
+            run(&transfer);
+            MANAGER.lock().active -= 1;
+            pump_queue();
+        });
+    }
+}
+
+fn run(transfer: &Transfer) {
+    transfer.info.lock().status = TransferStatus::Running;
+    transfer.emit_progress();
+
+    let result = run_with_reconnect(transfer);
+
+    // The source holds a clone of the session; drop it so a closed session can go away
+    transfer.source.lock().take();
+
+    {
+        let mut info = transfer.info.lock();
+        info.rate = 0.0;
+        info.eta_secs = None;
+        match result {
+            Ok(checksum) => {
+                info.status = TransferStatus::Completed;
+                info.eta_secs = Some(0);
+                info.checksum = checksum;
+            }
+            Err(Interrupt::Cancelled) => info.status = TransferStatus::Cancelled,
+            Err(Interrupt::Fatal(e)) | Err(Interrupt::Connection(e)) => {
+                info.status = TransferStatus::Failed;
+                info.error = Some(e);
+            }
+        }
+    }
+    transfer.emit_progress();
+    transfer.emit_finished();
+}
+
+fn run_with_reconnect(transfer: &Transfer) -> Result<Option<String>, Interrupt> {
+    let mut sess = transfer.source.lock().as_mut().and_then(|s| s.live.take());
+    let mut attempt = 0;
+
+    loop {
+        if transfer.is_cancelled() {
+            return Err(Interrupt::Cancelled);
+        }
+
+        let session = match sess.take() {
+            Some(session) => session,
+            None => reconnect(transfer)?,
+        };
+
+        // After the first attempt, always pick up from what already made it across
+        let resume = attempt > 0 || transfer.request.lock().resume;
+        match copy_and_verify(transfer, &session, resume) {
+            Ok(checksum) => return Ok(checksum),
+            Err(Interrupt::Connection(e)) if attempt < MAX_RECONNECT_ATTEMPTS => {
+                attempt += 1;
+                transfer.info.lock().error = Some(format!(
+                    "{} (reconnecting, attempt {}/{})", e, attempt, MAX_RECONNECT_ATTEMPTS
+                ));
+                transfer.emit_progress();
+                sleep_unless_cancelled(transfer, RECONNECT_BACKOFF * attempt);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn reconnect(transfer: &Transfer) -> Result<Session, Interrupt> {
+    let source = transfer.source.lock();
+    let source = source.as_ref()
+        .ok_or_else(|| Interrupt::Fatal("No connection available for this transfer".to_string()))?;
+    (source.reconnect)()
+        .map_err(|e| Interrupt::Connection(format!("Reconnect failed: {}", e)))
+}
+
+fn sleep_unless_cancelled(transfer: &Transfer, duration: Duration) {
+    let until = Instant::now() + duration;
+    while Instant::now() < until && !transfer.is_cancelled() {
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn copy_and_verify(transfer: &Transfer, sess: &Session, resume: bool) -> Result<Option<String>, Interrupt> {
+    let request = transfer.request.lock().clone();
+
+    match request.protocol {
+        TransferProtocol::Sftp => {
+            let sftp = tunnel::retry_would_block(|| sess.sftp())
+                .map_err(|e| Interrupt::Connection(format!("Failed to start SFTP subsystem: {}", e)))?;
+            match request.direction {
+                TransferDirection::Download => sftp_download(transfer, &sftp, &request, resume)?,
+                TransferDirection::Upload => sftp_upload(transfer, &sftp, &request, resume)?,
+            }
+        }
+        TransferProtocol::Scp => match request.direction {
+            TransferDirection::Download => scp_download(transfer, sess, &request)?,
+            TransferDirection::Upload => scp_upload(transfer, sess, &request)?,
+        },
+    }
+
+    if !request.verify {
+        return Ok(None);
+    }
+
+    let local = sha256_file(Path::new(&request.local_path)).map_err(Interrupt::Fatal)?;
+    let remote = remote_sha256(sess, &request.remote_path).map_err(Interrupt::Fatal)?;
+    if local != remote {
+        return Err(Interrupt::Fatal(format!(
+            "Checksum mismatch: local {} != remote {}", local, remote
+        )));
+    }
+    Ok(Some(local))
+}
+
+fn sftp_download(transfer: &Transfer, sftp: &Sftp, request: &TransferRequest, resume: bool) -> Result<(), Interrupt> {
+    let remote_path = Path::new(&request.remote_path);
+    let mut remote = tunnel::retry_would_block(|| sftp.open(remote_path))
+        .map_err(|e| Interrupt::Fatal(format!("Failed to open {}: {}", request.remote_path, e)))?;
+    let total = tunnel::retry_would_block(|| remote.stat())
+        .map_err(|e| Interrupt::Connection(format!("Failed to stat {}: {}", request.remote_path, e)))?
+        .size;
+
+    let existing = fs::metadata(&request.local_path).map(|m| m.len()).unwrap_or(0);
+    let offset = if resume && total.is_none_or(|total| existing <= total) { existing } else { 0 };
+
+    let mut local = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(offset == 0)
+        .open(&request.local_path)
+        .map_err(|e| Interrupt::Fatal(format!("Failed to open {}: {}", request.local_path, e)))?;
+    local.seek(SeekFrom::Start(offset))
+        .map_err(|e| Interrupt::Fatal(format!("Failed to seek {}: {}", request.local_path, e)))?;
+    remote.seek(SeekFrom::Start(offset))
+        .map_err(|e| Interrupt::Connection(format!("Failed to seek {}: {}", request.remote_path, e)))?;
+
+    transfer.info.lock().total_bytes = total;
+    copy(transfer, &mut remote, &mut local, offset, &request.remote_path, &request.local_path, Side::Reader)
+}
+
+fn sftp_upload(transfer: &Transfer, sftp: &Sftp, request: &TransferRequest, resume: bool) -> Result<(), Interrupt> {
+    let mut local = File::open(&request.local_path)
+        .map_err(|e| Interrupt::Fatal(format!("Failed to open {}: {}", request.local_path, e)))?;
+    let total = local.metadata()
+        .map_err(|e| Interrupt::Fatal(format!("Failed to stat {}: {}", request.local_path, e)))?
+        .len();
+
+    let remote_path = Path::new(&request.remote_path);
+    let existing = if resume {
+        tunnel::retry_would_block(|| sftp.stat(remote_path)).ok().and_then(|s| s.size).unwrap_or(0)
+    } else {
+        0
+    };
+    let offset = if existing <= total { existing } else { 0 };
+
+    let mut flags = OpenFlags::WRITE | OpenFlags::CREATE;
+    if offset == 0 {
+        flags |= OpenFlags::TRUNCATE;
+    }
+    let mut remote = tunnel::retry_would_block(|| sftp.open_mode(remote_path, flags, 0o644, OpenType::File))
+        .map_err(|e| Interrupt::Fatal(format!("Failed to open {} for writing: {}", request.remote_path, e)))?;
+    remote.seek(SeekFrom::Start(offset))
+        .map_err(|e| Interrupt::Connection(format!("Failed to seek {}: {}", request.remote_path, e)))?;
+    local.seek(SeekFrom::Start(offset))
+        .map_err(|e| Interrupt::Fatal(format!("Failed to seek {}: {}", request.local_path, e)))?;
+
+    transfer.info.lock().total_bytes = Some(total);
+    copy(transfer, &mut local, &mut remote, offset, &request.local_path, &request.remote_path, Side::Writer)?;
+
+    tunnel::retry_would_block(|| remote.fsync()).ok();
+    tunnel::retry_would_block(|| remote.close())
+        .map_err(|e| Interrupt::Connection(format!("Failed to close {}: {}", request.remote_path, e)))
+}
+
+fn scp_download(transfer: &Transfer, sess: &Session, request: &TransferRequest) -> Result<(), Interrupt> {
+    let (mut channel, stat) = tunnel::retry_would_block(|| sess.scp_recv(Path::new(&request.remote_path)))
+        .map_err(|e| Interrupt::Connection(format!("Failed to start SCP download of {}: {}", request.remote_path, e)))?;
+
+    let mut local = File::create(&request.local_path)
+        .map_err(|e| Interrupt::Fatal(format!("Failed to open {}: {}", request.local_path, e)))?;
+
+    transfer.info.lock().total_bytes = Some(stat.size());
+    // SCP appends a trailing NUL after the file data, so stop at the advertised size
+    let mut limited = (&mut channel).take(stat.size());
+    copy(transfer, &mut limited, &mut local, 0, &request.remote_path, &request.local_path, Side::Reader)?;
+
+    let _ = tunnel::retry_would_block(|| channel.close());
+    Ok(())
+}
+
+fn scp_upload(transfer: &Transfer, sess: &Session, request: &TransferRequest) -> Result<(), Interrupt> {
+    let mut local = File::open(&request.local_path)
+        .map_err(|e| Interrupt::Fatal(format!("Failed to open {}: {}", request.local_path, e)))?;
+    let total = local.metadata()
+        .map_err(|e| Interrupt::Fatal(format!("Failed to stat {}: {}", request.local_path, e)))?
+        .len();
+
+    let mut channel = tunnel::retry_would_block(|| sess.scp_send(Path::new(&request.remote_path), 0o644, total, None))
+        .map_err(|e| Interrupt::Connection(format!("Failed to start SCP upload to {}: {}", request.remote_path, e)))?;
+
+    transfer.info.lock().total_bytes = Some(total);
+    copy(transfer, &mut local, &mut channel, 0, &request.local_path, &request.remote_path, Side::Writer)?;
+
+    tunnel::retry_would_block(|| channel.send_eof())
+        .and_then(|_| tunnel::retry_would_block(|| channel.wait_eof()))
+        .and_then(|_| tunnel::retry_would_block(|| channel.close()))
+        .and_then(|_| tunnel::retry_would_block(|| channel.wait_close()))
+        .map_err(|e| Interrupt::Connection(format!("Failed to finish SCP upload to {}: {}", request.remote_path, e)))
+}
+
+/// Which end of `copy` is the SSH side, so errors there can trigger a reconnect
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Reader,
+    Writer,
+}
+
+fn copy(
+    transfer: &Transfer,
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+    offset: u64,
+    from: &str,
+    to: &str,
+    remote: Side,
+) -> Result<(), Interrupt> {
+    let classify = |side: Side, message: String| {
+        if side == remote { Interrupt::Connection(message) } else { Interrupt::Fatal(message) }
+    };
+
+    let mut meter = ProgressMeter::new(offset);
+    let mut done = offset;
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    meter.update(transfer, done);
+
+    loop {
+        if transfer.is_cancelled() {
+            return Err(Interrupt::Cancelled);
+        }
+
+        let n = tunnel::read_blocking(reader, &mut buffer)
+            .map_err(|e| classify(Side::Reader, format!("Failed to read {}: {}", from, e)))?;
+        if n == 0 {
+            break;
+        }
+        tunnel::write_all_blocking(writer, &buffer[..n])
+            .map_err(|e| classify(Side::Writer, format!("Failed to write {}: {}", to, e)))?;
+
+        done += n as u64;
+        meter.update(transfer, done);
+    }
+
+    if let Some(total) = transfer.info.lock().total_bytes {
+        if done < total {
+            return Err(classify(remote, format!("Transfer ended early at {} of {} bytes", done, total)));
+        }
+    }
+    writer.flush()
+        .map_err(|e| classify(Side::Writer, format!("Failed to flush {}: {}", to, e)))
+}
+
+fn sha256_file(path: &Path) -> Result<String, String> {
+    let mut file = File::open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buffer)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn remote_sha256(sess: &Session, path: &str) -> Result<String, String> {
+    let output = exec::capture(sess, &format!("sha256sum -- {}", exec::shell_quote(path)), CHECKSUM_TIMEOUT_SECS)?;
+    parse_sha256sum(&output)
+        .ok_or_else(|| format!("Could not checksum {} on the server", path))
+}
+
+/// The digest from a `sha256sum` output line
+fn parse_sha256sum(output: &str) -> Option<String> {
+    let digest = output.split_whitespace().next()?.trim_start_matches('\\');
+    if digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(digest.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_sha256sum() {
+        let digest = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert_eq!(parse_sha256sum(&format!("{}  /tmp/empty\n", digest)).as_deref(), Some(digest));
+        assert_eq!(parse_sha256sum(&format!("\\{}  /tmp/new\\nline\n", digest)).as_deref(), Some(digest));
+        assert_eq!(parse_sha256sum("sha256sum: /tmp/missing: No such file or directory\n"), None);
+        assert_eq!(parse_sha256sum(""), None);
+    }
+
+    #[test]
+    fn test_estimate_eta() {
+        assert_eq!(estimate_eta(0, 0.0), Some(0));
+        assert_eq!(estimate_eta(1000, 0.0), None);
+        assert_eq!(estimate_eta(1000, 100.0), Some(10));
+        assert_eq!(estimate_eta(1001, 100.0), Some(11));
+    }
+
+    #[test]
+    fn test_sha256_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("hello.txt");
+        fs::write(&path, b"hello\n").unwrap();
+        assert_eq!(
+            sha256_file(&path).unwrap(),
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03"
+        );
+    }
+
+    #[test]
+    fn test_transfer_request_defaults() {
+        let request: TransferRequest = serde_json::from_str(
+            r#"{"direction":"download","local_path":"/tmp/a","remote_path":"/srv/a"}"#,
+        ).unwrap();
+        assert_eq!(request.direction, TransferDirection::Download);
+        assert_eq!(request.protocol, TransferProtocol::Sftp);
+        assert!(!request.resume);
+        assert!(!request.verify);
+    }
+}
@@ -0,0 +1,538 @@
+use crate::scrollback::MAX_LINE_BYTES;
+use crate::secure_storage;
+use crate::terminal_encoding::{AnsiStripper, OutputEncoder, OutputFormat};
+use encoding_rs::Encoding;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Key of the rules in the database's config table
+pub const SETTINGS_KEY: &str = "trigger_rules";
+
+/// Where saved rules that are not a JSON list at all are moved to when the rules are next saved
+pub const UNREADABLE_KEY: &str = "trigger_rules_unreadable";
+
+// Actions a session can set off in a burst, and how many it earns back per second. Matches
+// past that are counted as suppressed, so a flood of output cannot fire thousands of them.
+const BURST: f64 = 10.0;
+const REFILL_PER_SEC: f64 = 2.0;
+
+// An unfinished line is matched as output arrives only up to this length, so prompts fire before
+// their newline; longer ones are matched once they end, rather than again on every chunk
+const MAX_PROMPT_BYTES: usize = 1024;
+
+// Replaced as a whole on every edit, so matching works on its own copy without holding the lock
+static RULES: Lazy<Mutex<Arc<Vec<CompiledRule>>>> = Lazy::new(|| Mutex::new(Arc::new(Vec::new())));
+
+// What was saved but could not be loaded; also held while the rules are being edited
+static UNUSABLE: Lazy<Mutex<Unusable>> = Lazy::new(|| Mutex::new(Unusable::default()));
+
+// Matching state of every open session, keyed by session id
+static SESSIONS: Lazy<Mutex<HashMap<String, Arc<Mutex<SessionTriggers>>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TriggerScope {
+    #[default]
+    All,
+    /// Only sessions opened from these saved servers
+    Servers { server_ids: Vec<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TriggerAction {
+    /// Emit `trigger-highlight` so the terminal can mark the match
+    Highlight {
+        #[serde(default)]
+        color: Option<String>,
+    },
+    /// Desktop notification with the matched line; titled with the rule name when unset
+    Notify {
+        #[serde(default)]
+        title: Option<String>,
+    },
+    /// Type text into the session, e.g. an answer to a prompt
+    SendText { text: String },
+    /// Start logging the session, unless it already is
+    StartLogging,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TriggerRule {
+    pub id: String,
+    pub name: String,
+    /// Regex matched against each line of output, escape sequences removed
+    pub pattern: String,
+    #[serde(default)]
+    pub case_insensitive: bool,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub scope: TriggerScope,
+    pub actions: Vec<TriggerAction>,
+    /// The rule fires at most once per this many milliseconds in a session
+    #[serde(default = "default_cooldown_ms")]
+    pub cooldown_ms: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_cooldown_ms() -> u64 {
+    1000
+}
+
+/// A rule matching a line of a session's output
+#[derive(Debug, Clone, Serialize)]
+pub struct TriggerFire {
+    pub session_id: String,
+    pub rule_id: String,
+    pub rule_name: String,
+    /// Scrollback number of the line
+    pub line_number: u64,
+    /// The line as far as it had arrived; a prompt may not be finished by a newline
+    pub line: String,
+    /// Offsets in UTF-16 code units, like scrollback search matches
+    pub start: usize,
+    pub end: usize,
+    /// Matches of this rule in the session that rate limiting dropped since it last fired
+    pub suppressed: u64,
+    pub actions: Vec<TriggerAction>,
+}
+
+#[derive(Debug)]
+struct CompiledRule {
+    rule: TriggerRule,
+    regex: Regex,
+}
+
+impl CompiledRule {
+    fn new(rule: TriggerRule) -> Result<Self, String> {
+        if rule.id.trim().is_empty() {
+            return Err(format!("Trigger {} has no id", rule.name));
+        }
+        if rule.actions.is_empty() {
+            return Err(format!("Trigger {} has no actions", rule.name));
+        }
+        let regex = RegexBuilder::new(&rule.pattern)
+            .case_insensitive(rule.case_insensitive)
+            .build()
+            .map_err(|e| format!("Invalid pattern for trigger {}: {}", rule.name, e))?;
+        Ok(CompiledRule { rule, regex })
+    }
+
+    fn applies_to(&self, server_id: Option<&str>) -> bool {
+        self.rule.enabled && match &self.rule.scope {
+            TriggerScope::All => true,
+            TriggerScope::Servers { server_ids } => {
+                server_id.is_some_and(|id| server_ids.iter().any(|s| s == id))
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct RuleState {
+    last_fired: Option<Instant>,
+    // A rule fires once per line, even as more of the line arrives
+    matched_line: Option<u64>,
+    suppressed: u64,
+}
+
+/// Splits a session's output into lines the way its scrollback does, so line numbers agree
+struct SessionTriggers {
+    server_id: Option<String>,
+    decoder: OutputEncoder,
+    stripper: AnsiStripper,
+    line_number: u64,
+    current: String,
+    pending_cr: bool,
+    // `current` got text that was not matched yet
+    unmatched: bool,
+    tokens: f64,
+    refilled_at: Instant,
+    rules: HashMap<String, RuleState>,
+}
+
+impl SessionTriggers {
+    fn new(server_id: Option<String>, charset: &'static Encoding, now: Instant) -> Self {
+        SessionTriggers {
+            server_id,
+            decoder: OutputEncoder::new(OutputFormat::Text, charset),
+            stripper: AnsiStripper::default(),
+            line_number: 0,
+            current: String::new(),
+            pending_cr: false,
+            unmatched: false,
+            tokens: BURST,
+            refilled_at: now,
+            rules: HashMap::new(),
+        }
+    }
+
+    fn push_output(&mut self, session_id: &str, rules: &[CompiledRule], data: &[u8], now: Instant) -> Vec<TriggerFire> {
+        let elapsed = now.saturating_duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * REFILL_PER_SEC).min(BURST);
+        self.refilled_at = now;
+
+        let text = self.decoder.encode(data);
+        let plain = self.stripper.strip(&text);
+        let mut fires = Vec::new();
+        for c in plain.chars() {
+            if self.pending_cr {
+                self.pending_cr = false;
+                if c != '\n' {
+                    self.current.clear();
+                }
+            }
+            match c {
+                '\n' => self.end_line(session_id, rules, now, &mut fires),
+                '\r' => self.pending_cr = true,
+                c => {
+                    self.current.push(c);
+                    self.unmatched = true;
+                    if self.current.len() >= MAX_LINE_BYTES {
+                        self.end_line(session_id, rules, now, &mut fires);
+                    }
+                }
+            }
+        }
+        // Prompts wait on an unfinished line
+        if self.unmatched && self.current.len() <= MAX_PROMPT_BYTES {
+            self.match_line(session_id, rules, now, &mut fires);
+        }
+        fires
+    }
+
+    fn end_line(&mut self, session_id: &str, rules: &[CompiledRule], now: Instant, fires: &mut Vec<TriggerFire>) {
+        if self.unmatched {
+            self.match_line(session_id, rules, now, fires);
+        }
+        self.current.clear();
+        self.line_number += 1;
+    }
+
+    fn match_line(&mut self, session_id: &str, rules: &[CompiledRule], now: Instant, fires: &mut Vec<TriggerFire>) {
+        self.unmatched = false;
+        for compiled in rules.iter().filter(|r| r.applies_to(self.server_id.as_deref())) {
+            let state = self.rules.entry(compiled.rule.id.clone()).or_default();
+            if state.matched_line == Some(self.line_number) {
+                continue;
+            }
+            let Some(found) = compiled.regex.find(&self.current) else {
+                continue;
+            };
+            state.matched_line = Some(self.line_number);
+
+            let cooling_down = state.last_fired
+                .is_some_and(|at| now.saturating_duration_since(at) < Duration::from_millis(compiled.rule.cooldown_ms));
+            if cooling_down || self.tokens < 1.0 {
+                state.suppressed += 1;
+                continue;
+            }
+            self.tokens -= 1.0;
+            state.last_fired = Some(now);
+
+            let start = self.current[..found.start()].encode_utf16().count();
+            fires.push(TriggerFire {
+                session_id: session_id.to_string(),
+                rule_id: compiled.rule.id.clone(),
+                rule_name: compiled.rule.name.clone(),
+                line_number: self.line_number,
+                line: self.current.clone(),
+                start,
+                end: start + found.as_str().encode_utf16().count(),
+                suppressed: std::mem::take(&mut state.suppressed),
+                actions: compiled.rule.actions.clone(),
+            });
+        }
+    }
+}
+
+/// `server_id` is the saved server the session was opened from, for scoped rules
+pub fn open(session_id: &str, server_id: Option<String>, charset: &'static Encoding) {
+    let session = SessionTriggers::new(server_id, charset, Instant::now());
+    SESSIONS.lock().insert(session_id.to_string(), Arc::new(Mutex::new(session)));
+}
+
+pub fn close(session_id: &str) {
+    SESSIONS.lock().remove(session_id);
+}
+
+/// Match the rules against a chunk of output. Returns the rules that fired.
+pub fn capture_output(session_id: &str, data: &[u8]) -> Vec<TriggerFire> {
+    let Some(session) = SESSIONS.lock().get(session_id).cloned() else {
+        return Vec::new();
+    };
+    let rules = RULES.lock().clone();
+    let fires = session.lock().push_output(session_id, &rules, data, Instant::now());
+    fires
+}
+
+pub fn rules() -> Vec<TriggerRule> {
+    RULES.lock().iter().map(|compiled| compiled.rule.clone()).collect()
+}
+
+/// A saved rule left out because it no longer parses or compiles
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedRule {
+    /// None when the saved entry has no readable id
+    pub rule_id: Option<String>,
+    pub error: String,
+}
+
+/// Saved rules that could not be loaded, kept as they were so saving other rules does not
+/// delete them
+#[derive(Default)]
+struct Unusable {
+    entries: Vec<serde_json::Value>,
+    /// The whole setting, when it is not a JSON list
+    unreadable: Option<String>,
+}
+
+impl Unusable {
+    fn entry_id(entry: &serde_json::Value) -> Option<&str> {
+        entry.get("id").and_then(|id| id.as_str())
+    }
+}
+
+/// Use the rules saved in the database. Rules that cannot be used are skipped rather than
+/// failing the database, and returned for reporting; they are saved back untouched until a
+/// rule with the same id replaces them or they are deleted.
+pub fn load() -> Result<Vec<SkippedRule>, String> {
+    let json = secure_storage::with_database(|db| db.get_setting(SETTINGS_KEY))?;
+    let Some(json) = json else { return Ok(Vec::new()) };
+    let mut unusable = UNUSABLE.lock();
+    let (rules, skipped, loaded_unusable) = parse_saved(&json);
+    *unusable = loaded_unusable;
+    *RULES.lock() = Arc::new(rules);
+    Ok(skipped)
+}
+
+fn parse_saved(json: &str) -> (Vec<CompiledRule>, Vec<SkippedRule>, Unusable) {
+    let entries: Vec<serde_json::Value> = match serde_json::from_str(json) {
+        Ok(entries) => entries,
+        Err(e) => {
+            let skipped = SkippedRule { rule_id: None, error: format!("Invalid trigger rules: {}", e) };
+            let unusable = Unusable { entries: Vec::new(), unreadable: Some(json.to_string()) };
+            return (Vec::new(), vec![skipped], unusable);
+        }
+    };
+    let mut rules = Vec::new();
+    let mut skipped = Vec::new();
+    let mut unusable = Unusable::default();
+    for entry in entries {
+        let rule_id = Unusable::entry_id(&entry).map(str::to_string);
+        let compiled = serde_json::from_value::<TriggerRule>(entry.clone())
+            .map_err(|e| format!("Invalid trigger rule: {}", e))
+            .and_then(CompiledRule::new);
+        match compiled {
+            Ok(rule) => rules.push(rule),
+            Err(error) => {
+                skipped.push(SkippedRule { rule_id, error });
+                unusable.entries.push(entry);
+            }
+        }
+    }
+    (rules, skipped, unusable)
+}
+
+/// Add the rule, or replace the one with its id
+pub fn save_rule(rule: TriggerRule) -> Result<(), String> {
+    let mut unusable = UNUSABLE.lock();
+    let mut updated: Vec<TriggerRule> = RULES.lock().iter().map(|compiled| compiled.rule.clone()).collect();
+    let remaining: Vec<serde_json::Value> = unusable.entries.iter()
+        .filter(|entry| Unusable::entry_id(entry) != Some(rule.id.as_str()))
+        .cloned()
+        .collect();
+    match updated.iter_mut().find(|existing| existing.id == rule.id) {
+        Some(existing) => *existing = rule,
+        None => updated.push(rule),
+    }
+    let compiled = store(updated, &remaining, unusable.unreadable.as_deref())?;
+    unusable.entries = remaining;
+    unusable.unreadable = None;
+    *RULES.lock() = Arc::new(compiled);
+    Ok(())
+}
+
+/// Also deletes a saved rule that could not be loaded
+pub fn delete_rule(id: &str) -> Result<(), String> {
+    let mut unusable = UNUSABLE.lock();
+    let current = RULES.lock().clone();
+    let updated: Vec<TriggerRule> = current.iter()
+        .filter(|compiled| compiled.rule.id != id)
+        .map(|compiled| compiled.rule.clone())
+        .collect();
+    let remaining: Vec<serde_json::Value> = unusable.entries.iter()
+        .filter(|entry| Unusable::entry_id(entry) != Some(id))
+        .cloned()
+        .collect();
+    if updated.len() == current.len() && remaining.len() == unusable.entries.len() {
+        return Err(format!("Trigger {} not found", id));
+    }
+    let compiled = store(updated, &remaining, unusable.unreadable.as_deref())?;
+    unusable.entries = remaining;
+    unusable.unreadable = None;
+    *RULES.lock() = Arc::new(compiled);
+    Ok(())
+}
+
+// Saved only if every rule compiles. Entries that could not be loaded are written back after
+// the rules; an unreadable setting is moved aside rather than overwritten.
+fn store(rules: Vec<TriggerRule>, unusable: &[serde_json::Value], unreadable: Option<&str>) -> Result<Vec<CompiledRule>, String> {
+    let json = saved_json(&rules, unusable)?;
+    let compiled = rules.into_iter().map(CompiledRule::new).collect::<Result<Vec<_>, _>>()?;
+    secure_storage::with_database(|db| {
+        if let Some(unreadable) = unreadable {
+            db.set_setting(UNREADABLE_KEY, unreadable)?;
+        }
+        db.set_setting(SETTINGS_KEY, &json)
+    })?;
+    Ok(compiled)
+}
+
+fn saved_json(rules: &[TriggerRule], unusable: &[serde_json::Value]) -> Result<String, String> {
+    let mut entries = rules.iter()
+        .map(serde_json::to_value)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    entries.extend(unusable.iter().cloned());
+    serde_json::to_string(&entries).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::UTF_8;
+
+    fn rule(id: &str, pattern: &str, scope: TriggerScope, cooldown_ms: u64) -> CompiledRule {
+        CompiledRule::new(TriggerRule {
+            id: id.to_string(),
+            name: id.to_string(),
+            pattern: pattern.to_string(),
+            case_insensitive: true,
+            enabled: true,
+            scope,
+            actions: vec![TriggerAction::Highlight { color: None }],
+            cooldown_ms,
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_rule_validation() {
+        let mut invalid = rule("r1", "x", TriggerScope::All, 0).rule;
+        invalid.pattern = "(".to_string();
+        assert!(CompiledRule::new(invalid.clone()).unwrap_err().starts_with("Invalid pattern for trigger r1"));
+        invalid.pattern = "x".to_string();
+        invalid.actions.clear();
+        assert!(CompiledRule::new(invalid).is_err());
+
+        let json = r#"{"id":"t","name":"Sudo","pattern":"password for","actions":[{"type":"send_text","text":"x\r"}]}"#;
+        let parsed: TriggerRule = serde_json::from_str(json).unwrap();
+        assert!(parsed.enabled);
+        assert_eq!((parsed.scope, parsed.cooldown_ms), (TriggerScope::All, 1000));
+    }
+
+    #[test]
+    fn test_invalid_saved_rules_are_skipped() {
+        let json = r#"[
+            {"id":"ok","name":"Ok","pattern":"error","actions":[{"type":"highlight"}]},
+            {"id":"bad-pattern","name":"Bad","pattern":"(","actions":[{"type":"highlight"}]},
+            {"id":"bad-action","name":"Bad","pattern":"x","actions":[{"type":"no_such_action"}]},
+            {"name":"No id"}
+        ]"#;
+        let (rules, skipped, unusable) = parse_saved(json);
+        assert_eq!(rules.iter().map(|compiled| compiled.rule.id.as_str()).collect::<Vec<_>>(), vec!["ok"]);
+        let skipped_ids: Vec<Option<&str>> = skipped.iter().map(|s| s.rule_id.as_deref()).collect();
+        assert_eq!(skipped_ids, vec![Some("bad-pattern"), Some("bad-action"), None]);
+        assert!(skipped[0].error.starts_with("Invalid pattern for trigger Bad"));
+
+        // What gets saved next keeps the skipped entries as they were
+        let saved: Vec<TriggerRule> = rules.into_iter().map(|compiled| compiled.rule).collect();
+        let resaved: Vec<serde_json::Value> = serde_json::from_str(&saved_json(&saved, &unusable.entries).unwrap()).unwrap();
+        let original: Vec<serde_json::Value> = serde_json::from_str(json).unwrap();
+        assert_eq!(resaved[1..], original[1..]);
+        assert!(unusable.unreadable.is_none());
+
+        let (rules, skipped, unusable) = parse_saved("not json");
+        assert!(rules.is_empty());
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(unusable.unreadable.as_deref(), Some("not json"));
+    }
+
+    #[test]
+    fn test_matches_lines_and_prompts() {
+        let now = Instant::now();
+        let rules = [rule("err", "error", TriggerScope::All, 0), rule("pw", "password: $", TriggerScope::All, 0)];
+        let mut session = SessionTriggers::new(None, UTF_8, now);
+
+        let fires = session.push_output("t", &rules, b"ok\r\n\x1b[31mERROR\x1b[0m: disk \xc3\xa9 full\r\n[sudo] pass", now);
+        assert_eq!(fires.len(), 1);
+        assert_eq!((fires[0].rule_id.as_str(), fires[0].line_number), ("err", 1));
+        assert_eq!((fires[0].line.as_str(), fires[0].start, fires[0].end), ("ERROR: disk é full", 0, 5));
+
+        // The prompt is matched before its line ends, and only once
+        let fires = session.push_output("t", &rules, b"word: ", now);
+        assert_eq!((fires.len(), fires[0].rule_id.as_str(), fires[0].line_number), (1, "pw", 2));
+        assert!(session.push_output("t", &rules, b"\r\n", now).is_empty());
+
+        // A long unfinished line is not matched again for every chunk, only when it ends
+        assert!(session.push_output("t", &rules, &[b'x'; MAX_PROMPT_BYTES], now).is_empty());
+        assert!(session.push_output("t", &rules, b" error", now).is_empty());
+        let fires = session.push_output("t", &rules, b"\n", now);
+        assert_eq!((fires.len(), fires[0].rule_id.as_str(), fires[0].line_number), (1, "err", 3));
+    }
+
+    #[test]
+    fn test_scope() {
+        let now = Instant::now();
+        let rules = [rule("web", "x", TriggerScope::Servers { server_ids: vec!["web1".to_string()] }, 0)];
+        let mut local = SessionTriggers::new(None, UTF_8, now);
+        let mut db = SessionTriggers::new(Some("db1".to_string()), UTF_8, now);
+        let mut web = SessionTriggers::new(Some("web1".to_string()), UTF_8, now);
+        assert!(local.push_output("t", &rules, b"x\n", now).is_empty());
+        assert!(db.push_output("t", &rules, b"x\n", now).is_empty());
+        assert_eq!(web.push_output("t", &rules, b"x\n", now).len(), 1);
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let now = Instant::now();
+        let rules = [rule("slow", "slow", TriggerScope::All, 1000), rule("any", ".", TriggerScope::All, 0)];
+        let mut session = SessionTriggers::new(None, UTF_8, now);
+
+        // A flood: the cooldown holds back "slow", the burst allowance caps the rest
+        let flood = "slow\n".repeat(1000);
+        let fires = session.push_output("t", &rules, flood.as_bytes(), now);
+        assert_eq!(fires.len(), BURST as usize);
+        assert_eq!(fires.iter().filter(|f| f.rule_id == "slow").count(), 1);
+
+        // Tokens come back over time; the fire reports what was dropped meanwhile
+        let later = now + Duration::from_secs(2);
+        let fires = session.push_output("t", &rules, b"slow\n", later);
+        assert_eq!(fires.len(), 2);
+        assert_eq!(fires[0].suppressed, 999);
+        assert_eq!(fires[1].suppressed, 991);
+    }
+
+    #[test]
+    fn test_line_numbers_follow_scrollback() {
+        let now = Instant::now();
+        let rules = [rule("done", "done", TriggerScope::All, 0)];
+        let mut session = SessionTriggers::new(None, UTF_8, now);
+        crate::scrollback::open("trigger-test", UTF_8);
+        let output = b"a\r\nprogress 10%\rprogress done\r\n";
+        crate::scrollback::capture_output("trigger-test", output);
+        let fires = session.push_output("t", &rules, output, now);
+        let lines = crate::scrollback::range("trigger-test", Some(fires[0].line_number), Some(1)).unwrap().lines;
+        assert_eq!(lines, vec![fires[0].line.clone()]);
+        crate::scrollback::close("trigger-test");
+    }
+}
@@ -0,0 +1,188 @@
+use ssh2::{Channel, ErrorCode};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// libssh2's LIBSSH2_ERROR_EAGAIN, returned by non-blocking sessions
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+
+// How long a non-blocking libssh2 call is retried before giving up
+const RETRY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes moved by one iteration of `pump`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PumpProgress {
+    /// Socket -> channel
+    pub sent: usize,
+    /// Channel -> socket
+    pub received: usize,
+}
+
+/// Whether a libssh2 error just means "try again" on a non-blocking session
+pub fn is_would_block(e: &ssh2::Error) -> bool {
+    e.code() == ErrorCode::Session(LIBSSH2_ERROR_EAGAIN)
+}
+
+/// Retry a libssh2 call on a non-blocking session until it stops returning EAGAIN
+pub fn retry_would_block<T>(mut op: impl FnMut() -> Result<T, ssh2::Error>) -> Result<T, ssh2::Error> {
+    let started = Instant::now();
+    loop {
+        match op() {
+            Err(ref e) if is_would_block(e) && started.elapsed() < RETRY_TIMEOUT => {
+                thread::sleep(Duration::from_millis(1));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// `Read::read` that waits out WouldBlock from a non-blocking session, for up to
+/// `RETRY_TIMEOUT` so a stalled server cannot hang the caller forever
+pub fn read_blocking<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    read_within(reader, buf, RETRY_TIMEOUT)
+}
+
+/// `Write::write_all` that waits out WouldBlock from a non-blocking session. Fails once
+/// nothing could be written for `RETRY_TIMEOUT`.
+pub fn write_all_blocking<W: Write + ?Sized>(writer: &mut W, buf: &[u8]) -> std::io::Result<()> {
+    write_all_within(writer, buf, RETRY_TIMEOUT)
+}
+
+fn read_within<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8], timeout: Duration) -> std::io::Result<usize> {
+    let started = Instant::now();
+    loop {
+        match reader.read(buf) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => wait_or_time_out(started, timeout)?,
+            result => return result,
+        }
+    }
+}
+
+fn write_all_within<W: Write + ?Sized>(writer: &mut W, mut buf: &[u8], timeout: Duration) -> std::io::Result<()> {
+    let mut progressed_at = Instant::now();
+    while !buf.is_empty() {
+        match writer.write(buf) {
+            Ok(0) => return Err(std::io::Error::new(ErrorKind::WriteZero, "failed to write whole buffer")),
+            Ok(n) => {
+                buf = &buf[n..];
+                progressed_at = Instant::now();
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => wait_or_time_out(progressed_at, timeout)?,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn wait_or_time_out(since: Instant, timeout: Duration) -> std::io::Result<()> {
+    if since.elapsed() >= timeout {
+        return Err(std::io::Error::new(ErrorKind::TimedOut, "timed out waiting for the server"));
+    }
+    thread::sleep(Duration::from_millis(1));
+    Ok(())
+}
+
+/// Copy bytes between a socket and an SSH channel until either side closes.
+///
+/// The channel's session must be in non-blocking mode. `tick` is called once per
+/// iteration with the bytes moved in that iteration; returning `false` stops the pump.
+pub fn pump(
+    channel: &mut Channel,
+    socket: &mut TcpStream,
+    mut tick: impl FnMut(PumpProgress) -> bool,
+) -> std::io::Result<()> {
+    socket.set_nonblocking(true)?;
+
+    let mut buffer = vec![0u8; 32768];
+    let mut to_remote: Vec<u8> = Vec::new();
+    let mut to_local: Vec<u8> = Vec::new();
+
+    loop {
+        let mut progress = PumpProgress::default();
+
+        if to_remote.is_empty() {
+            match socket.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => to_remote.extend_from_slice(&buffer[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !to_remote.is_empty() {
+            match channel.write(&to_remote) {
+                Ok(n) => {
+                    to_remote.drain(..n);
+                    progress.sent = n;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if to_local.is_empty() {
+            match channel.read(&mut buffer) {
+                Ok(0) if channel.eof() => break,
+                Ok(n) => to_local.extend_from_slice(&buffer[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !to_local.is_empty() {
+            match socket.write(&to_local) {
+                Ok(n) => {
+                    to_local.drain(..n);
+                    progress.received = n;
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if !tick(progress) {
+            break;
+        }
+
+        if progress.sent == 0 && progress.received == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Never has data and never takes any
+    struct Stalled;
+
+    impl Read for Stalled {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Stalled {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::WouldBlock.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_blocking_io_gives_up_on_a_stalled_server() {
+        let timeout = Duration::from_millis(20);
+        let mut buf = [0u8; 16];
+        assert_eq!(read_within(&mut Stalled, &mut buf, timeout).unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(write_all_within(&mut Stalled, b"data", timeout).unwrap_err().kind(), ErrorKind::TimedOut);
+
+        let mut written = Vec::new();
+        write_all_within(&mut written, b"data", timeout).unwrap();
+        assert_eq!(written, b"data");
+    }
+}